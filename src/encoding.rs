@@ -1,3 +1,4 @@
 //! # Binary encodings for specific data types
 
+pub mod crc64;
 pub mod prefix_varint;
@@ -0,0 +1,62 @@
+//! # CRC-64 checksums
+//!
+//! This module implements the CRC-64/XZ checksum (the reflected ECMA-182 polynomial, with all bits
+//! inverted on input and output), using a lookup table computed at compile time.
+
+const POLYNOMIAL: u64 = 0xC96C5795D7870F42;
+
+const TABLE: [u64; 256] = {
+  let mut table = [0u64; 256];
+  let mut i = 0;
+  while i < 256 {
+    let mut crc = i as u64;
+    let mut j = 0;
+    while j < 8 {
+      crc = if crc & 1 != 0 { (crc >> 1) ^ POLYNOMIAL } else { crc >> 1 };
+      j += 1;
+    }
+    table[i] = crc;
+    i += 1;
+  }
+  table
+};
+
+/// Continues computing a checksum from a previous result `crc` over additional `data`.
+///
+/// The checksum of an empty byte slice is `0`, so `update(update(0, a), b) == checksum(a ++ b)`.
+pub fn update(crc: u64, data: &[u8]) -> u64 {
+  let mut crc = !crc;
+  for &byte in data {
+    crc = TABLE[((crc ^ byte as u64) & 0xFF) as usize] ^ (crc >> 8);
+  }
+  !crc
+}
+
+/// Computes the checksum of a byte slice.
+pub fn checksum(data: &[u8]) -> u64 {
+  update(0, data)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rand::Rng;
+
+  #[test]
+  fn test_specific_checksum() {
+    // Test known check values.
+    assert_eq!(checksum(b""), 0);
+    assert_eq!(checksum(b"123456789"), 0x995DC9BBDF1939FA);
+  }
+
+  #[test]
+  fn test_incremental_update() {
+    // Test that splitting the input at any point does not change the result.
+    let mut rng = rand::thread_rng();
+    let data: Vec<u8> = (0..1000).map(|_| rng.gen()).collect();
+    let expected = checksum(&data);
+    for mid in [0, 1, 7, 500, 999, 1000] {
+      assert_eq!(update(update(0, &data[..mid]), &data[mid..]), expected, "Split at: {}", mid);
+    }
+  }
+}
@@ -10,7 +10,8 @@ fn unaligned_load_u64(p: &[u8]) -> u64 {
   u64::from_le_bytes(array)
 }
 
-fn length(initial: u8) -> u32 {
+/// Returns the length in bytes of an encoded integer, given the first byte of its encoding.
+pub fn length(initial: u8) -> u32 {
   1 + (initial as u32 | 0x100).trailing_zeros()
 }
 
@@ -1,6 +1,12 @@
 //! # The storage engine
 
 pub mod bplus;
+pub mod catalog;
+pub mod database;
+pub mod map;
 pub mod paging;
 pub mod prolly;
+pub mod table;
+#[cfg(test)]
+mod testing;
 pub mod vfs;
@@ -1 +1,1655 @@
 //! # B+ trees
+//!
+//! A B+ tree is an N-ary search tree with a high fanout, where all key-value pairs are stored in
+//! leaf nodes, and internal nodes only contain separator keys. Unlike Prolly trees, nodes are
+//! modified in place, and the tree is kept balanced by splitting and merging nodes.
+
+use super::map;
+use super::paging;
+use crate::encoding::prefix_varint;
+use std::collections;
+use std::marker;
+use std::mem;
+use std::ops;
+
+pub mod shared;
+
+/// # B+ tree
+///
+/// An implementation of [`map::Map`] backed by a B+ tree. All methods report I/O errors and
+/// corrupted pages through [`paging::Error`]. Since nodes are modified in place, an error returned
+/// by a modifying method may leave the tree partially modified.
+///
+/// ## Implementation notes
+///
+/// Invariants maintained by all methods:
+///
+/// - All leaf nodes are at the same depth.
+///
+/// - In an internal node with children `children` and keys `keys`, all keys in the subtree of
+///   `children[i]` are smaller than `keys[i]` and not smaller than `keys[i - 1]`.
+///
+/// - All nodes except the root are at least half full, up to the size of one cell: the cells of
+///   each node (including cell pointers) would occupy at least `(capacity - max_cell_size) / 2`
+///   bytes without prefix compression. Nodes which become larger than the page are split, and nodes
+///   which become smaller than this are merged with a sibling, or balanced with it if the merged
+///   node would not fit.
+///
+/// - The root is either a leaf, or an internal node with at least two children.
+///
+/// Leaves omit the common prefix of their keys from each cell, and the keys in internal nodes are
+/// the shortest byte strings separating adjacent leaves. Since the common prefix may get shorter
+/// when a key is added, a leaf may need to be split into more than two parts at once.
+///
+/// The root node always stays in the same page: when it is split, its content moves into new
+/// pages, and when it has a single child left, the content of the child moves into the root page.
+/// This means that the root page ID obtained through [`BasicTree::root`] never changes.
+///
+/// Keys are always stored inline, and must not be longer than one eighth of the page size.
+/// Values which would make a cell larger than a quarter of the page go into overflow pages.
+pub struct BasicTree<Store: paging::Store> {
+  root: u64,
+  _store: marker::PhantomData<Store>,
+}
+
+impl<Store: paging::Store> BasicTree<Store> {
+  /// Creates an empty tree in a newly allocated page.
+  pub fn create(store: &mut Store) -> paging::Result<Self, Store> {
+    let root = store.allocate()?;
+    Self::init(store, root)
+  }
+
+  /// Creates an empty tree in the given page, overwriting its content.
+  pub fn init(store: &mut Store, root: u64) -> paging::Result<Self, Store> {
+    BasicNode { height: 0, keys: Vec::new(), items: Vec::new() }.write(store, root)?;
+    Ok(Self::open(root))
+  }
+
+  /// Opens an existing tree given its root page ID.
+  pub fn open(root: u64) -> Self {
+    Self { root, _store: marker::PhantomData }
+  }
+
+  /// Returns the root page ID of the tree.
+  pub fn root(&self) -> u64 {
+    self.root
+  }
+
+  /// Deallocates all pages of the tree, including overflow pages.
+  pub fn destroy(self, store: &mut Store) -> paging::Result<(), Store> {
+    let mut stack = vec![self.root];
+    while let Some(page_id) = stack.pop() {
+      for item in BasicNode::read(store, page_id)?.items {
+        match item {
+          Item::Inline(_) => {}
+          Item::Overflow { page_id, .. } => paging::free_overflow(store, page_id)?,
+          Item::Child(page_id) => stack.push(page_id),
+        }
+      }
+      store.deallocate(page_id)?;
+    }
+    Ok(())
+  }
+
+  /// Descends from the root to the leaf which may contain the given key. Returns the nodes on the
+  /// path along with their page IDs, and the index of the child taken in each internal node.
+  fn descend(&self, store: &mut Store, key: &[u8]) -> paging::Result<Path, Store> {
+    let mut nodes = vec![(self.root, BasicNode::read(store, self.root)?)];
+    let mut indices = Vec::new();
+    loop {
+      let (_, node) = nodes.last().unwrap();
+      if node.height == 0 {
+        return Ok(Path { nodes, indices });
+      }
+      let i = node.child_index(key);
+      let page_id = node.child_page_id(i);
+      let child = BasicNode::read_child(store, page_id, node.height)?;
+      nodes.push((page_id, child));
+      indices.push(i);
+    }
+  }
+
+  /// Writes back the last node on a path after it was modified, splitting, merging or balancing
+  /// nodes up to the root as necessary.
+  fn rebalance(&mut self, store: &mut Store, path: Path) -> paging::Result<(), Store> {
+    let Path { mut nodes, mut indices } = path;
+    let page_size = store.page_size();
+    let capacity = paging::node_page_capacity(page_size);
+    let min_size = (capacity - max_cell_size(page_size)) / 2;
+    while let Some((page_id, node)) = nodes.pop() {
+      let Some((_, parent)) = nodes.last_mut() else {
+        return self.write_root(store, node);
+      };
+      let i = indices.pop().unwrap();
+      if node.size() > capacity {
+        let (parts, keys) = node.split_to_fit(capacity);
+        let children = Self::write_parts(store, parts, &[page_id])?;
+        parent.keys.splice(i..i, keys);
+        parent.items.splice(i..i + 1, children);
+      } else if node.uncompressed_size() < min_size {
+        // Merge with or balance against the left sibling, or the right one for the first child.
+        let (l, r) = if i > 0 { (i - 1, i) } else { (i, i + 1) };
+        let sibling_page_id = parent.child_page_id(if i > 0 { l } else { r });
+        let sibling = BasicNode::read_child(store, sibling_page_id, node.height + 1)?;
+        let (left, right) = if i > 0 { (sibling, node) } else { (node, sibling) };
+        let (left_page_id, right_page_id) = (parent.child_page_id(l), parent.child_page_id(r));
+        let merged = BasicNode::merge(left, parent.keys[l].clone(), right);
+        if merged.size() <= capacity {
+          merged.write(store, left_page_id)?;
+          store.deallocate(right_page_id)?;
+          parent.keys.remove(l);
+          parent.items.remove(r);
+        } else {
+          let (parts, keys) = merged.split_to_fit(capacity);
+          let children = Self::write_parts(store, parts, &[left_page_id, right_page_id])?;
+          parent.keys.splice(l..r, keys);
+          parent.items.splice(l..r + 1, children);
+        }
+      } else {
+        // The parent is unchanged.
+        return node.write(store, page_id);
+      }
+    }
+    Ok(())
+  }
+
+  /// Creates a leaf item for `value`, moving it into overflow pages if it is too large.
+  fn make_item(store: &mut Store, key: &[u8], value: &[u8]) -> paging::Result<Item, Store> {
+    paging::check_key(store, key)?;
+    let page_size = store.page_size();
+    let item = Item::Inline(value.into());
+    if BasicNode::leaf_cell_size(key, &item) + 2 <= max_cell_size(page_size) {
+      return Ok(item);
+    }
+    let page_id = paging::write_overflow(store, value)?;
+    Ok(Item::Overflow { page_id, len: value.len() as u64 })
+  }
+
+  /// Writes the parts of a split node, reusing the given pages before allocating new ones. Returns
+  /// the child pointers to the parts.
+  fn write_parts(
+    store: &mut Store,
+    parts: Vec<BasicNode>,
+    page_ids: &[u64],
+  ) -> paging::Result<Vec<Item>, Store> {
+    let mut children = Vec::with_capacity(parts.len());
+    for (i, part) in parts.into_iter().enumerate() {
+      let page_id = match page_ids.get(i) {
+        Some(&page_id) => page_id,
+        None => store.allocate()?,
+      };
+      part.write(store, page_id)?;
+      children.push(Item::Child(page_id));
+    }
+    Ok(children)
+  }
+
+  /// Writes the root node, splitting it or replacing it with its only child as necessary.
+  fn write_root(&mut self, store: &mut Store, node: BasicNode) -> paging::Result<(), Store> {
+    let capacity = paging::node_page_capacity(store.page_size());
+    let mut node = node;
+    while node.size() > capacity {
+      let height = node.height + 1;
+      let (parts, keys) = node.split_to_fit(capacity);
+      let items = Self::write_parts(store, parts, &[])?;
+      node = BasicNode { height, keys, items };
+    }
+    while node.height > 0 && node.items.len() == 1 {
+      let page_id = node.child_page_id(0);
+      node = BasicNode::read_child(store, page_id, node.height)?;
+      store.deallocate(page_id)?;
+    }
+    node.write(store, self.root)
+  }
+}
+
+impl<Store: paging::Store> map::Map<Store> for BasicTree<Store> {
+  type Cursor = BasicCursor;
+
+  fn get(&self, store: &mut Store, key: &[u8]) -> paging::Result<Option<Box<[u8]>>, Store> {
+    let mut node = BasicNode::read(store, self.root)?;
+    while node.height > 0 {
+      let page_id = node.child_page_id(node.child_index(key));
+      node = BasicNode::read_child(store, page_id, node.height)?;
+    }
+    match node.keys.binary_search_by(|k| (**k).cmp(key)) {
+      Ok(i) => match &node.items[i] {
+        Item::Inline(value) => Ok(Some(value.clone())),
+        Item::Overflow { page_id, len } => {
+          Ok(Some(paging::read_overflow(store, *page_id, *len)?.into()))
+        }
+        Item::Child(_) => unreachable!(),
+      },
+      Err(_) => Ok(None),
+    }
+  }
+
+  fn insert(&mut self, store: &mut Store, key: &[u8], value: &[u8]) -> paging::Result<bool, Store> {
+    let mut path = self.descend(store, key)?;
+    let item = Self::make_item(store, key, value)?;
+    let (_, leaf) = path.nodes.last_mut().unwrap();
+    let found = match leaf.keys.binary_search_by(|k| (**k).cmp(key)) {
+      Ok(i) => {
+        if let Item::Overflow { page_id, .. } = mem::replace(&mut leaf.items[i], item) {
+          paging::free_overflow(store, page_id)?;
+        }
+        true
+      }
+      Err(i) => {
+        leaf.keys.insert(i, key.into());
+        leaf.items.insert(i, item);
+        false
+      }
+    };
+    self.rebalance(store, path)?;
+    Ok(found)
+  }
+
+  fn remove(&mut self, store: &mut Store, key: &[u8]) -> paging::Result<bool, Store> {
+    let mut path = self.descend(store, key)?;
+    let (_, leaf) = path.nodes.last_mut().unwrap();
+    let Ok(i) = leaf.keys.binary_search_by(|k| (**k).cmp(key)) else { return Ok(false) };
+    leaf.keys.remove(i);
+    if let Item::Overflow { page_id, .. } = leaf.items.remove(i) {
+      paging::free_overflow(store, page_id)?;
+    }
+    self.rebalance(store, path)?;
+    Ok(true)
+  }
+
+  fn upper_bound(
+    &self,
+    store: &mut Store,
+    bound: ops::Bound<&[u8]>,
+  ) -> paging::Result<Self::Cursor, Store> {
+    BasicCursor::seek(store, self.root, |node| match bound {
+      ops::Bound::Included(key) if node.height == 0 => node.keys.partition_point(|k| **k <= *key),
+      ops::Bound::Excluded(key) if node.height == 0 => node.keys.partition_point(|k| **k < *key),
+      ops::Bound::Included(key) | ops::Bound::Excluded(key) => node.child_index(key),
+      ops::Bound::Unbounded => node.items.len() - usize::from(node.height > 0),
+    })
+  }
+
+  fn lower_bound(
+    &self,
+    store: &mut Store,
+    bound: ops::Bound<&[u8]>,
+  ) -> paging::Result<Self::Cursor, Store> {
+    BasicCursor::seek(store, self.root, |node| match bound {
+      ops::Bound::Included(key) if node.height == 0 => node.keys.partition_point(|k| **k < *key),
+      ops::Bound::Excluded(key) if node.height == 0 => node.keys.partition_point(|k| **k <= *key),
+      ops::Bound::Included(key) | ops::Bound::Excluded(key) => node.child_index(key),
+      ops::Bound::Unbounded => 0,
+    })
+  }
+}
+
+/// # Bulk loader
+///
+/// Builds a [`BasicTree`] bottom-up from key-value pairs given in increasing order of keys, which
+/// is much faster than inserting them one at a time. Leaves are written as soon as they are
+/// complete, and internal levels are written when the loader is finished, so that pages are
+/// allocated in sequential order within each level.
+///
+/// Each node is filled until the next cell would make it larger than the fill factor times the
+/// capacity of a page, without prefix compression. The fill factor is clamped so that all nodes
+/// are at least half full, as required by [`BasicTree`]. The last node on each level is merged
+/// with or balanced against the previous one if it would be less than half full.
+pub struct Loader<Store: paging::Store> {
+  target: usize,
+  leaves: Level,
+  _store: marker::PhantomData<Store>,
+}
+
+impl<Store: paging::Store> Loader<Store> {
+  /// Creates a loader packing nodes up to the given fill factor.
+  ///
+  /// # Panics
+  ///
+  /// Panics if `fill` is not greater than `0` and at most `1`.
+  pub fn new(store: &Store, fill: f64) -> Self {
+    assert!(fill > 0.0 && fill <= 1.0, "fill factor must be in (0, 1]");
+    let capacity = paging::node_page_capacity(store.page_size());
+    let max_cell = max_cell_size(store.page_size());
+    // Full nodes are larger than `target - max_cell`, which must not be less than the minimum
+    // size. Leaves no larger than `capacity - 2` still fit after prefix compression.
+    let target =
+      ((capacity as f64 * fill) as usize).clamp((capacity - max_cell) / 2 + max_cell, capacity - 2);
+    Self { target, leaves: Level::new(0), _store: marker::PhantomData }
+  }
+
+  /// Adds a key-value pair after all pairs added so far.
+  ///
+  /// # Panics
+  ///
+  /// Panics if the key is not greater than all keys added so far.
+  pub fn push(&mut self, store: &mut Store, key: &[u8], value: &[u8]) -> paging::Result<(), Store> {
+    let last = self
+      .leaves
+      .current
+      .keys
+      .last()
+      .or(self.leaves.pending.as_ref().map(|(_, node)| node.keys.last().unwrap()));
+    assert!(last.is_none_or(|last| **last < *key), "keys must be strictly increasing");
+    let item = BasicTree::make_item(store, key, value)?;
+    self.leaves.push(store, self.target, key.into(), item)
+  }
+
+  /// Writes the remaining leaves and all internal nodes, returning the tree.
+  pub fn finish(self, store: &mut Store) -> paging::Result<BasicTree<Store>, Store> {
+    let mut children = self.leaves.finish(store)?;
+    let mut height = 1;
+    while children.len() > 1 {
+      let mut level = Level::new(height);
+      for (key, item) in children {
+        level.push(store, self.target, key, item)?;
+      }
+      children = level.finish(store)?;
+      height += 1;
+    }
+    let Some((_, Item::Child(root))) = children.pop() else { unreachable!() };
+    Ok(BasicTree::open(root))
+  }
+
+  /// Abandons the loader, deallocating all pages written so far.
+  pub fn abort(self, store: &mut Store) -> paging::Result<(), Store> {
+    for (_, item) in self.leaves.written {
+      BasicTree::<Store>::open(item_page_id(&item)).destroy(store)?;
+    }
+    let pending = self.leaves.pending.into_iter().map(|(_, node)| node);
+    for node in pending.chain([self.leaves.current]) {
+      for item in node.items {
+        if let Item::Overflow { page_id, .. } = item {
+          paging::free_overflow(store, page_id)?;
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+impl<Store: paging::Store> BasicTree<Store> {
+  /// Builds a tree from key-value pairs in increasing order of keys. See [`Loader`].
+  ///
+  /// # Panics
+  ///
+  /// Panics if the keys are not strictly increasing, or if `fill` is not greater than `0` and at
+  /// most `1`.
+  pub fn bulk_load<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+    store: &mut Store,
+    entries: impl IntoIterator<Item = (K, V)>,
+    fill: f64,
+  ) -> paging::Result<Self, Store> {
+    let mut loader = Loader::new(store, fill);
+    for (key, value) in entries {
+      loader.push(store, key.as_ref(), value.as_ref())?;
+    }
+    loader.finish(store)
+  }
+}
+
+/// A pointer to a node written by a [`Loader`], along with the key separating it from the previous
+/// node on the same level.
+type Pointer = (Box<[u8]>, Item);
+
+/// One level of a tree being built by a [`Loader`].
+struct Level {
+  /// The last complete node, which is not written yet so that it can be balanced against the last
+  /// node of the level, along with the key separating it from the previous node.
+  pending: Option<(Box<[u8]>, BasicNode)>,
+  /// The node being filled.
+  current: BasicNode,
+  /// The key separating the current node from the previous node.
+  separator: Box<[u8]>,
+  /// The size of the current node without prefix compression.
+  size: usize,
+  /// Pointers to the written nodes, along with the keys separating them from the previous nodes.
+  written: Vec<Pointer>,
+}
+
+impl Level {
+  fn new(height: u8) -> Self {
+    let current = BasicNode { height, keys: Vec::new(), items: Vec::new() };
+    Self { pending: None, current, separator: Box::default(), size: 0, written: Vec::new() }
+  }
+
+  /// Adds an entry to the level: a key-value pair in leaves, or a child pointer along with the key
+  /// separating it from the previous child in internal nodes.
+  fn push<Store: paging::Store>(
+    &mut self,
+    store: &mut Store,
+    target: usize,
+    key: Box<[u8]>,
+    item: Item,
+  ) -> paging::Result<(), Store> {
+    let height = self.current.height;
+    let size = match height {
+      0 => BasicNode::leaf_cell(&key, &item).len() + 2,
+      _ => BasicNode::internal_cell(&key, &item).len() + 2,
+    };
+    if !self.current.items.is_empty() && self.size + size > target {
+      let separator = match height {
+        0 => separator(self.current.keys.last().unwrap(), &key),
+        _ => key.clone(),
+      };
+      let node =
+        mem::replace(&mut self.current, BasicNode { height, keys: Vec::new(), items: Vec::new() });
+      let previous = mem::replace(&mut self.separator, separator);
+      if let Some((separator, pending)) = self.pending.replace((previous, node)) {
+        self.write(store, separator, pending)?;
+      }
+      self.size = 0;
+    }
+    if height > 0 && self.current.items.is_empty() {
+      self.size = LAST_CELL_SIZE;
+    } else {
+      self.current.keys.push(key);
+      self.size += size;
+    }
+    self.current.items.push(item);
+    Ok(())
+  }
+
+  /// Writes a node into a newly allocated page.
+  fn write<Store: paging::Store>(
+    &mut self,
+    store: &mut Store,
+    separator: Box<[u8]>,
+    node: BasicNode,
+  ) -> paging::Result<(), Store> {
+    let page_id = store.allocate()?;
+    node.write(store, page_id)?;
+    self.written.push((separator, Item::Child(page_id)));
+    Ok(())
+  }
+
+  /// Writes the remaining nodes, returning pointers to all nodes on the level along with the keys
+  /// separating them.
+  fn finish<Store: paging::Store>(
+    mut self,
+    store: &mut Store,
+  ) -> paging::Result<Vec<Pointer>, Store> {
+    let capacity = paging::node_page_capacity(store.page_size());
+    let min_size = (capacity - max_cell_size(store.page_size())) / 2;
+    let current =
+      mem::replace(&mut self.current, BasicNode { height: 0, keys: Vec::new(), items: Vec::new() });
+    let separator = mem::take(&mut self.separator);
+    match self.pending.take() {
+      Some((previous, pending)) if current.uncompressed_size() < min_size => {
+        let merged = BasicNode::merge(pending, separator, current);
+        if merged.size() <= capacity {
+          self.write(store, previous, merged)?;
+        } else {
+          let sizes = merged.cell_sizes_with_prefix(0);
+          let (left, key, right) = merged.split_by(&sizes);
+          self.write(store, previous, left)?;
+          self.write(store, key, right)?;
+        }
+      }
+      Some((previous, pending)) => {
+        self.write(store, previous, pending)?;
+        self.write(store, separator, current)?;
+      }
+      None => self.write(store, separator, current)?,
+    }
+    Ok(self.written)
+  }
+}
+
+/// Returns the page ID of a child pointer.
+fn item_page_id(item: &Item) -> u64 {
+  match item {
+    Item::Child(page_id) => *page_id,
+    _ => unreachable!(),
+  }
+}
+
+/// # Structural problems
+///
+/// A violation of the invariants of a [`BasicTree`], found by [`BasicTree::verify`]. Page IDs
+/// refer to the page where the problem was found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+  /// A page failed its checksum.
+  Checksum { page_id: u64 },
+  /// A page does not contain a valid node or overflow page.
+  Corrupt { page_id: u64 },
+  /// The keys of a node are not in strictly increasing order.
+  Unsorted { page_id: u64 },
+  /// A key of a node is outside the range given by the separating keys in its ancestors.
+  OutOfRange { page_id: u64, key: Box<[u8]> },
+  /// The height cell of an internal node is not one more than the height of a child.
+  Height { page_id: u64, expected: u8, found: u8 },
+  /// A leaf is at a different depth from the first leaf.
+  Depth { page_id: u64, expected: usize, found: usize },
+  /// A node other than the root is less than half full, with its cells occupying `size` bytes
+  /// without prefix compression.
+  Underfull { page_id: u64, size: usize, min_size: usize },
+  /// The root is an internal node with only one child.
+  SingleChild { page_id: u64 },
+  /// A node or overflow page is reachable more than once.
+  Shared { page_id: u64 },
+}
+
+impl<Store: paging::Store> BasicTree<Store> {
+  /// Checks the invariants of the tree, returning all problems found. Only I/O errors are
+  /// reported through [`paging::Error`]; checksum mismatches and corrupted pages are reported as
+  /// problems, and the subtrees below them are skipped. Checksums of overflow pages are not
+  /// verified.
+  pub fn verify(&self, store: &mut Store) -> paging::Result<Vec<Problem>, Store> {
+    let page_size = store.page_size();
+    let min_size = (paging::node_page_capacity(page_size) - max_cell_size(page_size)) / 2;
+    let mut verifier = Verifier {
+      min_size,
+      visited: collections::HashSet::new(),
+      leaf_depth: None,
+      problems: Vec::new(),
+    };
+    verifier.node(store, self.root, None, 0, (None, None))?;
+    Ok(verifier.problems)
+  }
+}
+
+/// The state of [`BasicTree::verify`].
+struct Verifier {
+  min_size: usize,
+  visited: collections::HashSet<u64>,
+  leaf_depth: Option<usize>,
+  problems: Vec<Problem>,
+}
+
+impl Verifier {
+  /// Checks the subtree rooted at a page, given the expected height of the node, its depth, and
+  /// the inclusive lower and exclusive upper bounds of its keys.
+  fn node<Store: paging::Store>(
+    &mut self,
+    store: &mut Store,
+    page_id: u64,
+    height: Option<u8>,
+    depth: usize,
+    (lower, upper): (Option<&[u8]>, Option<&[u8]>),
+  ) -> paging::Result<(), Store> {
+    if !self.visited.insert(page_id) {
+      self.problems.push(Problem::Shared { page_id });
+      return Ok(());
+    }
+    let node = match BasicNode::read_unsorted(store, page_id) {
+      Ok(node) => node,
+      Err(error) => return self.error(error),
+    };
+    if let Some(expected) = height.filter(|&height| height != node.height) {
+      self.problems.push(Problem::Height { page_id, expected, found: node.height });
+    }
+    if !node.keys.windows(2).all(|pair| pair[0] < pair[1]) {
+      self.problems.push(Problem::Unsorted { page_id });
+    }
+    for key in &node.keys {
+      let in_range =
+        lower.is_none_or(|lower| lower <= &key[..]) && upper.is_none_or(|upper| &key[..] < upper);
+      if !in_range {
+        self.problems.push(Problem::OutOfRange { page_id, key: key.clone() });
+      }
+    }
+    let size = node.uncompressed_size();
+    if depth > 0 && size < self.min_size {
+      self.problems.push(Problem::Underfull { page_id, size, min_size: self.min_size });
+    }
+    if depth == 0 && node.height > 0 && node.items.len() < 2 {
+      self.problems.push(Problem::SingleChild { page_id });
+    }
+    if node.height == 0 {
+      let expected = *self.leaf_depth.get_or_insert(depth);
+      if depth != expected {
+        self.problems.push(Problem::Depth { page_id, expected, found: depth });
+      }
+      for item in &node.items {
+        if let Item::Overflow { page_id, .. } = *item {
+          self.overflow(store, page_id)?;
+        }
+      }
+      return Ok(());
+    }
+    for i in 0..node.items.len() {
+      let lower = if i == 0 { lower } else { Some(&node.keys[i - 1][..]) };
+      let upper = node.keys.get(i).map(|key| &key[..]).or(upper);
+      let child = node.child_page_id(i);
+      self.node(store, child, Some(node.height - 1), depth + 1, (lower, upper))?;
+    }
+    Ok(())
+  }
+
+  /// Checks a chain of overflow pages.
+  fn overflow<Store: paging::Store>(
+    &mut self,
+    store: &mut Store,
+    page_id: u64,
+  ) -> paging::Result<(), Store> {
+    let page_ids = match paging::overflow_pages(store, page_id) {
+      Ok(page_ids) => page_ids,
+      Err(error) => return self.error(error),
+    };
+    for page_id in page_ids {
+      if !self.visited.insert(page_id) {
+        self.problems.push(Problem::Shared { page_id });
+      }
+    }
+    Ok(())
+  }
+
+  /// Records a checksum mismatch or corrupted page as a problem, or propagates an I/O error.
+  fn error<E>(&mut self, error: paging::Error<E>) -> Result<(), paging::Error<E>> {
+    match error {
+      paging::Error::Checksum { page_id } => self.problems.push(Problem::Checksum { page_id }),
+      paging::Error::Corrupt { page_id } => self.problems.push(Problem::Corrupt { page_id }),
+      error => return Err(error),
+    }
+    Ok(())
+  }
+}
+
+/// # Standard implementation for [`map::Cursor`]
+///
+/// The cursor holds copies of all nodes on the path from the root to the current leaf, so that
+/// moving to an adjacent leaf only reads the nodes which are not shared between the two paths.
+/// Since B+ tree nodes are modified in place, a cursor must not be used after the tree is modified.
+pub struct BasicCursor {
+  /// Nodes on the current path, along with the index of the next child (in internal nodes) or the
+  /// index of the element after the gap (in the leaf).
+  path: Vec<(BasicNode, usize)>,
+  /// Buffer for values stored in overflow pages.
+  buffer: Vec<u8>,
+}
+
+impl BasicCursor {
+  /// Creates a cursor by descending from the root, using `index` to choose the child index in each
+  /// internal node and the gap index in the leaf.
+  fn seek<Store: paging::Store>(
+    store: &mut Store,
+    root: u64,
+    index: impl Fn(&BasicNode) -> usize,
+  ) -> paging::Result<Self, Store> {
+    let mut path = Vec::new();
+    let mut node = BasicNode::read(store, root)?;
+    while node.height > 0 {
+      let i = index(&node);
+      let child = BasicNode::read_child(store, node.child_page_id(i), node.height)?;
+      path.push((node, i));
+      node = child;
+    }
+    let i = index(&node);
+    path.push((node, i));
+    Ok(Self { path, buffer: Vec::new() })
+  }
+
+  /// Moves the cursor to the start of the next leaf (if `forward` is `true`) or to the end of the
+  /// previous leaf (otherwise). Returns `false` if there is no such leaf.
+  fn step<Store: paging::Store>(
+    &mut self,
+    store: &mut Store,
+    forward: bool,
+  ) -> paging::Result<bool, Store> {
+    let leaf = self.path.len() - 1;
+    let Some(level) = (0..leaf).rev().find(|&level| {
+      let (node, i) = &self.path[level];
+      if forward {
+        i + 1 < node.items.len()
+      } else {
+        *i > 0
+      }
+    }) else {
+      return Ok(false);
+    };
+    // Read the new nodes first, so that the cursor is unchanged if an error occurs.
+    let (node, i) = &self.path[level];
+    let i = if forward { i + 1 } else { i - 1 };
+    let mut nodes = Vec::new();
+    let mut parent = (node.height, node.child_page_id(i));
+    while parent.0 > 0 {
+      let node = BasicNode::read_child(store, parent.1, parent.0)?;
+      if node.height > 0 {
+        let j = if forward { 0 } else { node.items.len() - 1 };
+        parent = (node.height, node.child_page_id(j));
+      } else {
+        parent = (0, 0);
+      }
+      nodes.push(node);
+    }
+    self.path.truncate(level + 1);
+    self.path[level].1 = i;
+    for node in nodes {
+      let j = match (forward, node.height) {
+        (true, _) => 0,
+        (false, 0) => node.items.len(),
+        (false, _) => node.items.len() - 1,
+      };
+      self.path.push((node, j));
+    }
+    Ok(true)
+  }
+
+  /// Loads the value of the `i`-th element in the current leaf, if it is stored in overflow pages.
+  fn load<Store: paging::Store>(
+    &mut self,
+    store: &mut Store,
+    i: usize,
+  ) -> paging::Result<(), Store> {
+    let (leaf, _) = self.path.last().unwrap();
+    if let Item::Overflow { page_id, len } = leaf.items[i] {
+      self.buffer = paging::read_overflow(store, page_id, len)?;
+    }
+    Ok(())
+  }
+
+  /// Returns the key and value of the `i`-th element in the current leaf, which must be loaded.
+  fn element(&self, i: usize) -> (&[u8], &[u8]) {
+    let (leaf, _) = self.path.last().unwrap();
+    match &leaf.items[i] {
+      Item::Inline(value) => (&leaf.keys[i], value),
+      Item::Overflow { .. } => (&leaf.keys[i], &self.buffer),
+      Item::Child(_) => unreachable!(),
+    }
+  }
+}
+
+impl<Store: paging::Store> map::Cursor<Store> for BasicCursor {
+  fn next(&mut self, store: &mut Store) -> paging::Result<Option<map::Element<'_>>, Store> {
+    let (leaf, i) = self.path.last().unwrap();
+    if *i == leaf.items.len() && !self.step(store, true)? {
+      return Ok(None);
+    }
+    let i = self.path.last().unwrap().1;
+    self.load(store, i)?;
+    self.path.last_mut().unwrap().1 = i + 1;
+    Ok(Some(self.element(i)))
+  }
+
+  fn prev(&mut self, store: &mut Store) -> paging::Result<Option<map::Element<'_>>, Store> {
+    let (_, i) = self.path.last().unwrap();
+    if *i == 0 && !self.step(store, false)? {
+      return Ok(None);
+    }
+    let i = self.path.last().unwrap().1 - 1;
+    self.load(store, i)?;
+    self.path.last_mut().unwrap().1 = i;
+    Ok(Some(self.element(i)))
+  }
+
+  fn peek_next(&mut self, store: &mut Store) -> paging::Result<Option<map::Element<'_>>, Store> {
+    let (leaf, i) = self.path.last().unwrap();
+    if *i == leaf.items.len() && !self.step(store, true)? {
+      return Ok(None);
+    }
+    let i = self.path.last().unwrap().1;
+    self.load(store, i)?;
+    Ok(Some(self.element(i)))
+  }
+
+  fn peek_prev(&mut self, store: &mut Store) -> paging::Result<Option<map::Element<'_>>, Store> {
+    let (_, i) = self.path.last().unwrap();
+    if *i == 0 && !self.step(store, false)? {
+      return Ok(None);
+    }
+    let i = self.path.last().unwrap().1 - 1;
+    self.load(store, i)?;
+    Ok(Some(self.element(i)))
+  }
+}
+
+/// Returns the maximum size of a cell (including its cell pointer) in a node page.
+fn max_cell_size(page_size: usize) -> usize {
+  paging::node_page_capacity(page_size) / 4
+}
+
+/// A path from the root to a leaf.
+struct Path {
+  /// Nodes on the path, along with their page IDs.
+  nodes: Vec<(u64, BasicNode)>,
+  /// The index of the child taken in each internal node on the path.
+  indices: Vec<usize>,
+}
+
+/// The content of a leaf entry, or a child pointer in an internal node.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Item {
+  /// A value stored in a leaf cell.
+  Inline(Box<[u8]>),
+  /// A value stored in a chain of overflow pages, along with its length.
+  Overflow { page_id: u64, len: u64 },
+  /// A child pointer in an internal node.
+  Child(u64),
+}
+
+/// # Node of a [`BasicTree`]
+///
+/// Leaf nodes are stored in pages of type `1`, and internal nodes are stored in pages of type `0`.
+/// Leaf nodes have one item for each key, and internal nodes have one more child than keys.
+#[derive(Clone, Debug)]
+struct BasicNode {
+  height: u8,
+  keys: Vec<Box<[u8]>>,
+  items: Vec<Item>,
+}
+
+impl BasicNode {
+  /// Reads a node from a page.
+  fn read<Store: paging::Store>(store: &mut Store, page_id: u64) -> paging::Result<Self, Store> {
+    let page = store.get(page_id)?;
+    Self::decode(page).ok_or(paging::Error::Corrupt { page_id })
+  }
+
+  /// Reads a node from a page, without checking that its keys are sorted.
+  fn read_unsorted<Store: paging::Store>(
+    store: &mut Store,
+    page_id: u64,
+  ) -> paging::Result<Self, Store> {
+    let page = store.get(page_id)?;
+    Self::decode_unsorted(page).ok_or(paging::Error::Corrupt { page_id })
+  }
+
+  /// Reads a child node from a page, checking that it has the expected height and is not empty.
+  fn read_child<Store: paging::Store>(
+    store: &mut Store,
+    page_id: u64,
+    parent_height: u8,
+  ) -> paging::Result<Self, Store> {
+    let node = Self::read(store, page_id)?;
+    if node.height + 1 != parent_height || node.items.is_empty() {
+      return Err(paging::Error::Corrupt { page_id });
+    }
+    Ok(node)
+  }
+
+  /// Decodes a node from a page.
+  ///
+  /// - Leaf cells contain `(key, value)` pairs, where the value length is multiplied by two. Large
+  ///   values are replaced by first page IDs and indicated by an odd length. In prefixed leaf pages,
+  ///   the last cell contains the common prefix of all keys, which is omitted from the other cells.
+  /// - Internal cells contain `(pointer, key)` pairs, except for the last one, which contains
+  ///   `(pointer, height)`.
+  fn decode(page: &[u8]) -> Option<Self> {
+    let node = Self::decode_unsorted(page)?;
+    let sorted = node.keys.windows(2).all(|pair| pair[0] < pair[1]);
+    sorted.then_some(node)
+  }
+
+  /// Decodes a node from a page, without checking that its keys are sorted.
+  fn decode_unsorted(page: &[u8]) -> Option<Self> {
+    let (page_type, cells) = paging::read_node_page(page)?;
+    let mut node = Self { height: 0, keys: Vec::new(), items: Vec::new() };
+    match page_type {
+      paging::BPLUS_LEAF | paging::BPLUS_PREFIXED_LEAF => {
+        let (prefix, cells) = match page_type {
+          paging::BPLUS_LEAF => (&[][..], &cells[..]),
+          _ => {
+            let (last, cells) = cells.split_last()?;
+            let mut last = *last;
+            let len = paging::take_varint(&mut last)?;
+            let prefix = paging::take_bytes(&mut last, usize::try_from(len).ok()?)?;
+            if !last.is_empty() || prefix.is_empty() || cells.len() < 2 {
+              return None;
+            }
+            (prefix, cells)
+          }
+        };
+        for mut cell in cells.iter().copied() {
+          let len = paging::take_varint(&mut cell)?;
+          let suffix = paging::take_bytes(&mut cell, usize::try_from(len).ok()?)?;
+          node.keys.push([prefix, suffix].concat().into());
+          let tag = paging::take_varint(&mut cell)?;
+          node.items.push(if tag % 2 == 0 {
+            Item::Inline(paging::take_bytes(&mut cell, usize::try_from(tag / 2).ok()?)?.into())
+          } else {
+            Item::Overflow { page_id: paging::take_u64(&mut cell)?, len: tag / 2 }
+          });
+          if !cell.is_empty() {
+            return None;
+          }
+        }
+      }
+      paging::BPLUS_INTERNAL => {
+        let (last, cells) = cells.split_last()?;
+        for mut cell in cells.iter().copied() {
+          node.items.push(Item::Child(paging::take_u64(&mut cell)?));
+          let len = paging::take_varint(&mut cell)?;
+          node.keys.push(paging::take_bytes(&mut cell, usize::try_from(len).ok()?)?.into());
+          if !cell.is_empty() {
+            return None;
+          }
+        }
+        let mut last = *last;
+        node.items.push(Item::Child(paging::take_u64(&mut last)?));
+        match last {
+          &[height] if height > 0 => node.height = height,
+          _ => return None,
+        }
+      }
+      _ => return None,
+    }
+    Some(node)
+  }
+
+  /// Encodes the node into cells.
+  fn cells(&self) -> Vec<Vec<u8>> {
+    let mut cells = Vec::with_capacity(self.items.len() + 1);
+    if self.height == 0 {
+      let prefix = self.prefix();
+      for (key, item) in self.keys.iter().zip(&self.items) {
+        cells.push(Self::leaf_cell(&key[prefix.len()..], item));
+      }
+      if !prefix.is_empty() {
+        cells.push(Self::prefix_cell(prefix));
+      }
+    } else {
+      for (key, item) in self.keys.iter().zip(&self.items) {
+        cells.push(Self::internal_cell(key, item));
+      }
+      let mut cell = self.child_page_id(self.items.len() - 1).to_le_bytes().to_vec();
+      cell.push(self.height);
+      cells.push(cell);
+    }
+    cells
+  }
+
+  /// Encodes a leaf cell.
+  fn leaf_cell(key: &[u8], item: &Item) -> Vec<u8> {
+    let mut cell = Vec::new();
+    prefix_varint::encode(key.len() as u64, &mut cell);
+    cell.extend_from_slice(key);
+    match item {
+      Item::Inline(value) => {
+        prefix_varint::encode(2 * value.len() as u64, &mut cell);
+        cell.extend_from_slice(value);
+      }
+      Item::Overflow { page_id, len } => {
+        prefix_varint::encode(2 * len + 1, &mut cell);
+        cell.extend_from_slice(&page_id.to_le_bytes());
+      }
+      Item::Child(_) => unreachable!(),
+    }
+    cell
+  }
+
+  /// Encodes the last cell of a prefixed leaf, which holds the common prefix of all keys.
+  fn prefix_cell(prefix: &[u8]) -> Vec<u8> {
+    let mut cell = Vec::new();
+    prefix_varint::encode(prefix.len() as u64, &mut cell);
+    cell.extend_from_slice(prefix);
+    cell
+  }
+
+  /// Encodes an internal cell, other than the last one.
+  fn internal_cell(key: &[u8], item: &Item) -> Vec<u8> {
+    let Item::Child(page_id) = item else { unreachable!() };
+    let mut cell = page_id.to_le_bytes().to_vec();
+    prefix_varint::encode(key.len() as u64, &mut cell);
+    cell.extend_from_slice(key);
+    cell
+  }
+
+  /// Returns the size of the leaf cell encoding an entry.
+  fn leaf_cell_size(key: &[u8], item: &Item) -> usize {
+    Self::leaf_cell(key, item).len()
+  }
+
+  /// Writes a node into a page.
+  fn write<Store: paging::Store>(
+    &self,
+    store: &mut Store,
+    page_id: u64,
+  ) -> paging::Result<(), Store> {
+    let page_type = match (self.height, self.prefix().is_empty()) {
+      (0, true) => paging::BPLUS_LEAF,
+      (0, false) => paging::BPLUS_PREFIXED_LEAF,
+      _ => paging::BPLUS_INTERNAL,
+    };
+    let mut page = vec![0; store.page_size()];
+    assert!(paging::write_node_page(&mut page, page_type, &self.cells()));
+    store.write(page_id, &page)
+  }
+
+  /// Returns the common prefix of all keys in a leaf with at least two keys, which is omitted from
+  /// its cells. Returns an empty slice for other nodes.
+  fn prefix(&self) -> &[u8] {
+    match (self.height, &self.keys[..]) {
+      (0, [first, .., last]) => &first[..common_prefix_len(first, last)],
+      _ => &[],
+    }
+  }
+
+  /// Returns the sizes of the cells (including cell pointers) holding each key. In internal nodes,
+  /// this excludes the last cell, which always takes 11 bytes. In leaves, this excludes the cell
+  /// holding the common prefix, and the sizes are computed with the prefix omitted.
+  fn cell_sizes(&self) -> Vec<usize> {
+    self.cell_sizes_with_prefix(self.prefix().len())
+  }
+
+  /// Returns the sizes of the cells holding each key, with the given number of bytes omitted from
+  /// each key.
+  fn cell_sizes_with_prefix(&self, prefix: usize) -> Vec<usize> {
+    let cell = if self.height == 0 { Self::leaf_cell } else { Self::internal_cell };
+    self
+      .keys
+      .iter()
+      .zip(&self.items)
+      .map(|(key, item)| cell(&key[prefix..], item).len() + 2)
+      .collect()
+  }
+
+  /// Returns the number of bytes occupied by the cells of the node (including cell pointers).
+  fn size(&self) -> usize {
+    let last = match self.height {
+      0 if self.prefix().is_empty() => 0,
+      0 => Self::prefix_cell(self.prefix()).len() + 2,
+      _ => LAST_CELL_SIZE,
+    };
+    self.cell_sizes().iter().sum::<usize>() + last
+  }
+
+  /// Returns the number of bytes that the cells of the node would occupy without prefix
+  /// compression. This is used to decide whether a node is at least half full, so that the
+  /// decision does not depend on how well the keys compress.
+  fn uncompressed_size(&self) -> usize {
+    let last = if self.height == 0 { 0 } else { LAST_CELL_SIZE };
+    self.cell_sizes_with_prefix(0).iter().sum::<usize>() + last
+  }
+
+  /// Splits the node into two nodes of roughly equal sizes, returning them along with the key
+  /// separating them.
+  fn split(self) -> (Self, Box<[u8]>, Self) {
+    let sizes = self.cell_sizes();
+    self.split_by(&sizes)
+  }
+
+  /// Splits the node into two nodes of roughly equal sizes, given the sizes of its cells.
+  fn split_by(self, sizes: &[usize]) -> (Self, Box<[u8]>, Self) {
+    let total = sizes.iter().sum::<usize>();
+    // Find the split point which minimizes the difference between the two sizes. In internal
+    // nodes, the key at the split point moves up into the parent.
+    let (mut best, mut before) = (1, sizes[0]);
+    let mut sum = 0;
+    for (i, &size) in sizes.iter().enumerate() {
+      let (left, right) = match self.height {
+        0 => (sum, total - sum),
+        _ => (sum, total - sum - size),
+      };
+      sum += size;
+      if (self.height > 0 || i > 0) && left.abs_diff(right) < before.abs_diff(total - before) {
+        (best, before) = (i, left);
+      }
+    }
+    let Self { height, mut keys, mut items } = self;
+    if height == 0 {
+      let right = Self { height, keys: keys.split_off(best), items: items.split_off(best) };
+      let key = separator(keys.last().unwrap(), &right.keys[0]);
+      (Self { height, keys, items }, key, right)
+    } else {
+      let right = Self { height, keys: keys.split_off(best + 1), items: items.split_off(best + 1) };
+      let key = keys.pop().unwrap();
+      (Self { height, keys, items }, key, right)
+    }
+  }
+
+  /// Splits the node repeatedly until all parts fit into the given capacity, returning the parts
+  /// along with the keys separating them. Splitting a leaf may require more than two parts, since
+  /// removing a key can shorten the common prefix of the remaining keys.
+  fn split_to_fit(self, capacity: usize) -> (Vec<Self>, Vec<Box<[u8]>>) {
+    if self.size() <= capacity {
+      return (vec![self], Vec::new());
+    }
+    let (left, key, right) = self.split();
+    let (mut parts, mut keys) = left.split_to_fit(capacity);
+    let (right_parts, right_keys) = right.split_to_fit(capacity);
+    keys.push(key);
+    parts.extend(right_parts);
+    keys.extend(right_keys);
+    (parts, keys)
+  }
+
+  /// Concatenates two adjacent nodes, given the key separating them in the parent.
+  fn merge(left: Self, key: Box<[u8]>, right: Self) -> Self {
+    let Self { height, mut keys, mut items } = left;
+    if height > 0 {
+      keys.push(key);
+    }
+    keys.extend(right.keys);
+    items.extend(right.items);
+    Self { height, keys, items }
+  }
+
+  /// Returns the index of the child which may contain the given key.
+  fn child_index(&self, key: &[u8]) -> usize {
+    self.keys.partition_point(|k| **k <= *key)
+  }
+
+  /// Returns the page ID of the `i`-th child of an internal node.
+  fn child_page_id(&self, i: usize) -> u64 {
+    match self.items[i] {
+      Item::Child(page_id) => page_id,
+      _ => unreachable!(),
+    }
+  }
+}
+
+/// Returns the length of the common prefix of two keys.
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+  a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+/// Returns the shortest key `key` such that `left < key <= right`, which must be a prefix of
+/// `right`. Requires `left < right`.
+fn separator(left: &[u8], right: &[u8]) -> Box<[u8]> {
+  right[..common_prefix_len(left, right) + 1].into()
+}
+
+/// The size of the last cell of an internal node, which holds `(pointer, height)`, including its
+/// cell pointer.
+const LAST_CELL_SIZE: usize = 11;
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::storage::testing::{open_store, TestStore};
+  use map::{Cursor as _, Map as _};
+  use paging::Store as _;
+  use rand::rngs::StdRng;
+  use rand::{Rng, SeedableRng};
+  use std::collections;
+
+  type TestTree = BasicTree<TestStore>;
+
+  fn key(i: u32) -> Vec<u8> {
+    format!("key{:06}", i).into_bytes()
+  }
+
+  fn value(i: u32, len: usize) -> Vec<u8> {
+    (0..len).map(|j| (i as usize + j) as u8).collect()
+  }
+
+  /// Checks the invariants of the subtree rooted at the given page, returning its keys.
+  fn check(store: &mut TestStore, page_id: u64, root: bool, keys: &mut Vec<Box<[u8]>>) -> u8 {
+    let node = BasicNode::read(store, page_id).unwrap();
+    let capacity = paging::node_page_capacity(512);
+    assert!(node.size() <= capacity);
+    if root {
+      assert!(node.height == 0 || node.items.len() >= 2);
+    } else {
+      assert!(node.uncompressed_size() >= (capacity - max_cell_size(512)) / 2);
+    }
+    if node.height == 0 {
+      keys.extend(node.keys);
+      return 0;
+    }
+    for i in 0..node.items.len() {
+      let start = keys.len();
+      let height = check(store, node.child_page_id(i), false, keys);
+      assert_eq!(height + 1, node.height);
+      assert!(i == 0 || keys[start] >= node.keys[i - 1]);
+      assert!(i == node.keys.len() || keys[keys.len() - 1] < node.keys[i]);
+    }
+    node.height
+  }
+
+  fn collect(store: &mut TestStore, tree: &TestTree) -> Vec<Box<[u8]>> {
+    let mut keys = Vec::new();
+    check(store, tree.root(), true, &mut keys);
+    assert_eq!(tree.verify(store).unwrap(), []);
+    keys
+  }
+
+  #[test]
+  fn test_empty() {
+    let mut store = open_store();
+    let mut tree = TestTree::create(&mut store).unwrap();
+    assert_eq!(tree.get(&mut store, b"a").unwrap(), None);
+    assert!(!tree.remove(&mut store, b"a").unwrap());
+    assert!(collect(&mut store, &tree).is_empty());
+  }
+
+  #[test]
+  fn test_insert_get_remove() {
+    let mut store = open_store();
+    let mut tree = TestTree::create(&mut store).unwrap();
+    let root = tree.root();
+    for i in 0..500 {
+      assert!(!tree.insert(&mut store, &key(i * 7 % 500), &value(i, 10)).unwrap());
+    }
+    for i in 0..500 {
+      let expected = value(i, 10);
+      assert_eq!(tree.get(&mut store, &key(i * 7 % 500)).unwrap().as_deref(), Some(&expected[..]));
+    }
+    assert!(tree.insert(&mut store, &key(42), b"updated").unwrap());
+    assert_eq!(tree.get(&mut store, &key(42)).unwrap().as_deref(), Some(&b"updated"[..]));
+    assert_eq!(collect(&mut store, &tree).len(), 500);
+    for i in 0..500 {
+      assert!(tree.remove(&mut store, &key(i)).unwrap());
+      assert!(!tree.remove(&mut store, &key(i)).unwrap());
+      assert_eq!(tree.get(&mut store, &key(i)).unwrap(), None);
+    }
+    assert!(collect(&mut store, &tree).is_empty());
+    // The root page never moves.
+    assert_eq!(tree.root(), root);
+  }
+
+  #[test]
+  fn test_key_too_large() {
+    let mut store = open_store();
+    let mut tree = TestTree::create(&mut store).unwrap();
+    let res = tree.insert(&mut store, &[0; 65], b"value");
+    assert!(matches!(res, Err(paging::Error::TooLarge { len: 65, max: 64 })));
+    tree.insert(&mut store, &[0; 64], b"value").unwrap();
+    let mut loader = Loader::new(&store, 1.0);
+    let res = loader.push(&mut store, &[0; 65], b"value");
+    assert!(matches!(res, Err(paging::Error::TooLarge { .. })));
+    loader.abort(&mut store).unwrap();
+  }
+
+  #[test]
+  fn test_random_operations() {
+    // Test against a reference implementation, checking invariants along the way.
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut store = open_store();
+    let mut tree = TestTree::create(&mut store).unwrap();
+    let mut model = collections::BTreeMap::new();
+    for round in 0..5000 {
+      let i = rng.gen_range(0..600);
+      if rng.gen_bool(0.55) {
+        // Include keys and values of varying sizes.
+        let key = [key(i), vec![b'.'; rng.gen_range(0..50)]].concat();
+        let value = value(round, rng.gen_range(0..150));
+        let present = model.insert(key.clone(), value.clone()).is_some();
+        assert_eq!(tree.insert(&mut store, &key, &value).unwrap(), present);
+      } else if let Some(key) = model.range(key(i)..).next().map(|(k, _)| k.clone()) {
+        model.remove(&key);
+        assert!(tree.remove(&mut store, &key).unwrap());
+      }
+      if round % 500 == 0 {
+        let keys = collect(&mut store, &tree);
+        assert!(keys.iter().map(|k| &k[..]).eq(model.keys().map(|k| &k[..])));
+      }
+    }
+    for (key, value) in &model {
+      assert_eq!(tree.get(&mut store, key).unwrap().as_deref(), Some(&value[..]));
+    }
+  }
+
+  #[test]
+  fn test_cursor_bounds() {
+    let mut store = open_store();
+    let mut tree = TestTree::create(&mut store).unwrap();
+    for i in 0..200 {
+      tree.insert(&mut store, &key(2 * i), &value(i, 4)).unwrap();
+    }
+    let k = |i: u32| key(i);
+    // Included and excluded bounds, on both present and absent keys.
+    let cases = vec![
+      (ops::Bound::Included(k(100)), Some(98), Some(100)),
+      (ops::Bound::Excluded(k(100)), Some(100), Some(102)),
+      (ops::Bound::Included(k(101)), Some(100), Some(102)),
+      (ops::Bound::Excluded(k(101)), Some(100), Some(102)),
+      (ops::Bound::Included(k(0)), None, Some(0)),
+      (ops::Bound::Included(k(398)), Some(396), Some(398)),
+      (ops::Bound::Excluded(k(398)), Some(398), None),
+      (ops::Bound::Unbounded, None, Some(0)),
+    ];
+    for (bound, before, after) in cases {
+      let mut cursor = tree.lower_bound(&mut store, bound.as_ref().map(|k| &k[..])).unwrap();
+      assert_eq!(cursor.peek_prev(&mut store).unwrap().map(|(k, _)| k.to_vec()), before.map(key));
+      assert_eq!(cursor.peek_next(&mut store).unwrap().map(|(k, _)| k.to_vec()), after.map(key));
+    }
+    let cases = vec![
+      (ops::Bound::Included(k(100)), Some(100), Some(102)),
+      (ops::Bound::Excluded(k(100)), Some(98), Some(100)),
+      (ops::Bound::Included(k(101)), Some(100), Some(102)),
+      (ops::Bound::Excluded(k(0)), None, Some(0)),
+      (ops::Bound::Included(k(999)), Some(398), None),
+      (ops::Bound::Unbounded, Some(398), None),
+    ];
+    for (bound, before, after) in cases {
+      let mut cursor = tree.upper_bound(&mut store, bound.as_ref().map(|k| &k[..])).unwrap();
+      assert_eq!(cursor.peek_prev(&mut store).unwrap().map(|(k, _)| k.to_vec()), before.map(key));
+      assert_eq!(cursor.peek_next(&mut store).unwrap().map(|(k, _)| k.to_vec()), after.map(key));
+    }
+  }
+
+  #[test]
+  fn test_cursor_back_and_forth() {
+    let mut store = open_store();
+    let mut tree = TestTree::create(&mut store).unwrap();
+    let mut cursor = tree.lower_bound(&mut store, ops::Bound::Unbounded).unwrap();
+    assert!(cursor.next(&mut store).unwrap().is_none());
+    assert!(cursor.prev(&mut store).unwrap().is_none());
+    // Include some values stored in overflow pages.
+    let len = |i: u32| if i.is_multiple_of(50) { 1000 } else { 4 };
+    for i in 0..300 {
+      tree.insert(&mut store, &key(i), &value(i, len(i))).unwrap();
+    }
+    let mut cursor = tree.upper_bound(&mut store, ops::Bound::Unbounded).unwrap();
+    assert!(cursor.next(&mut store).unwrap().is_none());
+    for i in (0..300).rev() {
+      let expected = (&key(i)[..], &value(i, len(i))[..]);
+      assert_eq!(cursor.prev(&mut store).unwrap(), Some(expected));
+    }
+    assert!(cursor.prev(&mut store).unwrap().is_none());
+    for i in 0..150 {
+      let expected = (&key(i)[..], &value(i, len(i))[..]);
+      assert_eq!(cursor.next(&mut store).unwrap(), Some(expected));
+    }
+    assert_eq!(cursor.prev(&mut store).unwrap().unwrap().0, &key(149)[..]);
+    assert_eq!(cursor.next(&mut store).unwrap().unwrap().0, &key(149)[..]);
+  }
+
+  #[test]
+  fn test_prefix_compression() {
+    let mut store = open_store();
+    let mut tree = TestTree::create(&mut store).unwrap();
+    // Without compression, only 7 of these would fit into a page.
+    let prefix = [b'p'; 56];
+    let long_key = |i: u32| [&prefix[..], format!("{:04}", i).as_bytes()].concat();
+    for i in 0..40 {
+      tree.insert(&mut store, &long_key(i), b"v").unwrap();
+    }
+    let root = BasicNode::read(&mut store, tree.root()).unwrap();
+    assert_eq!(root.height, 0);
+    assert_eq!(u16::from_le_bytes(store.get(tree.root()).unwrap()[0..2].try_into().unwrap()), 5);
+    // A key without the common prefix makes the leaf split into more than two parts.
+    tree.insert(&mut store, b"q", b"v").unwrap();
+    let root = BasicNode::read(&mut store, tree.root()).unwrap();
+    assert!(root.items.len() > 2);
+    assert_eq!(collect(&mut store, &tree).len(), 41);
+    // Test against a reference implementation with a few groups of keys sharing long prefixes.
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut model =
+      (0..40).map(|i| (long_key(i), b"v".to_vec())).collect::<collections::BTreeMap<_, _>>();
+    model.insert(b"q".to_vec(), b"v".to_vec());
+    for round in 0..3000 {
+      let group = [b'a' + rng.gen_range(0..3); 50];
+      let key = [&group[..], format!("{:04}", rng.gen_range(0..300)).as_bytes()].concat();
+      if rng.gen_bool(0.6) {
+        let value = value(round, rng.gen_range(0..20));
+        let present = model.insert(key.clone(), value.clone()).is_some();
+        assert_eq!(tree.insert(&mut store, &key, &value).unwrap(), present);
+      } else {
+        assert_eq!(tree.remove(&mut store, &key).unwrap(), model.remove(&key).is_some());
+      }
+      if round % 300 == 0 {
+        let keys = collect(&mut store, &tree);
+        assert!(keys.iter().map(|k| &k[..]).eq(model.keys().map(|k| &k[..])));
+      }
+    }
+    for (key, value) in &model {
+      assert_eq!(tree.get(&mut store, key).unwrap().as_deref(), Some(&value[..]));
+    }
+  }
+
+  #[test]
+  fn test_suffix_truncation() {
+    let mut store = open_store();
+    let mut tree = TestTree::create(&mut store).unwrap();
+    let long_key = |i: u32| format!("{:06}{}", i, "x".repeat(40)).into_bytes();
+    for i in 0..300 {
+      tree.insert(&mut store, &long_key(i), b"v").unwrap();
+    }
+    // Separators only need to distinguish the numbers.
+    let root = BasicNode::read(&mut store, tree.root()).unwrap();
+    assert!(root.height > 0);
+    assert!(root.keys.iter().all(|key| key.len() <= 6));
+    assert_eq!(collect(&mut store, &tree).len(), 300);
+  }
+
+  #[test]
+  fn test_overflow_values() {
+    let mut store = open_store();
+    let mut tree = TestTree::create(&mut store).unwrap();
+    for i in 0..50 {
+      tree.insert(&mut store, &key(i), &value(i, i as usize * 37)).unwrap();
+    }
+    for i in 0..50 {
+      let expected = value(i, i as usize * 37);
+      assert_eq!(tree.get(&mut store, &key(i)).unwrap().as_deref(), Some(&expected[..]));
+    }
+  }
+
+  #[test]
+  fn test_pages_reused() {
+    let mut store = open_store();
+    let mut tree = TestTree::create(&mut store).unwrap();
+    let mut page_count = 0;
+    for round in 0..5 {
+      for i in 0..300 {
+        tree.insert(&mut store, &key(i), &value(round, 100)).unwrap();
+      }
+      for i in 0..300 {
+        tree.remove(&mut store, &key(i)).unwrap();
+      }
+      // All pages are reused after the first round.
+      if round == 0 {
+        page_count = store.page_count();
+      }
+      assert_eq!(store.page_count(), page_count);
+    }
+  }
+
+  #[test]
+  fn test_destroy() {
+    let mut store = open_store();
+    let page_count = store.page_count();
+    let mut tree = TestTree::create(&mut store).unwrap();
+    for i in 0..300 {
+      tree.insert(&mut store, &key(i), &value(i, i as usize % 200)).unwrap();
+    }
+    tree.destroy(&mut store).unwrap();
+    // All pages are reused by a tree with the same content.
+    let grown = store.page_count();
+    let mut tree = TestTree::create(&mut store).unwrap();
+    for i in 0..300 {
+      tree.insert(&mut store, &key(i), &value(i, i as usize % 200)).unwrap();
+    }
+    assert_eq!(store.page_count(), grown);
+    assert!(grown > page_count);
+  }
+
+  /// Returns the page IDs of all leaves in the subtree rooted at the given page, in key order.
+  fn leaf_pages(store: &mut TestStore, page_id: u64, pages: &mut Vec<u64>) {
+    let node = BasicNode::read(store, page_id).unwrap();
+    if node.height == 0 {
+      return pages.push(page_id);
+    }
+    for i in 0..node.items.len() {
+      leaf_pages(store, node.child_page_id(i), pages);
+    }
+  }
+
+  #[test]
+  fn test_bulk_load() {
+    for (count, fill) in [(0, 1.0), (1, 1.0), (20, 0.5), (1000, 1.0), (3000, 0.7), (3000, 0.01)] {
+      let mut store = open_store();
+      let entries = (0..count).map(|i| (key(i), value(i, i as usize * 7 % 150)));
+      let mut tree = TestTree::bulk_load(&mut store, entries, fill).unwrap();
+      let keys = collect(&mut store, &tree);
+      assert!(keys.iter().map(|key| &key[..]).eq((0..count).map(key).collect::<Vec<_>>().iter()));
+      for i in 0..count {
+        let expected = value(i, i as usize * 7 % 150);
+        assert_eq!(tree.get(&mut store, &key(i)).unwrap().as_deref(), Some(&expected[..]));
+      }
+      // The tree remains valid under further modifications.
+      for i in (0..count).step_by(3) {
+        assert!(tree.remove(&mut store, &key(i)).unwrap());
+      }
+      for i in count..count + 100 {
+        assert!(!tree.insert(&mut store, &key(i), &value(i, 10)).unwrap());
+      }
+      let keys = collect(&mut store, &tree);
+      assert_eq!(keys.len(), (count - count.div_ceil(3) + 100) as usize);
+    }
+  }
+
+  #[test]
+  fn test_bulk_load_overflow_values() {
+    let mut store = open_store();
+    let entries = (0..50).map(|i| (key(i), value(i, i as usize * 37)));
+    let tree = TestTree::bulk_load(&mut store, entries, 1.0).unwrap();
+    collect(&mut store, &tree);
+    for i in 0..50 {
+      let expected = value(i, i as usize * 37);
+      assert_eq!(tree.get(&mut store, &key(i)).unwrap().as_deref(), Some(&expected[..]));
+    }
+  }
+
+  #[test]
+  fn test_bulk_load_sequential_pages() {
+    let mut store = open_store();
+    let first = store.page_count();
+    let entries = (0..2000).map(|i| (key(i), value(i, 20)));
+    let tree = TestTree::bulk_load(&mut store, entries, 1.0).unwrap();
+    collect(&mut store, &tree);
+    let mut pages = Vec::new();
+    leaf_pages(&mut store, tree.root(), &mut pages);
+    // Leaves are allocated in key order, followed by internal nodes and the root.
+    assert!(pages.iter().copied().eq(first..first + pages.len() as u64));
+    assert_eq!(tree.root(), store.page_count() - 1);
+  }
+
+  #[test]
+  fn test_bulk_load_fill_factor() {
+    let mut leaves = Vec::new();
+    for fill in [0.6, 0.8, 1.0] {
+      let mut store = open_store();
+      let entries = (0..2000).map(|i| (key(i), value(i, 20)));
+      let tree = TestTree::bulk_load(&mut store, entries, fill).unwrap();
+      let mut pages = Vec::new();
+      leaf_pages(&mut store, tree.root(), &mut pages);
+      leaves.push(pages.len());
+    }
+    assert!(leaves[0] > leaves[1] && leaves[1] > leaves[2]);
+  }
+
+  #[test]
+  fn test_loader_abort() {
+    let entries = || (0..300).map(|i| (key(i), value(i, i as usize % 200)));
+    let mut store = open_store();
+    TestTree::bulk_load(&mut store, entries(), 1.0).unwrap();
+    let page_count = store.page_count();
+    // All pages written before aborting are reused.
+    let mut store = open_store();
+    let mut loader = Loader::new(&store, 1.0);
+    for (key, value) in entries() {
+      loader.push(&mut store, &key, &value).unwrap();
+    }
+    loader.abort(&mut store).unwrap();
+    TestTree::bulk_load(&mut store, entries(), 1.0).unwrap();
+    assert_eq!(store.page_count(), page_count);
+  }
+
+  #[test]
+  #[should_panic(expected = "keys must be strictly increasing")]
+  fn test_loader_unsorted() {
+    let mut store = open_store();
+    let mut loader = Loader::new(&store, 1.0);
+    loader.push(&mut store, &key(1), b"").unwrap();
+    loader.push(&mut store, &key(1), b"").unwrap();
+  }
+
+  /// Builds a tree with three levels, modifies it, and returns the problems found along with the
+  /// problem expected by the modification.
+  fn verify_modified(
+    modify: impl FnOnce(&mut TestStore, u64, BasicNode) -> Problem,
+  ) -> (Vec<Problem>, Problem) {
+    let mut store = open_store();
+    let entries = (0..3000).map(|i| (key(i), value(i, i as usize % 100)));
+    let tree = TestTree::bulk_load(&mut store, entries, 1.0).unwrap();
+    let root = BasicNode::read(&mut store, tree.root()).unwrap();
+    assert_eq!(root.height, 2);
+    let expected = modify(&mut store, tree.root(), root);
+    (tree.verify(&mut store).unwrap(), expected)
+  }
+
+  /// Reads the `i`-th child of a node.
+  fn read_child(store: &mut TestStore, node: &BasicNode, i: usize) -> (u64, BasicNode) {
+    let page_id = node.child_page_id(i);
+    (page_id, BasicNode::read_unsorted(store, page_id).unwrap())
+  }
+
+  #[test]
+  fn test_verify() {
+    let (problems, expected) = verify_modified(|store, root_page_id, mut root| {
+      let page_id = root.child_page_id(0);
+      root.items[1] = Item::Child(page_id);
+      root.write(store, root_page_id).unwrap();
+      Problem::Shared { page_id }
+    });
+    assert!(problems.contains(&expected));
+    let (problems, expected) = verify_modified(|store, root_page_id, mut root| {
+      root.keys.clear();
+      root.items.truncate(1);
+      root.write(store, root_page_id).unwrap();
+      Problem::SingleChild { page_id: root_page_id }
+    });
+    assert_eq!(problems, [expected]);
+    let (problems, expected) = verify_modified(|store, _, root| {
+      let page_id = root.child_page_id(0);
+      store.write(page_id, &[0xCC; 512]).unwrap();
+      Problem::Corrupt { page_id }
+    });
+    assert_eq!(problems, [expected]);
+    let (problems, expected) = verify_modified(|store, _, root| {
+      let (page_id, mut node) = read_child(store, &root, 0);
+      node.keys.swap(0, 1);
+      node.write(store, page_id).unwrap();
+      Problem::Unsorted { page_id }
+    });
+    assert!(problems.contains(&expected));
+    let (problems, expected) = verify_modified(|store, _, root| {
+      let (_, node) = read_child(store, &root, 1);
+      let (page_id, mut leaf) = read_child(store, &node, 0);
+      leaf.keys[0] = key(0).into();
+      leaf.write(store, page_id).unwrap();
+      Problem::OutOfRange { page_id, key: key(0).into() }
+    });
+    assert_eq!(problems, [expected]);
+  }
+
+  #[test]
+  fn test_verify_balance() {
+    let (problems, expected) = verify_modified(|store, _, root| {
+      let (page_id, mut node) = read_child(store, &root, 0);
+      node.height = 3;
+      node.write(store, page_id).unwrap();
+      Problem::Height { page_id, expected: 1, found: 3 }
+    });
+    assert!(problems.contains(&expected));
+    let (problems, expected) = verify_modified(|store, root_page_id, mut root| {
+      let (_, node) = read_child(store, &root, 1);
+      let page_id = node.child_page_id(0);
+      root.items[1] = Item::Child(page_id);
+      root.write(store, root_page_id).unwrap();
+      Problem::Depth { page_id, expected: 2, found: 1 }
+    });
+    assert!(problems.contains(&expected));
+    let (problems, expected) = verify_modified(|store, _, root| {
+      let (_, node) = read_child(store, &root, 0);
+      let (page_id, mut leaf) = read_child(store, &node, 0);
+      leaf.keys.truncate(1);
+      leaf.items.truncate(1);
+      leaf.write(store, page_id).unwrap();
+      let size = leaf.uncompressed_size();
+      let min_size = (paging::node_page_capacity(512) - max_cell_size(512)) / 2;
+      Problem::Underfull { page_id, size, min_size }
+    });
+    assert_eq!(problems, [expected]);
+  }
+
+  #[test]
+  fn test_corrupt_page() {
+    let mut store = open_store();
+    let mut tree = TestTree::create(&mut store).unwrap();
+    for i in 0..100 {
+      tree.insert(&mut store, &key(i), &value(i, 4)).unwrap();
+    }
+    let root = BasicNode::read(&mut store, tree.root()).unwrap();
+    let child = root.child_page_id(0);
+    store.write(child, &[0xCC; 512]).unwrap();
+    assert!(
+      matches!(tree.get(&mut store, &key(0)), Err(paging::Error::Corrupt { page_id }) if page_id == child)
+    );
+  }
+}
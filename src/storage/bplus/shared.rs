@@ -0,0 +1,531 @@
+//! # Concurrent access to B+ trees
+//!
+//! A [`SharedTree`] allows any number of threads to read a [`BasicTree`] while another thread
+//! modifies it. Readers descend the tree with latch coupling: a shared latch is taken on each child
+//! before the latch on its parent is released. Writers are serialised, and compute their changes
+//! on a private overlay of the store before installing them under exclusive latches on only the
+//! pages they modify, so that splitting a leaf does not block readers in other parts of the tree.
+
+use super::{BasicNode, BasicTree, Item};
+use crate::storage::{map, paging};
+use std::cmp;
+use std::collections;
+use std::ops;
+use std::sync;
+
+/// # Shared B+ tree
+///
+/// A wrapper around a store holding a [`BasicTree`], with methods taking `&self` which may be called
+/// from multiple threads at the same time.
+///
+/// Lookups and each leaf visited by a range scan observe the tree either entirely before or
+/// entirely after any modification. A range scan as a whole is not a snapshot: it may observe
+/// modifications made while it is in progress.
+///
+/// Unlike [`BasicTree`], a modifying method which returns an error leaves the tree unchanged,
+/// except for write errors while the changes are being installed.
+///
+/// ## Implementation notes
+///
+/// Latches are always acquired from the root downwards, and among pages at the same height in
+/// ascending order of page IDs, so readers and the writer cannot deadlock. Waiting writers take
+/// precedence over new readers of the same page, so a stream of readers cannot starve the writer.
+pub struct SharedTree<Store: paging::Store> {
+  root: u64,
+  store: sync::Mutex<Store>,
+  latches: Latches,
+  writer: sync::Mutex<()>,
+}
+
+impl<Store: paging::Store> SharedTree<Store> {
+  /// Wraps a store holding the given tree.
+  pub fn new(store: Store, tree: BasicTree<Store>) -> Self {
+    Self {
+      root: tree.root(),
+      store: sync::Mutex::new(store),
+      latches: Latches::default(),
+      writer: sync::Mutex::new(()),
+    }
+  }
+
+  /// Returns the root page ID of the tree.
+  pub fn root(&self) -> u64 {
+    self.root
+  }
+
+  /// Returns the store holding the tree.
+  pub fn into_inner(self) -> Store {
+    self.store.into_inner().unwrap()
+  }
+
+  /// Returns a copy of the value corresponding to the key.
+  pub fn get(&self, key: &[u8]) -> paging::Result<Option<Box<[u8]>>, Store> {
+    let (_guard, leaf, _) = self.descend(ops::Bound::Included(key))?;
+    match leaf.keys.binary_search_by(|k| (**k).cmp(key)) {
+      Ok(i) => Ok(Some(self.value(&leaf.items[i])?)),
+      Err(_) => Ok(None),
+    }
+  }
+
+  /// Returns copies of all key-value pairs in the given range, in ascending order of keys.
+  pub fn range<'a>(
+    &self,
+    range: impl ops::RangeBounds<&'a [u8]>,
+  ) -> paging::Result<Vec<map::OwnedElement>, Store> {
+    let mut res = Vec::new();
+    let mut start = range.start_bound().map(|key| Box::<[u8]>::from(*key));
+    loop {
+      let (guard, leaf, fence) = self.descend(start.as_ref().map(|key| &key[..]))?;
+      let i = match &start {
+        ops::Bound::Included(key) => leaf.keys.partition_point(|k| k < key),
+        ops::Bound::Excluded(key) => leaf.keys.partition_point(|k| k <= key),
+        ops::Bound::Unbounded => 0,
+      };
+      for (key, item) in leaf.keys[i..].iter().zip(&leaf.items[i..]) {
+        if !range.contains(&&key[..]) {
+          return Ok(res);
+        }
+        res.push((key.clone(), self.value(item)?));
+      }
+      drop(guard);
+      // All remaining keys are in the leaves after the fence key, if any.
+      match fence {
+        Some(fence) if range.contains(&&fence[..]) => start = ops::Bound::Included(fence),
+        _ => return Ok(res),
+      }
+    }
+  }
+
+  /// Inserts or updates a key-value pair in the tree. Returns whether the key was present.
+  pub fn insert(&self, key: &[u8], value: &[u8]) -> paging::Result<bool, Store> {
+    self.modify(|tree, store| map::Map::insert(tree, store, key, value))
+  }
+
+  /// Removes a key-value pair from the tree. Returns whether the key was present.
+  pub fn remove(&self, key: &[u8]) -> paging::Result<bool, Store> {
+    self.modify(|tree, store| map::Map::remove(tree, store, key))
+  }
+
+  /// Reads a node from a page. The caller must hold a latch on the page.
+  fn read(&self, page_id: u64, parent_height: Option<u8>) -> paging::Result<BasicNode, Store> {
+    let mut store = self.store.lock().unwrap();
+    match parent_height {
+      Some(height) => BasicNode::read_child(&mut *store, page_id, height),
+      None => BasicNode::read(&mut *store, page_id),
+    }
+  }
+
+  /// Returns a copy of the value of a leaf item. The caller must hold a latch on the leaf.
+  fn value(&self, item: &Item) -> paging::Result<Box<[u8]>, Store> {
+    match item {
+      Item::Inline(value) => Ok(value.clone()),
+      Item::Overflow { page_id, len } => {
+        let mut store = self.store.lock().unwrap();
+        Ok(paging::read_overflow(&mut *store, *page_id, *len)?.into())
+      }
+      Item::Child(_) => unreachable!(),
+    }
+  }
+
+  /// Descends from the root to the leaf which may contain the smallest key within the given lower
+  /// bound. Returns a shared latch on the leaf, the leaf, and the smallest separator key greater
+  /// than all keys in the leaf, if there is one.
+  fn descend(&self, start: ops::Bound<&[u8]>) -> paging::Result<Descent<'_>, Store> {
+    let mut guard = self.latches.acquire(self.root, false);
+    let mut node = self.read(self.root, None)?;
+    let mut fence = None;
+    while node.height > 0 {
+      let i = match start {
+        ops::Bound::Included(key) | ops::Bound::Excluded(key) => node.child_index(key),
+        ops::Bound::Unbounded => 0,
+      };
+      if i < node.keys.len() {
+        fence = Some(node.keys[i].clone());
+      }
+      let page_id = node.child_page_id(i);
+      let child_guard = self.latches.acquire(page_id, false);
+      node = self.read(page_id, Some(node.height))?;
+      guard = child_guard;
+    }
+    Ok((guard, node, fence))
+  }
+
+  /// Runs a modification on a private overlay of the store, then installs its changes.
+  fn modify<'a, T>(
+    &'a self,
+    f: impl FnOnce(
+      &mut BasicTree<Pending<'a, Store>>,
+      &mut Pending<'a, Store>,
+    ) -> paging::Result<T, Store>,
+  ) -> paging::Result<T, Store> {
+    let _writer = self.writer.lock().unwrap();
+    let mut pending = Pending::new(&self.store);
+    match f(&mut BasicTree::open(self.root), &mut pending) {
+      Ok(res) => {
+        pending.install(&self.latches)?;
+        Ok(res)
+      }
+      Err(err) => {
+        pending.discard()?;
+        Err(err)
+      }
+    }
+  }
+}
+
+/// A shared latch on a leaf, the leaf, and the smallest separator key greater than all its keys.
+type Descent<'a> = (LatchGuard<'a>, BasicNode, Option<Box<[u8]>>);
+
+/// A store which buffers modifications to a shared store until they are installed.
+///
+/// Pages are allocated from the shared store right away, since no reader can reach them before the
+/// changes are installed. Deallocations are deferred, since readers may still be reading the pages.
+struct Pending<'a, Store: paging::Store> {
+  store: &'a sync::Mutex<Store>,
+  page_size: usize,
+  original: collections::HashMap<u64, Box<[u8]>>,
+  written: collections::HashMap<u64, Box<[u8]>>,
+  allocated: collections::BTreeSet<u64>,
+  deallocated: collections::BTreeSet<u64>,
+}
+
+impl<'a, Store: paging::Store> Pending<'a, Store> {
+  fn new(store: &'a sync::Mutex<Store>) -> Self {
+    let page_size = store.lock().unwrap().page_size();
+    Self {
+      store,
+      page_size,
+      original: collections::HashMap::new(),
+      written: collections::HashMap::new(),
+      allocated: collections::BTreeSet::new(),
+      deallocated: collections::BTreeSet::new(),
+    }
+  }
+
+  /// Writes the buffered modifications to the shared store, holding exclusive latches on all
+  /// modified nodes which may be reachable by readers.
+  fn install(self, latches: &Latches) -> paging::Result<(), Store> {
+    let mut nodes = Vec::new();
+    for &page_id in self.written.keys().chain(&self.deallocated) {
+      if self.allocated.contains(&page_id) {
+        continue;
+      }
+      let node = match self.original.get(&page_id) {
+        Some(page) => BasicNode::decode(page),
+        None => BasicNode::decode(self.store.lock().unwrap().get(page_id)?),
+      };
+      if let Some(node) = node {
+        nodes.push((cmp::Reverse(node.height), page_id));
+      }
+    }
+    nodes.sort_unstable();
+    nodes.dedup();
+    let _guards =
+      nodes.iter().map(|&(_, page_id)| latches.acquire(page_id, true)).collect::<Vec<_>>();
+    let mut store = self.store.lock().unwrap();
+    for (page_id, page) in &self.written {
+      store.write(*page_id, page)?;
+    }
+    for &page_id in &self.deallocated {
+      store.deallocate(page_id)?;
+    }
+    Ok(())
+  }
+
+  /// Returns the newly allocated pages to the shared store, discarding all modifications.
+  fn discard(self) -> paging::Result<(), Store> {
+    let mut store = self.store.lock().unwrap();
+    for &page_id in &self.allocated {
+      store.deallocate(page_id)?;
+    }
+    Ok(())
+  }
+}
+
+impl<Store: paging::Store> paging::Store for Pending<'_, Store> {
+  type File = Store::File;
+
+  fn page_size(&self) -> usize {
+    self.page_size
+  }
+
+  fn get(&mut self, page_id: u64) -> paging::Result<&[u8], Self> {
+    if self.written.contains_key(&page_id) {
+      return Ok(&self.written[&page_id]);
+    }
+    if !self.original.contains_key(&page_id) {
+      let page = self.store.lock().unwrap().get(page_id)?.into();
+      self.original.insert(page_id, page);
+    }
+    Ok(&self.original[&page_id])
+  }
+
+  fn write(&mut self, page_id: u64, data: &[u8]) -> paging::Result<(), Self> {
+    assert_eq!(data.len(), self.page_size);
+    self.written.insert(page_id, data.into());
+    Ok(())
+  }
+
+  fn allocate(&mut self) -> paging::Result<u64, Self> {
+    let page_id = self.store.lock().unwrap().allocate()?;
+    self.allocated.insert(page_id);
+    Ok(page_id)
+  }
+
+  fn deallocate(&mut self, page_id: u64) -> paging::Result<(), Self> {
+    self.written.remove(&page_id);
+    self.deallocated.insert(page_id);
+    Ok(())
+  }
+}
+
+/// A reader-writer latch on a page, which prefers waiting writers over new readers.
+#[derive(Default)]
+struct Latch {
+  state: sync::Mutex<LatchState>,
+  released: sync::Condvar,
+}
+
+#[derive(Default)]
+struct LatchState {
+  readers: usize,
+  writer: bool,
+  waiting_writers: usize,
+}
+
+/// A latch held by the current thread, released when dropped.
+struct LatchGuard<'a> {
+  latches: &'a Latches,
+  page_id: u64,
+  latch: sync::Arc<Latch>,
+  exclusive: bool,
+}
+
+impl Drop for LatchGuard<'_> {
+  fn drop(&mut self) {
+    let mut state = self.latch.state.lock().unwrap();
+    if self.exclusive {
+      state.writer = false;
+    } else {
+      state.readers -= 1;
+    }
+    self.latch.released.notify_all();
+    drop(state);
+    // Other threads can only obtain the latch through the table, so if only the table and this
+    // guard refer to it, nobody else holds or waits on it.
+    let mut table = self.latches.table.lock().unwrap();
+    if sync::Arc::strong_count(&self.latch) == 2 {
+      table.remove(&self.page_id);
+    }
+  }
+}
+
+/// The latches on all pages which are currently latched or waited on.
+#[derive(Default)]
+struct Latches {
+  table: sync::Mutex<collections::HashMap<u64, sync::Arc<Latch>>>,
+}
+
+impl Latches {
+  /// Acquires a shared or exclusive latch on a page, blocking until it is available.
+  fn acquire(&self, page_id: u64, exclusive: bool) -> LatchGuard<'_> {
+    let latch = self.table.lock().unwrap().entry(page_id).or_default().clone();
+    let mut state = latch.state.lock().unwrap();
+    if exclusive {
+      state.waiting_writers += 1;
+      while state.writer || state.readers > 0 {
+        state = latch.released.wait(state).unwrap();
+      }
+      state.waiting_writers -= 1;
+      state.writer = true;
+    } else {
+      while state.writer || state.waiting_writers > 0 {
+        state = latch.released.wait(state).unwrap();
+      }
+      state.readers += 1;
+    }
+    drop(state);
+    LatchGuard { latches: self, page_id, latch, exclusive }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::storage::testing::{open_store, TestStore};
+  use rand::rngs::StdRng;
+  use rand::{Rng, SeedableRng};
+  use std::sync::atomic;
+  use std::thread;
+
+  fn open_tree() -> SharedTree<TestStore> {
+    let mut store = open_store();
+    let tree = BasicTree::create(&mut store).unwrap();
+    SharedTree::new(store, tree)
+  }
+
+  fn key(i: u32) -> Vec<u8> {
+    format!("key{:06}", i).into_bytes()
+  }
+
+  /// Returns a value which starts with the key, so that readers can check that they never see the
+  /// value of another key. Some values go into overflow pages.
+  fn value(key: &[u8], version: u32) -> Vec<u8> {
+    let mut value = key.to_vec();
+    value.resize(key.len() + (version as usize * 37) % 200, version as u8);
+    value
+  }
+
+  /// Checks that the elements are sorted and that each value belongs to its key.
+  fn check_elements(elements: &[map::OwnedElement]) {
+    for pair in elements.windows(2) {
+      assert!(pair[0].0 < pair[1].0);
+    }
+    for (key, value) in elements {
+      assert!(value.starts_with(key));
+    }
+  }
+
+  #[test]
+  fn test_insert_get_remove() {
+    let tree = open_tree();
+    let mut model = collections::BTreeMap::new();
+    let mut rng = StdRng::seed_from_u64(0);
+    for round in 0..2000 {
+      let key = key(rng.gen_range(0..500));
+      if rng.gen_bool(0.6) {
+        let value = value(&key, round);
+        let present = model.insert(key.clone(), value.clone()).is_some();
+        assert_eq!(tree.insert(&key, &value).unwrap(), present);
+      } else {
+        assert_eq!(tree.remove(&key).unwrap(), model.remove(&key).is_some());
+      }
+      assert_eq!(tree.get(&key).unwrap().as_deref(), model.get(&key).map(|v| &v[..]));
+    }
+    let (start, end) = (key(100), key(400));
+    let expected = model
+      .range(start.clone()..end.clone())
+      .map(|(k, v)| (k[..].into(), v[..].into()))
+      .collect::<Vec<map::OwnedElement>>();
+    assert_eq!(tree.range(&start[..]..&end[..]).unwrap(), expected);
+    let expected = model.iter().map(|(k, v)| (k[..].into(), v[..].into())).collect::<Vec<_>>();
+    assert_eq!(tree.range(..).unwrap(), expected);
+    // The underlying tree has the same content.
+    let root = tree.root();
+    let mut store = tree.into_inner();
+    let inner = BasicTree::<TestStore>::open(root);
+    for (key, value) in &model {
+      assert_eq!(map::Map::get(&inner, &mut store, key).unwrap().as_deref(), Some(&value[..]));
+    }
+  }
+
+  #[test]
+  fn test_concurrent_readers_and_writers() {
+    const KEYS: u32 = 1000;
+    let tree = open_tree();
+    for i in 0..KEYS {
+      tree.insert(&key(i), &value(&key(i), 0)).unwrap();
+    }
+    let done = atomic::AtomicBool::new(false);
+    let models = thread::scope(|s| {
+      let readers = (0..4)
+        .map(|t| {
+          let (tree, done) = (&tree, &done);
+          s.spawn(move || {
+            let mut rng = StdRng::seed_from_u64(100 + t);
+            let mut rounds = 0;
+            while !done.load(atomic::Ordering::Relaxed) || rounds < 100 {
+              let i = rng.gen_range(0..KEYS);
+              let (key, end) = (key(i), key(i + rng.gen_range(0..100)));
+              if let Some(value) = tree.get(&key).unwrap() {
+                assert!(value.starts_with(&key));
+              }
+              check_elements(&tree.range(&key[..]..&end[..]).unwrap());
+              rounds += 1;
+            }
+          })
+        })
+        .collect::<Vec<_>>();
+      // Each writer owns the keys congruent to its index modulo 2.
+      let writers = (0..2)
+        .map(|t| {
+          let tree = &tree;
+          s.spawn(move || {
+            let mut rng = StdRng::seed_from_u64(t);
+            let mut model = collections::BTreeMap::new();
+            for i in (t as u32..KEYS).step_by(2) {
+              model.insert(key(i), value(&key(i), 0));
+            }
+            for round in 1..1000 {
+              let key = key(rng.gen_range(0..KEYS / 2) * 2 + t as u32);
+              if rng.gen_bool(0.5) {
+                let value = value(&key, round);
+                let present = model.insert(key.clone(), value.clone()).is_some();
+                assert_eq!(tree.insert(&key, &value).unwrap(), present);
+              } else {
+                assert_eq!(tree.remove(&key).unwrap(), model.remove(&key).is_some());
+              }
+            }
+            model
+          })
+        })
+        .collect::<Vec<_>>();
+      // Stop the readers before checking the writers, so that a failing writer does not hang.
+      let models = writers.into_iter().map(|w| w.join()).collect::<Vec<_>>();
+      done.store(true, atomic::Ordering::Relaxed);
+      for reader in readers {
+        reader.join().unwrap();
+      }
+      models.into_iter().map(|m| m.unwrap()).collect::<Vec<_>>()
+    });
+    let expected = models
+      .into_iter()
+      .flatten()
+      .collect::<collections::BTreeMap<_, _>>()
+      .into_iter()
+      .map(|(k, v)| (k[..].into(), v[..].into()))
+      .collect::<Vec<map::OwnedElement>>();
+    assert_eq!(tree.range(..).unwrap(), expected);
+    // Latches are forgotten once released.
+    assert!(tree.latches.table.lock().unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_range_during_splits() {
+    // Scans over the whole tree while a writer keeps splitting and merging leaves must see every
+    // key which is never removed.
+    const KEYS: u32 = 1000;
+    let tree = open_tree();
+    for i in (0..KEYS).step_by(2) {
+      tree.insert(&key(i), &value(&key(i), 0)).unwrap();
+    }
+    let done = atomic::AtomicBool::new(false);
+    thread::scope(|s| {
+      let reader = s.spawn(|| {
+        let mut scans = 0;
+        while !done.load(atomic::Ordering::Relaxed) || scans < 10 {
+          let elements = tree.range(..).unwrap();
+          check_elements(&elements);
+          let stable = elements.iter().filter(|(k, _)| k.ends_with(b"0") || k.ends_with(b"2"));
+          let count = stable.count() as u32;
+          assert!(count >= KEYS / 5);
+          scans += 1;
+        }
+      });
+      let mut rng = StdRng::seed_from_u64(0);
+      for round in 1..3000 {
+        let i = rng.gen_range(0..KEYS);
+        if i % 10 == 0 || i % 10 == 2 {
+          continue;
+        }
+        if rng.gen_bool(0.5) {
+          tree.insert(&key(i), &value(&key(i), round)).unwrap();
+        } else {
+          tree.remove(&key(i)).unwrap();
+        }
+      }
+      done.store(true, atomic::Ordering::Relaxed);
+      reader.join().unwrap();
+    });
+  }
+}
@@ -0,0 +1,654 @@
+//! # Catalog
+//!
+//! The schema table is a B+ tree rooted at page `1`, which records the root page of every table and
+//! secondary index in the database, along with the information necessary to interpret them. Since
+//! extractors, filters and Prolly tree policies are code rather than data, the schema table only
+//! records their *identities*: names chosen by the application, which it maps back to the
+//! implementations when opening tables (see [`table::Table::new`] and [`table::Index::open`]).
+//!
+//! The schema table is modified through the same store as the trees it describes, so changes to the
+//! catalog are committed or discarded atomically along with the rest of the transaction.
+
+use super::bplus;
+use super::map;
+use super::paging;
+use super::prolly;
+use super::table;
+use super::vfs;
+use crate::encoding::prefix_varint;
+use std::fmt;
+use std::result;
+
+/// # Catalog errors
+///
+/// Errors that can occur when modifying the catalog. Apart from storage errors, this covers names
+/// which are already taken or do not exist.
+#[derive(Debug)]
+pub enum Error<E> {
+  /// The underlying store reported an error.
+  Storage(paging::Error<E>),
+  /// A table or index with the given name already exists.
+  AlreadyExists { name: Box<str> },
+  /// No table or index with the given name exists.
+  NotFound { name: Box<str> },
+}
+
+/// Conversion from storage errors, so that they can be propagated with `?`.
+impl<E> From<paging::Error<E>> for Error<E> {
+  fn from(error: paging::Error<E>) -> Self {
+    Error::Storage(error)
+  }
+}
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Error::Storage(error) => write!(f, "{error}"),
+      Error::AlreadyExists { name } => write!(f, "{name} already exists"),
+      Error::NotFound { name } => write!(f, "{name} does not exist"),
+    }
+  }
+}
+
+/// The result type of catalog operations in a store of type `S`.
+pub type Result<T, S> = result::Result<T, Error<<<S as paging::Store>::File as vfs::File>::Error>>;
+
+/// # Kind of tree
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TreeKind {
+  /// A [`bplus::BasicTree`].
+  BPlus,
+  /// A [`prolly::BasicTree`].
+  Prolly,
+}
+
+/// # Table schema
+///
+/// The catalog entry of a table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableSchema {
+  /// The name of the table.
+  pub name: Box<str>,
+  /// The kind of tree holding the primary index.
+  pub tree: TreeKind,
+  /// The root page ID of the primary index, or `None` for an empty Prolly tree.
+  pub root: Option<u64>,
+  /// The identity of the [`prolly::Policy`] (and aggregate, if any) of a Prolly tree. Trees built
+  /// with different policies are not compatible, so this should change whenever the policy does.
+  /// This is empty for B+ trees.
+  pub policy: Box<str>,
+}
+
+/// # Index definition schema
+///
+/// The identities of the parts of a [`table::Definition`], from which the application rebuilds the
+/// definition when opening the index.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DefinitionSchema {
+  /// The kind of the index.
+  pub kind: table::Kind,
+  /// The identity of the [`table::Extractor`] computing index keys.
+  pub extractor: Box<str>,
+  /// The identity of the [`table::Filter`] of a partial index.
+  pub filter: Option<Box<str>>,
+  /// The identity of the [`table::Extractor`] computing projections in a covering index.
+  pub projection: Option<Box<str>>,
+}
+
+/// # Index schema
+///
+/// The catalog entry of a secondary index.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexSchema {
+  /// The name of the indexed table.
+  pub table: Box<str>,
+  /// The name of the index, which is unique among the indices of the table.
+  pub name: Box<str>,
+  /// The root page ID of the B+ tree holding the index.
+  pub root: u64,
+  /// The definition of the index.
+  pub definition: DefinitionSchema,
+}
+
+/// # Catalog
+///
+/// Provides access to the schema table. The catalog only records where tables and indices are
+/// stored: trees are created by the caller and then registered, while dropping a table or index also
+/// deallocates its pages.
+///
+/// ## Implementation notes
+///
+/// The key of a table entry is the byte `0x00` followed by the name of the table. The value is the
+/// kind of tree as a single byte (`0` for B+ trees, `1` for Prolly trees), followed by the root page
+/// ID (or `0` for an empty Prolly tree) and the policy identity.
+///
+/// The key of an index entry is the byte `0x01`, followed by the name of the table and the name of
+/// the index, so that the indices of each table are adjacent. The value is the root page ID, the
+/// kind of index as a single byte (`0` for non-unique, `1` for unique and `2` for posting list
+/// indices), and the identities of the extractor, the filter and the projection.
+///
+/// Page IDs are encoded as prefix varints. Names and identities are stored as their length (encoded
+/// as a prefix varint) followed by their content, except the index name at the end of index keys,
+/// which is stored as is. Optional identities are stored with their length incremented by one, and
+/// a zero length indicates that they are absent.
+pub struct Catalog<Store: paging::Store> {
+  tree: bplus::BasicTree<Store>,
+}
+
+const TABLE_TAG: u8 = 0x00;
+const INDEX_TAG: u8 = 0x01;
+
+impl<Store: paging::Store> Catalog<Store> {
+  /// The page ID of the root of the schema table.
+  pub const ROOT: u64 = 1;
+
+  /// Opens the schema table, initialising it if the store is newly created.
+  pub fn open(store: &mut Store) -> paging::Result<Self, Store> {
+    if store.get(Self::ROOT)?.iter().all(|&byte| byte == 0) {
+      bplus::BasicTree::init(store, Self::ROOT)?;
+    }
+    Ok(Self { tree: bplus::BasicTree::open(Self::ROOT) })
+  }
+
+  /// Returns all tables, ordered by name.
+  pub fn tables(&self, store: &mut Store) -> paging::Result<Vec<TableSchema>, Store> {
+    let entries = self.scan(store, &[TABLE_TAG])?;
+    let tables = entries.iter().map(|(key, value)| decode_table(key, value));
+    tables.collect::<Option<_>>().ok_or(paging::Error::Corrupt { page_id: Self::ROOT })
+  }
+
+  /// Returns the table with the given name.
+  pub fn table(&self, store: &mut Store, name: &str) -> paging::Result<Option<TableSchema>, Store> {
+    use map::Map;
+    let key = table_key(name);
+    let Some(value) = self.tree.get(store, &key)? else { return Ok(None) };
+    let table = decode_table(&key, &value).ok_or(paging::Error::Corrupt { page_id: Self::ROOT })?;
+    Ok(Some(table))
+  }
+
+  /// Returns all indices of a table, ordered by name.
+  pub fn indices(&self, store: &mut Store, table: &str) -> paging::Result<Vec<IndexSchema>, Store> {
+    let entries = self.scan(store, &index_key(table, ""))?;
+    let indices = entries.iter().map(|(key, value)| decode_index(key, value));
+    indices.collect::<Option<_>>().ok_or(paging::Error::Corrupt { page_id: Self::ROOT })
+  }
+
+  /// Returns the index of a table with the given name.
+  pub fn index(
+    &self,
+    store: &mut Store,
+    table: &str,
+    name: &str,
+  ) -> paging::Result<Option<IndexSchema>, Store> {
+    use map::Map;
+    let key = index_key(table, name);
+    let Some(value) = self.tree.get(store, &key)? else { return Ok(None) };
+    let index = decode_index(&key, &value).ok_or(paging::Error::Corrupt { page_id: Self::ROOT })?;
+    Ok(Some(index))
+  }
+
+  /// Registers a table whose primary index has been created by the caller. Fails with
+  /// [`paging::Error::TooLarge`] if the name is too long to be a key of the schema table.
+  pub fn create_table(&mut self, store: &mut Store, schema: &TableSchema) -> Result<(), Store> {
+    use map::Map;
+    if self.table(store, &schema.name)?.is_some() {
+      return Err(Error::AlreadyExists { name: schema.name.clone() });
+    }
+    self.tree.insert(store, &table_key(&schema.name), &encode_table(schema))?;
+    Ok(())
+  }
+
+  /// Records the new root page ID of the primary index of a table, which changes whenever a Prolly
+  /// tree is modified.
+  pub fn set_root(
+    &mut self,
+    store: &mut Store,
+    name: &str,
+    root: Option<u64>,
+  ) -> Result<(), Store> {
+    use map::Map;
+    let mut schema = self.table(store, name)?.ok_or_else(|| not_found(name))?;
+    schema.root = root;
+    self.tree.insert(store, &table_key(name), &encode_table(&schema))?;
+    Ok(())
+  }
+
+  /// Renames a table, along with the entries of its indices. Fails with
+  /// [`paging::Error::TooLarge`] if the new name is too long to be a key of the schema table.
+  pub fn rename_table(
+    &mut self,
+    store: &mut Store,
+    name: &str,
+    new_name: &str,
+  ) -> Result<(), Store> {
+    use map::Map;
+    let mut schema = self.table(store, name)?.ok_or_else(|| not_found(name))?;
+    if self.table(store, new_name)?.is_some() {
+      return Err(Error::AlreadyExists { name: new_name.into() });
+    }
+    // Check all new keys before making any change.
+    let indices = self.indices(store, name)?;
+    paging::check_key(store, &table_key(new_name))?;
+    for index in &indices {
+      paging::check_key(store, &index_key(new_name, &index.name))?;
+    }
+    for index in indices {
+      self.tree.remove(store, &index_key(name, &index.name))?;
+      self.tree.insert(store, &index_key(new_name, &index.name), &encode_index(&index))?;
+    }
+    self.tree.remove(store, &table_key(name))?;
+    schema.name = new_name.into();
+    self.tree.insert(store, &table_key(new_name), &encode_table(&schema))?;
+    Ok(())
+  }
+
+  /// Removes a table and all its indices, deallocating their pages. Pages of previous versions of a
+  /// Prolly tree must have been released (see [`prolly::BasicTree::release`]).
+  pub fn drop_table(&mut self, store: &mut Store, name: &str) -> Result<(), Store> {
+    use map::Map;
+    let schema = self.table(store, name)?.ok_or_else(|| not_found(name))?;
+    for index in self.indices(store, name)? {
+      self.drop_index(store, name, &index.name)?;
+    }
+    match (schema.tree, schema.root) {
+      (TreeKind::BPlus, Some(root)) => bplus::BasicTree::<Store>::open(root).destroy(store)?,
+      (TreeKind::Prolly, Some(root)) => prolly::destroy(store, root)?,
+      (_, None) => {}
+    }
+    self.tree.remove(store, &table_key(name))?;
+    Ok(())
+  }
+
+  /// Registers a secondary index whose B+ tree has been created by the caller (e.g. through
+  /// [`table::Table::create_index`]). Fails with [`paging::Error::TooLarge`] if the names are too
+  /// long to be a key of the schema table.
+  pub fn create_index(&mut self, store: &mut Store, schema: &IndexSchema) -> Result<(), Store> {
+    use map::Map;
+    if self.table(store, &schema.table)?.is_none() {
+      return Err(not_found(&schema.table));
+    }
+    if self.index(store, &schema.table, &schema.name)?.is_some() {
+      return Err(Error::AlreadyExists { name: schema.name.clone() });
+    }
+    self.tree.insert(store, &index_key(&schema.table, &schema.name), &encode_index(schema))?;
+    Ok(())
+  }
+
+  /// Registers a secondary index whose B+ tree has been created by the caller, replacing the index
+  /// of the table with the same name, if any, and deallocating its pages. This swaps an index
+  /// rebuilt through [`table::Table::finish_index`] into the schema table.
+  ///
+  /// Fails with [`paging::Error::TooLarge`] if the names are too long to be a key of the schema
+  /// table.
+  pub fn replace_index(&mut self, store: &mut Store, schema: &IndexSchema) -> Result<(), Store> {
+    use map::Map;
+    if self.table(store, &schema.table)?.is_none() {
+      return Err(not_found(&schema.table));
+    }
+    let key = index_key(&schema.table, &schema.name);
+    paging::check_key(store, &key)?;
+    if let Some(old) = self.index(store, &schema.table, &schema.name)? {
+      if old.root != schema.root {
+        bplus::BasicTree::<Store>::open(old.root).destroy(store)?;
+      }
+    }
+    self.tree.insert(store, &key, &encode_index(schema))?;
+    Ok(())
+  }
+
+  /// Renames a secondary index of a table. Fails with [`paging::Error::TooLarge`] if the new name
+  /// is too long to be a key of the schema table.
+  pub fn rename_index(
+    &mut self,
+    store: &mut Store,
+    table: &str,
+    name: &str,
+    new_name: &str,
+  ) -> Result<(), Store> {
+    use map::Map;
+    let mut schema = self.index(store, table, name)?.ok_or_else(|| not_found(name))?;
+    if self.index(store, table, new_name)?.is_some() {
+      return Err(Error::AlreadyExists { name: new_name.into() });
+    }
+    paging::check_key(store, &index_key(table, new_name))?;
+    self.tree.remove(store, &index_key(table, name))?;
+    schema.name = new_name.into();
+    self.tree.insert(store, &index_key(table, new_name), &encode_index(&schema))?;
+    Ok(())
+  }
+
+  /// Removes a secondary index of a table, deallocating its pages.
+  pub fn drop_index(&mut self, store: &mut Store, table: &str, name: &str) -> Result<(), Store> {
+    use map::Map;
+    let schema = self.index(store, table, name)?.ok_or_else(|| not_found(name))?;
+    bplus::BasicTree::<Store>::open(schema.root).destroy(store)?;
+    self.tree.remove(store, &index_key(table, name))?;
+    Ok(())
+  }
+
+  /// Returns all entries of the schema table whose keys start with the given prefix.
+  fn scan(
+    &self,
+    store: &mut Store,
+    prefix: &[u8],
+  ) -> paging::Result<Vec<map::OwnedElement>, Store> {
+    use map::{Cursor, Map};
+    let mut cursor = self.tree.lower_bound(store, std::ops::Bound::Included(prefix))?;
+    let mut res = Vec::new();
+    while let Some((key, value)) = cursor.next(store)? {
+      if !key.starts_with(prefix) {
+        break;
+      }
+      res.push((key.into(), value.into()));
+    }
+    Ok(res)
+  }
+}
+
+/// Returns the error for a missing table or index.
+fn not_found<E>(name: &str) -> Error<E> {
+  Error::NotFound { name: name.into() }
+}
+
+/// Returns the key of a table entry.
+fn table_key(name: &str) -> Vec<u8> {
+  [&[TABLE_TAG], name.as_bytes()].concat()
+}
+
+/// Returns the key of an index entry.
+fn index_key(table: &str, name: &str) -> Vec<u8> {
+  let mut res = vec![INDEX_TAG];
+  encode_str(table, &mut res);
+  res.extend_from_slice(name.as_bytes());
+  res
+}
+
+/// Encodes the value of a table entry.
+fn encode_table(schema: &TableSchema) -> Vec<u8> {
+  let mut res = vec![match schema.tree {
+    TreeKind::BPlus => 0,
+    TreeKind::Prolly => 1,
+  }];
+  prefix_varint::encode(schema.root.unwrap_or(0), &mut res);
+  encode_str(&schema.policy, &mut res);
+  res
+}
+
+/// Decodes a table entry.
+fn decode_table(key: &[u8], value: &[u8]) -> Option<TableSchema> {
+  let (&TABLE_TAG, name) = key.split_first()? else { return None };
+  let mut value = value;
+  let tree = match paging::take_bytes(&mut value, 1)? {
+    [0] => TreeKind::BPlus,
+    [1] => TreeKind::Prolly,
+    _ => return None,
+  };
+  let root = Some(paging::take_varint(&mut value)?).filter(|&root| root != 0);
+  let policy = take_str(&mut value)?;
+  let name = str::from_utf8(name).ok()?.into();
+  value.is_empty().then_some(TableSchema { name, tree, root, policy })
+}
+
+/// Encodes the value of an index entry.
+fn encode_index(schema: &IndexSchema) -> Vec<u8> {
+  let DefinitionSchema { kind, extractor, filter, projection } = &schema.definition;
+  let mut res = Vec::new();
+  prefix_varint::encode(schema.root, &mut res);
+  res.push(match kind {
+    table::Kind::NonUnique => 0,
+    table::Kind::Unique => 1,
+    table::Kind::Postings => 2,
+  });
+  encode_str(extractor, &mut res);
+  for identity in [filter, projection] {
+    match identity {
+      Some(identity) => {
+        prefix_varint::encode(identity.len() as u64 + 1, &mut res);
+        res.extend_from_slice(identity.as_bytes());
+      }
+      None => prefix_varint::encode(0, &mut res),
+    }
+  }
+  res
+}
+
+/// Decodes an index entry.
+fn decode_index(key: &[u8], value: &[u8]) -> Option<IndexSchema> {
+  let (&INDEX_TAG, mut key) = key.split_first()? else { return None };
+  let table = take_str(&mut key)?;
+  let name = str::from_utf8(key).ok()?.into();
+  let mut value = value;
+  let root = paging::take_varint(&mut value)?;
+  let kind = match paging::take_bytes(&mut value, 1)? {
+    [0] => table::Kind::NonUnique,
+    [1] => table::Kind::Unique,
+    [2] => table::Kind::Postings,
+    _ => return None,
+  };
+  let extractor = take_str(&mut value)?;
+  let mut identities = [None, None];
+  for identity in &mut identities {
+    let len = paging::take_varint(&mut value)?;
+    if len > 0 {
+      let bytes = paging::take_bytes(&mut value, usize::try_from(len - 1).ok()?)?;
+      *identity = Some(str::from_utf8(bytes).ok()?.into());
+    }
+  }
+  let [filter, projection] = identities;
+  let definition = DefinitionSchema { kind, extractor, filter, projection };
+  value.is_empty().then_some(IndexSchema { table, name, root, definition })
+}
+
+/// Encodes a string as its length followed by its content.
+fn encode_str(s: &str, res: &mut Vec<u8>) {
+  prefix_varint::encode(s.len() as u64, res);
+  res.extend_from_slice(s.as_bytes());
+}
+
+/// Reads a string encoded by [`encode_str`] from the front of `data`, advancing it.
+fn take_str(data: &mut &[u8]) -> Option<Box<str>> {
+  let len = paging::take_varint(data)?;
+  let bytes = paging::take_bytes(data, usize::try_from(len).ok()?)?;
+  Some(str::from_utf8(bytes).ok()?.into())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::storage::testing::{open_store, TestPolicy, TestStore};
+  use crate::storage::vfs::FileSystem;
+  use map::Map;
+  use paging::Store;
+
+  fn bplus_table(store: &mut TestStore, name: &str) -> TableSchema {
+    let root = bplus::BasicTree::create(store).unwrap().root();
+    TableSchema { name: name.into(), tree: TreeKind::BPlus, root: Some(root), policy: "".into() }
+  }
+
+  fn index(store: &mut TestStore, table: &str, name: &str) -> IndexSchema {
+    let root = bplus::BasicTree::create(store).unwrap().root();
+    let definition = DefinitionSchema {
+      kind: table::Kind::Postings,
+      extractor: "first byte".into(),
+      filter: Some("non-empty".into()),
+      projection: None,
+    };
+    IndexSchema { table: table.into(), name: name.into(), root, definition }
+  }
+
+  #[test]
+  fn test_tables() {
+    let mut store = open_store();
+    let mut catalog = Catalog::open(&mut store).unwrap();
+    assert_eq!(catalog.tables(&mut store).unwrap(), []);
+    let users = bplus_table(&mut store, "users");
+    let events = TableSchema {
+      name: "events".into(),
+      tree: TreeKind::Prolly,
+      root: None,
+      policy: "test-policy-v1".into(),
+    };
+    catalog.create_table(&mut store, &users).unwrap();
+    catalog.create_table(&mut store, &events).unwrap();
+    let duplicate = catalog.create_table(&mut store, &users);
+    assert!(matches!(duplicate, Err(Error::AlreadyExists { .. })));
+    assert_eq!(catalog.tables(&mut store).unwrap(), [events.clone(), users.clone()]);
+
+    catalog.set_root(&mut store, "events", Some(42)).unwrap();
+    assert_eq!(catalog.table(&mut store, "events").unwrap().unwrap().root, Some(42));
+    catalog.rename_table(&mut store, "users", "accounts").unwrap();
+    let renamed = catalog.rename_table(&mut store, "events", "accounts");
+    assert!(matches!(renamed, Err(Error::AlreadyExists { .. })));
+    let missing = catalog.rename_table(&mut store, "users", "people");
+    assert!(matches!(missing, Err(Error::NotFound { .. })));
+    assert_eq!(catalog.table(&mut store, "users").unwrap(), None);
+    let accounts = catalog.table(&mut store, "accounts").unwrap().unwrap();
+    assert_eq!(accounts, TableSchema { name: "accounts".into(), ..users });
+  }
+
+  #[test]
+  fn test_indices() {
+    let mut store = open_store();
+    let mut catalog = Catalog::open(&mut store).unwrap();
+    let orphan = index(&mut store, "users", "by_email");
+    let missing = catalog.create_index(&mut store, &orphan);
+    assert!(matches!(missing, Err(Error::NotFound { .. })));
+    let users = bplus_table(&mut store, "users");
+    catalog.create_table(&mut store, &users).unwrap();
+    // Index names are unique per table, and tables whose names are prefixes of each other do not
+    // share indices.
+    let user = bplus_table(&mut store, "user");
+    catalog.create_table(&mut store, &user).unwrap();
+    let by_email = index(&mut store, "users", "by_email");
+    let by_name = index(&mut store, "users", "by_name");
+    let other = index(&mut store, "user", "by_email");
+    for index in [&by_name, &by_email, &other] {
+      catalog.create_index(&mut store, index).unwrap();
+    }
+    let duplicate = catalog.create_index(&mut store, &by_name);
+    assert!(matches!(duplicate, Err(Error::AlreadyExists { .. })));
+    assert_eq!(catalog.indices(&mut store, "users").unwrap(), [by_email.clone(), by_name.clone()]);
+    assert_eq!(catalog.indices(&mut store, "user").unwrap(), [other]);
+
+    catalog.rename_index(&mut store, "users", "by_name", "by_full_name").unwrap();
+    catalog.rename_table(&mut store, "users", "accounts").unwrap();
+    assert_eq!(catalog.indices(&mut store, "users").unwrap(), []);
+    let indices = catalog.indices(&mut store, "accounts").unwrap();
+    let names = indices.iter().map(|index| &index.name[..]).collect::<Vec<_>>();
+    assert_eq!(names, ["by_email", "by_full_name"]);
+    assert!(indices.iter().all(|index| &index.table[..] == "accounts"));
+    assert_eq!(indices[0].definition, by_email.definition);
+
+    catalog.drop_index(&mut store, "accounts", "by_email").unwrap();
+    let missing = catalog.drop_index(&mut store, "accounts", "by_email");
+    assert!(matches!(missing, Err(Error::NotFound { .. })));
+    assert_eq!(catalog.indices(&mut store, "accounts").unwrap().len(), 1);
+  }
+
+  #[test]
+  fn test_drop_table_frees_pages() {
+    let mut store = open_store();
+    let mut catalog = Catalog::open(&mut store).unwrap();
+    let page_count = store.page_count();
+    let users = bplus_table(&mut store, "users");
+    let mut tree = bplus::BasicTree::<TestStore>::open(users.root.unwrap());
+    for i in 0..200u32 {
+      tree.insert(&mut store, &i.to_be_bytes(), &[i as u8; 100]).unwrap();
+    }
+    catalog.create_table(&mut store, &users).unwrap();
+    let by_name = index(&mut store, "users", "by_name");
+    catalog.create_index(&mut store, &by_name).unwrap();
+    let mut tree = prolly::BasicTree::<TestStore, TestPolicy>::new(TestPolicy);
+    for i in 0..200u32 {
+      tree.insert(&mut store, &i.to_be_bytes(), &[i as u8; 100]).unwrap();
+    }
+    tree.release(&mut store).unwrap();
+    let events = TableSchema {
+      name: "events".into(),
+      tree: TreeKind::Prolly,
+      root: tree.root(),
+      policy: "test-policy-v1".into(),
+    };
+    catalog.create_table(&mut store, &events).unwrap();
+    let grown = store.page_count();
+
+    catalog.drop_table(&mut store, "users").unwrap();
+    catalog.drop_table(&mut store, "events").unwrap();
+    assert_eq!(catalog.tables(&mut store).unwrap(), []);
+    assert_eq!(catalog.indices(&mut store, "users").unwrap(), []);
+    // All pages are reused, since the schema table stays in its root page.
+    for _ in page_count..grown {
+      store.allocate().unwrap();
+    }
+    assert_eq!(store.page_count(), grown);
+  }
+
+  #[test]
+  fn test_replace_index() {
+    let mut store = open_store();
+    let mut catalog = Catalog::open(&mut store).unwrap();
+    let orphan = index(&mut store, "users", "by_name");
+    let missing = catalog.replace_index(&mut store, &orphan);
+    assert!(matches!(missing, Err(Error::NotFound { .. })));
+    let users = bplus_table(&mut store, "users");
+    catalog.create_table(&mut store, &users).unwrap();
+    catalog.replace_index(&mut store, &orphan).unwrap();
+    assert_eq!(catalog.index(&mut store, "users", "by_name").unwrap().as_ref(), Some(&orphan));
+    // Replacing an index with itself keeps its pages.
+    catalog.replace_index(&mut store, &orphan).unwrap();
+    let page_count = store.page_count();
+    let rebuilt = index(&mut store, "users", "by_name");
+    catalog.replace_index(&mut store, &rebuilt).unwrap();
+    assert_eq!(catalog.indices(&mut store, "users").unwrap(), [rebuilt]);
+    // The pages of the old index are reused.
+    assert_eq!(store.allocate().unwrap(), orphan.root);
+    assert_eq!(store.page_count(), page_count + 1);
+  }
+
+  #[test]
+  fn test_long_names() {
+    let mut store = open_store();
+    let mut catalog = Catalog::open(&mut store).unwrap();
+    let long = "x".repeat(100);
+    let too_large = |res| matches!(res, Err(Error::Storage(paging::Error::TooLarge { .. })));
+    let table = bplus_table(&mut store, &long);
+    assert!(too_large(catalog.create_table(&mut store, &table)));
+    let users = bplus_table(&mut store, "users");
+    catalog.create_table(&mut store, &users).unwrap();
+    let by_name = index(&mut store, "users", "by_name");
+    catalog.create_index(&mut store, &by_name).unwrap();
+    let long_index = index(&mut store, "users", &long);
+    assert!(too_large(catalog.create_index(&mut store, &long_index)));
+    assert!(too_large(catalog.rename_index(&mut store, "users", "by_name", &long)));
+    // Renaming a table fails without changes if the new keys of its indices are too long.
+    assert!(too_large(catalog.rename_table(&mut store, "users", &long[..60])));
+    assert_eq!(catalog.tables(&mut store).unwrap(), [users]);
+    assert_eq!(catalog.indices(&mut store, "users").unwrap(), [by_name]);
+  }
+
+  #[test]
+  fn test_reopen() {
+    let mut fs = vfs::MemoryFileSystem::default();
+    let mut store = paging::BasicStore::open(fs.open("db").unwrap(), 512).unwrap();
+    let mut catalog = Catalog::open(&mut store).unwrap();
+    let users = bplus_table(&mut store, "users");
+    let by_name = index(&mut store, "users", "by_name");
+    catalog.create_table(&mut store, &users).unwrap();
+    catalog.create_index(&mut store, &by_name).unwrap();
+    store.flush().unwrap();
+
+    let mut store = paging::BasicStore::open(fs.open("db").unwrap(), 512).unwrap();
+    let catalog = Catalog::open(&mut store).unwrap();
+    assert_eq!(catalog.tables(&mut store).unwrap(), [users]);
+    assert_eq!(catalog.indices(&mut store, "users").unwrap(), [by_name]);
+  }
+
+  #[test]
+  fn test_corrupt_entry() {
+    let mut store = open_store();
+    let mut catalog = Catalog::open(&mut store).unwrap();
+    catalog.tree.insert(&mut store, &table_key("users"), &[7]).unwrap();
+    let corrupt = catalog.tables(&mut store);
+    assert!(matches!(corrupt, Err(paging::Error::Corrupt { page_id: 1 })));
+  }
+}
@@ -0,0 +1,1044 @@
+//! # Databases and transactions
+//!
+//! A database consists of a main database file and a WAL file, as described in the file format.
+//! All access goes through transactions, which present the pages of the database as a
+//! [`paging::Store`], so that trees and tables can be used inside them as usual. Write transactions
+//! keep modified pages in memory, and append them to the WAL when committed.
+
+use super::catalog;
+use super::paging;
+use super::vfs;
+use crate::encoding::crc64;
+use std::collections;
+use std::result;
+use std::sync;
+
+const FRAME_HEADER_SIZE: usize = 32;
+
+/// The result type of operations on a [`Database`] with files of type `F`.
+pub type Result<T, F> = result::Result<T, paging::Error<<F as vfs::File>::Error>>;
+
+/// # Database
+///
+/// A handle to a database, from which any number of read transactions and at most one write
+/// transaction at a time can be started. Write transactions started through the same handle wait
+/// for each other, and the WAL file is locked (see [`vfs::File::try_lock`]) while a write
+/// transaction is active, so that other handles, possibly in other processes, cannot start one.
+///
+/// Each read transaction sees the database as of the last transaction committed before it started,
+/// even while later transactions are committed. Committed transactions stay in the WAL until
+/// [`Database::checkpoint`] is called, which only copies them into the main database file as far as
+/// no active read transaction could observe the change.
+///
+/// ## Implementation notes
+///
+/// The WAL consists of full page frames, as described in the file format. When the database is
+/// opened, the committed frames are read, and the committed frames of each page are kept in an
+/// in-memory index.
+///
+/// Each read transaction pins a *mark*, which is the number of committed frames when it started,
+/// and reads the latest frame of each page before its mark, or the page in the main database file
+/// if there is no such frame. A checkpoint only copies the latest frame of each page before the
+/// oldest mark of any active read transaction: pages changed by such frames are read from the WAL
+/// by all active read transactions, so overwriting them in the main database file goes unnoticed.
+/// The WAL is only truncated once all committed frames have been copied and there are no active
+/// read transactions.
+///
+/// Checkpoints also lock the WAL file, as well as the main database file, which each handle locks
+/// in shared mode while it has active read transactions. Other handles may commit transactions at
+/// any time this handle does not hold the lock on the WAL, and truncate the WAL at any time this
+/// handle does not hold either lock, so the WAL is checked for such changes whenever a transaction
+/// is started, and before a checkpoint. New frames are added to the index, and if the WAL was
+/// truncated (its size decreased, or its first frame has a different salt), the state is read
+/// again from the main database file and the WAL.
+///
+/// Marks are only known within a handle, so a checkpoint does nothing while another handle has
+/// active read transactions. Read transactions of the handle running the checkpoint give up their
+/// shared lock for its duration, which is safe since no other handle can take the exclusive lock
+/// while this one holds the lock on the WAL.
+pub struct Database<File: vfs::File> {
+  page_size: usize,
+  shared: sync::Mutex<Shared<File>>,
+  writer: sync::Mutex<()>,
+}
+
+/// The state of a [`Database`] which is shared by all transactions.
+struct Shared<File: vfs::File> {
+  page_size: usize,
+  file: File,
+  wal: File,
+  /// The salt of the frames in the WAL.
+  salt: u64,
+  /// The number of frames in the WAL up to the last commit frame.
+  frames: u64,
+  /// The committed frames of each page in the WAL, in increasing order.
+  index: collections::HashMap<u64, Vec<u64>>,
+  /// The number of frames which have been copied into the main database file.
+  backfilled: u64,
+  /// The number of active read transactions with each mark.
+  readers: collections::BTreeMap<u64, usize>,
+  /// The page count after the last committed transaction.
+  page_count: u64,
+  /// The first free page ID after the last committed transaction.
+  freelist: u64,
+}
+
+impl<File: vfs::File> Database<File> {
+  /// Opens the database contained in `file` and `wal`, recovering all transactions committed to
+  /// the WAL. If the main database file is empty, a new database with the given page size is
+  /// initialised, containing an empty schema table; otherwise the page size recorded in the file is
+  /// used.
+  ///
+  /// # Panics
+  ///
+  /// Panics if `page_size` is not a power of two between `512` and `65536`.
+  pub fn open(file: File, wal: File, page_size: usize) -> Result<Self, File> {
+    assert!(page_size.is_power_of_two() && (512..=65536).contains(&page_size));
+    let mut file = file;
+    let page_size = if file.size()? == 0 {
+      file.write(0, &paging::encode_header(page_size, 0))?;
+      file.sync()?;
+      page_size
+    } else {
+      let mut header = [0; 24];
+      file.read(0, &mut header)?;
+      paging::decode_header(&header).ok_or(paging::Error::Corrupt { page_id: 0 })?.0
+    };
+    let mut shared = Shared {
+      page_size,
+      file,
+      wal,
+      salt: 0,
+      frames: 0,
+      index: collections::HashMap::new(),
+      backfilled: 0,
+      readers: collections::BTreeMap::new(),
+      page_count: 0,
+      freelist: 0,
+    };
+    shared.refresh_shared()?;
+    let mut page = vec![0; page_size];
+    shared.read(catalog::Catalog::<WriteTransaction<File>>::ROOT, u64::MAX, &mut page)?;
+    let res = Self { page_size, shared: sync::Mutex::new(shared), writer: sync::Mutex::new(()) };
+    if page.iter().all(|&byte| byte == 0) {
+      let mut transaction = res.begin_write()?;
+      catalog::Catalog::open(&mut transaction)?;
+      transaction.commit()?;
+    }
+    Ok(res)
+  }
+
+  /// Returns the page size of the database.
+  pub fn page_size(&self) -> usize {
+    self.page_size
+  }
+
+  /// Starts a read transaction, which sees all transactions committed so far. Fails with
+  /// [`paging::Error::Busy`] while a checkpoint of another handle is active.
+  pub fn begin_read(&self) -> Result<ReadTransaction<'_, File>, File> {
+    let mut shared = self.shared.lock().unwrap();
+    // The main database file stays locked while this handle has active read transactions.
+    let locked = !shared.readers.is_empty();
+    if !locked && !shared.file.try_lock_shared()? {
+      return Err(paging::Error::Busy);
+    }
+    if let Err(err) = shared.refresh() {
+      if !locked {
+        // An error only means that the lock was already lost.
+        let _ = shared.file.unlock();
+      }
+      return Err(err);
+    }
+    let mark = shared.frames;
+    *shared.readers.entry(mark).or_default() += 1;
+    let page_count = shared.page_count;
+    Ok(ReadTransaction { db: self, mark, page_count, pages: collections::HashMap::new() })
+  }
+
+  /// Starts a write transaction, waiting for any other write transaction started through this
+  /// handle to finish. Fails with [`paging::Error::Busy`] without waiting if another handle has an
+  /// active write transaction or checkpoint.
+  pub fn begin_write(&self) -> Result<WriteTransaction<'_, File>, File> {
+    let writer = self.writer.lock().unwrap();
+    let mut shared = self.shared.lock().unwrap();
+    // Waiting for the lock here would also block read transactions of this handle.
+    if !shared.wal.try_lock()? {
+      return Err(paging::Error::Busy);
+    }
+    if let Err(err) = shared.refresh() {
+      // An error only means that the lock was already lost.
+      let _ = shared.wal.unlock();
+      return Err(err);
+    }
+    Ok(WriteTransaction {
+      db: self,
+      _writer: writer,
+      page_count: shared.page_count,
+      freelist: shared.freelist,
+      pages: collections::HashMap::new(),
+      dirty: collections::BTreeSet::new(),
+      savepoints: Vec::new(),
+    })
+  }
+
+  /// Writes committed pages from the WAL into the main database file and synchronises it, stopping
+  /// at the mark of the oldest active read transaction. If all committed pages have been written and
+  /// there are no active read transactions, the WAL is truncated. Returns whether this happened.
+  ///
+  /// Does nothing and returns `false` while a write transaction or another checkpoint of any handle
+  /// is active, or while another handle has an active read transaction.
+  pub fn checkpoint(&self) -> Result<bool, File> {
+    let Ok(_writer) = self.writer.try_lock() else { return Ok(false) };
+    let mut shared = self.shared.lock().unwrap();
+    if !shared.wal.try_lock()? {
+      return Ok(false);
+    }
+    // Read transactions of this handle are held back by their marks instead.
+    let readers = !shared.readers.is_empty();
+    if readers {
+      let _ = shared.file.unlock();
+    }
+    let mut res = match shared.file.try_lock() {
+      Ok(true) => {
+        let res = shared.checkpoint();
+        let _ = shared.file.unlock();
+        res
+      }
+      Ok(false) => Ok(false),
+      Err(err) => Err(err.into()),
+    };
+    if readers {
+      // Only checkpoints lock the main database file exclusively, which requires the lock on the
+      // WAL.
+      res = match shared.file.try_lock_shared() {
+        Ok(true) => res,
+        Ok(false) => Err(paging::Error::Busy),
+        Err(err) => Err(err.into()),
+      };
+    }
+    // An error only means that the lock was already lost.
+    let _ = shared.wal.unlock();
+    res
+  }
+}
+
+impl<File: vfs::File> Shared<File> {
+  /// Returns the size of a frame in the WAL.
+  fn frame_size(&self) -> u64 {
+    (FRAME_HEADER_SIZE + self.page_size) as u64
+  }
+
+  /// Reads transactions committed through other handles since the WAL was last read, locking the
+  /// main database file in shared mode so that no checkpoint truncates the WAL in the meantime.
+  fn refresh_shared(&mut self) -> Result<(), File> {
+    if !self.file.try_lock_shared()? {
+      return Err(paging::Error::Busy);
+    }
+    let res = self.refresh();
+    // An error only means that the lock was already lost.
+    let _ = self.file.unlock();
+    res
+  }
+
+  /// Reads transactions committed through other handles since the WAL was last read. The lock on
+  /// the WAL, or a lock on the main database file, must be held.
+  fn refresh(&mut self) -> Result<(), File> {
+    let size = self.wal.size()?;
+    let truncated = if self.frames == 0 || size < self.frames * self.frame_size() {
+      true
+    } else {
+      let mut salt = [0; 8];
+      self.wal.read(0, &mut salt)?;
+      u64::from_le_bytes(salt) != self.salt
+    };
+    if truncated {
+      // Other handles cannot truncate the WAL while this one has active read transactions, whose
+      // frames would be lost.
+      if self.frames > 0 && !self.readers.is_empty() {
+        return Err(paging::Error::Corrupt { page_id: 0 });
+      }
+      // Transactions may also have been checkpointed into the main database file.
+      let size = self.file.size()?;
+      let mut header = [0; 24];
+      self.file.read(0, &mut header)?;
+      let (page_size, freelist) =
+        paging::decode_header(&header).ok_or(paging::Error::Corrupt { page_id: 0 })?;
+      if page_size != self.page_size || size % page_size as u64 != 0 {
+        return Err(paging::Error::Corrupt { page_id: 0 });
+      }
+      // Page `1` is reserved for the schema table even before it is written.
+      self.page_count = (size / page_size as u64).max(2);
+      self.freelist = freelist;
+      self.index.clear();
+      self.frames = 0;
+      self.backfilled = 0;
+    }
+    self.recover(size)
+  }
+
+  /// Reads the committed frames in the WAL following the known ones into the index.
+  fn recover(&mut self, size: u64) -> Result<(), File> {
+    let mut frame = vec![0; FRAME_HEADER_SIZE + self.page_size];
+    let mut pending = Vec::new();
+    let mut n = self.frames;
+    while (n + 1) * self.frame_size() <= size {
+      self.wal.read(n * self.frame_size(), &mut frame)?;
+      let Some((salt, page_id, commit)) = decode_frame(&frame) else { break };
+      if n == 0 {
+        self.salt = salt;
+      } else if salt != self.salt {
+        break;
+      }
+      pending.push((page_id, n));
+      n += 1;
+      if commit != 0 {
+        let page = &frame[FRAME_HEADER_SIZE..];
+        let (_, freelist) =
+          paging::decode_header(page).ok_or(paging::Error::Corrupt { page_id: 0 })?;
+        for (page_id, frame) in pending.drain(..) {
+          self.index.entry(page_id).or_default().push(frame);
+        }
+        self.frames = n;
+        self.page_count = commit;
+        self.freelist = freelist;
+      }
+    }
+    Ok(())
+  }
+
+  /// Returns the latest frame of a page before the given mark.
+  fn frame(&self, page_id: u64, mark: u64) -> Option<u64> {
+    let frames = self.index.get(&page_id)?;
+    frames[..frames.partition_point(|&frame| frame < mark)].last().copied()
+  }
+
+  /// Reads the latest version of a page committed before the given mark.
+  fn read(&mut self, page_id: u64, mark: u64, buf: &mut [u8]) -> Result<(), File> {
+    if let Some(frame) = self.frame(page_id, mark) {
+      self.wal.read(frame * self.frame_size() + FRAME_HEADER_SIZE as u64, buf)?;
+      return Ok(());
+    }
+    let offset = page_id * self.page_size as u64;
+    if offset < self.file.size()? {
+      self.file.read(offset, buf)?;
+    } else {
+      buf.fill(0);
+    }
+    Ok(())
+  }
+
+  /// Appends a transaction to the WAL and synchronises it, then makes it visible.
+  fn append<'a>(
+    &mut self,
+    pages: impl IntoIterator<Item = (u64, &'a [u8])>,
+    page_count: u64,
+    freelist: u64,
+  ) -> Result<(), File> {
+    let salt = if self.frames == 0 { rand::random() } else { self.salt };
+    let header = paging::encode_header(self.page_size, freelist);
+    let mut page_ids = Vec::new();
+    let mut data = Vec::new();
+    for (page_id, page) in pages {
+      page_ids.push(page_id);
+      data.extend_from_slice(&encode_frame(salt, page_id, 0, page));
+    }
+    page_ids.push(0);
+    data.extend_from_slice(&encode_frame(salt, 0, page_count, &header));
+    self.wal.write(self.frames * self.frame_size(), &data)?;
+    self.wal.sync()?;
+    for (i, page_id) in page_ids.into_iter().enumerate() {
+      self.index.entry(page_id).or_default().push(self.frames + i as u64);
+    }
+    self.salt = salt;
+    self.frames += data.len() as u64 / self.frame_size();
+    self.page_count = page_count;
+    self.freelist = freelist;
+    Ok(())
+  }
+
+  /// Writes committed pages before the oldest mark into the main database file, then truncates
+  /// the WAL if possible. See [`Database::checkpoint`].
+  fn checkpoint(&mut self) -> Result<bool, File> {
+    self.refresh()?;
+    let limit = self.readers.keys().next().copied().unwrap_or(self.frames);
+    if limit > self.backfilled {
+      self.backfill(limit)?;
+    }
+    if self.backfilled < self.frames || !self.readers.is_empty() {
+      return Ok(false);
+    }
+    self.file.truncate(self.page_count * self.page_size as u64)?;
+    self.file.sync()?;
+    self.wal.truncate(0)?;
+    self.wal.sync()?;
+    self.index.clear();
+    self.frames = 0;
+    self.backfilled = 0;
+    Ok(true)
+  }
+
+  /// Writes committed pages before the given mark into the main database file.
+  fn backfill(&mut self, limit: u64) -> Result<(), File> {
+    let mut page = vec![0; self.page_size];
+    let mut frames = Vec::new();
+    for &page_id in self.index.keys() {
+      // Frames before `backfilled` have been copied by a previous checkpoint.
+      match self.frame(page_id, limit) {
+        Some(frame) if frame >= self.backfilled => frames.push((page_id, frame)),
+        _ => {}
+      }
+    }
+    for (page_id, frame) in frames {
+      self.wal.read(frame * self.frame_size() + FRAME_HEADER_SIZE as u64, &mut page)?;
+      self.file.write(page_id * self.page_size as u64, &page)?;
+    }
+    self.file.sync()?;
+    self.backfilled = limit;
+    Ok(())
+  }
+}
+
+/// Encodes a WAL frame.
+fn encode_frame(salt: u64, page_id: u64, commit: u64, page: &[u8]) -> Vec<u8> {
+  let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + page.len());
+  frame.extend_from_slice(&salt.to_le_bytes());
+  frame.extend_from_slice(&page_id.to_le_bytes());
+  frame.extend_from_slice(&commit.to_le_bytes());
+  let checksum = crc64::update(crc64::checksum(&frame), page);
+  frame.extend_from_slice(&checksum.to_le_bytes());
+  frame.extend_from_slice(page);
+  frame
+}
+
+/// Decodes the header of a WAL frame, returning its salt, page ID and commit field, or `None` if
+/// the checksum does not match.
+fn decode_frame(frame: &[u8]) -> Option<(u64, u64, u64)> {
+  let field = |i: usize| u64::from_le_bytes(frame[i * 8..i * 8 + 8].try_into().unwrap());
+  let checksum = crc64::update(crc64::checksum(&frame[..24]), &frame[FRAME_HEADER_SIZE..]);
+  let (salt, page_id, commit) = (field(0), field(1), field(2));
+  // Only the header page may mark a commit.
+  let valid = checksum == field(3) && (commit == 0 || page_id == 0);
+  valid.then_some((salt, page_id, commit))
+}
+
+/// # Read transaction
+///
+/// Reads pages of a [`Database`] through [`paging::Store`], as of the last transaction committed
+/// before it started. Pages are cached for the duration of the transaction.
+///
+/// The modifying methods of [`paging::Store`] fail with [`paging::Error::ReadOnly`].
+pub struct ReadTransaction<'a, File: vfs::File> {
+  db: &'a Database<File>,
+  mark: u64,
+  page_count: u64,
+  pages: collections::HashMap<u64, Box<[u8]>>,
+}
+
+/// Unpins the mark of the transaction, and unlocks the main database file if no other read
+/// transaction of the handle is active.
+impl<File: vfs::File> Drop for ReadTransaction<'_, File> {
+  fn drop(&mut self) {
+    let mut shared = self.db.shared.lock().unwrap();
+    let collections::btree_map::Entry::Occupied(mut entry) = shared.readers.entry(self.mark) else {
+      unreachable!()
+    };
+    *entry.get_mut() -= 1;
+    if *entry.get() == 0 {
+      entry.remove();
+    }
+    if shared.readers.is_empty() {
+      // An error only means that the lock was already lost.
+      let _ = shared.file.unlock();
+    }
+  }
+}
+
+impl<File: vfs::File> paging::Store for ReadTransaction<'_, File> {
+  type File = File;
+
+  fn page_size(&self) -> usize {
+    self.db.page_size
+  }
+
+  fn get(&mut self, page_id: u64) -> paging::Result<&[u8], Self> {
+    if page_id == 0 || page_id >= self.page_count {
+      return Err(paging::Error::Corrupt { page_id });
+    }
+    if !self.pages.contains_key(&page_id) {
+      let mut page = vec![0; self.db.page_size].into_boxed_slice();
+      self.db.shared.lock().unwrap().read(page_id, self.mark, &mut page)?;
+      self.pages.insert(page_id, page);
+    }
+    Ok(&self.pages[&page_id])
+  }
+
+  fn write(&mut self, _page_id: u64, _data: &[u8]) -> paging::Result<(), Self> {
+    Err(paging::Error::ReadOnly)
+  }
+
+  fn allocate(&mut self) -> paging::Result<u64, Self> {
+    Err(paging::Error::ReadOnly)
+  }
+
+  fn deallocate(&mut self, _page_id: u64) -> paging::Result<(), Self> {
+    Err(paging::Error::ReadOnly)
+  }
+}
+
+/// # Write transaction
+///
+/// Reads and modifies pages of a [`Database`] through [`paging::Store`]. Modified pages and the
+/// allocator state are kept in memory until [`WriteTransaction::commit`] is called, and are
+/// discarded by [`WriteTransaction::rollback`] or when the transaction is dropped.
+///
+/// Like in SQLite, named savepoints can be nested within the transaction:
+///
+/// - [`WriteTransaction::savepoint`] starts a new savepoint. Names need not be unique; the most
+///   recent savepoint with a given name is the one referred to.
+/// - [`WriteTransaction::rollback_to`] discards all changes made since the savepoint started,
+///   including those of savepoints nested within it, which are removed. The savepoint itself
+///   remains, so that it can be rolled back to again.
+/// - [`WriteTransaction::release`] removes the savepoint and all savepoints nested within it. Their
+///   changes become part of the enclosing savepoint, or of the transaction.
+/// - [`WriteTransaction::commit`] releases all savepoints.
+///
+/// ## Implementation notes
+///
+/// Each savepoint records the allocator state when it started, and the state of each page before
+/// its first modification while the savepoint is the innermost one. Rolling back to a savepoint
+/// restores these states, starting from the innermost savepoint.
+pub struct WriteTransaction<'a, File: vfs::File> {
+  db: &'a Database<File>,
+  _writer: sync::MutexGuard<'a, ()>,
+  page_count: u64,
+  freelist: u64,
+  pages: collections::HashMap<u64, Box<[u8]>>,
+  dirty: collections::BTreeSet<u64>,
+  savepoints: Vec<Savepoint>,
+}
+
+/// The state of a write transaction when a savepoint started.
+struct Savepoint {
+  name: Box<str>,
+  page_count: u64,
+  freelist: u64,
+  pages: collections::HashMap<u64, PageState>,
+}
+
+/// The cached content of a page, if any, and whether it was dirty.
+type PageState = (Option<Box<[u8]>>, bool);
+
+impl<File: vfs::File> WriteTransaction<'_, File> {
+  /// Appends all modified pages to the WAL and synchronises it. The changes become visible to
+  /// transactions started afterwards.
+  pub fn commit(self) -> Result<(), File> {
+    let mut shared = self.db.shared.lock().unwrap();
+    let unchanged = (self.page_count, self.freelist) == (shared.page_count, shared.freelist);
+    if self.dirty.is_empty() && unchanged {
+      return Ok(());
+    }
+    let pages = self.dirty.iter().map(|page_id| (*page_id, &self.pages[page_id][..]));
+    shared.append(pages, self.page_count, self.freelist)
+  }
+
+  /// Discards all changes.
+  pub fn rollback(self) {}
+
+  /// Starts a new savepoint with the given name.
+  pub fn savepoint(&mut self, name: &str) {
+    self.savepoints.push(Savepoint {
+      name: name.into(),
+      page_count: self.page_count,
+      freelist: self.freelist,
+      pages: collections::HashMap::new(),
+    });
+  }
+
+  /// Removes the most recent savepoint with the given name and all savepoints started after it,
+  /// keeping their changes. Returns `false` if there is no such savepoint.
+  pub fn release(&mut self, name: &str) -> bool {
+    let Some(index) = self.find_savepoint(name) else { return false };
+    let released = self.savepoints.split_off(index);
+    if let Some(parent) = self.savepoints.last_mut() {
+      // Earlier states take precedence.
+      for (page_id, state) in released.into_iter().flat_map(|savepoint| savepoint.pages) {
+        parent.pages.entry(page_id).or_insert(state);
+      }
+    }
+    true
+  }
+
+  /// Discards all changes made since the most recent savepoint with the given name started, and
+  /// removes all savepoints started after it. Returns `false` if there is no such savepoint.
+  pub fn rollback_to(&mut self, name: &str) -> bool {
+    let Some(index) = self.find_savepoint(name) else { return false };
+    let mut savepoints = self.savepoints.split_off(index);
+    for savepoint in savepoints.iter_mut().rev() {
+      for (page_id, (page, dirty)) in savepoint.pages.drain() {
+        match page {
+          Some(page) => self.pages.insert(page_id, page),
+          None => self.pages.remove(&page_id),
+        };
+        if dirty {
+          self.dirty.insert(page_id);
+        } else {
+          self.dirty.remove(&page_id);
+        }
+      }
+    }
+    savepoints.truncate(1);
+    self.page_count = savepoints[0].page_count;
+    self.freelist = savepoints[0].freelist;
+    self.savepoints.append(&mut savepoints);
+    true
+  }
+
+  /// Returns the index of the most recent savepoint with the given name.
+  fn find_savepoint(&self, name: &str) -> Option<usize> {
+    self.savepoints.iter().rposition(|savepoint| *savepoint.name == *name)
+  }
+}
+
+/// Releases the lock on the WAL file.
+impl<File: vfs::File> Drop for WriteTransaction<'_, File> {
+  fn drop(&mut self) {
+    // An error only means that the lock was already lost.
+    let _ = self.db.shared.lock().unwrap().wal.unlock();
+  }
+}
+
+impl<File: vfs::File> paging::Store for WriteTransaction<'_, File> {
+  type File = File;
+
+  fn page_size(&self) -> usize {
+    self.db.page_size
+  }
+
+  fn get(&mut self, page_id: u64) -> paging::Result<&[u8], Self> {
+    if page_id == 0 || page_id >= self.page_count {
+      return Err(paging::Error::Corrupt { page_id });
+    }
+    if !self.pages.contains_key(&page_id) {
+      let mut page = vec![0; self.db.page_size].into_boxed_slice();
+      self.db.shared.lock().unwrap().read(page_id, u64::MAX, &mut page)?;
+      self.pages.insert(page_id, page);
+    }
+    Ok(&self.pages[&page_id])
+  }
+
+  fn write(&mut self, page_id: u64, data: &[u8]) -> paging::Result<(), Self> {
+    assert_eq!(data.len(), self.db.page_size);
+    if page_id == 0 || page_id >= self.page_count {
+      return Err(paging::Error::Corrupt { page_id });
+    }
+    if let Some(savepoint) = self.savepoints.last_mut() {
+      let state = || (self.pages.get(&page_id).cloned(), self.dirty.contains(&page_id));
+      savepoint.pages.entry(page_id).or_insert_with(state);
+    }
+    self.pages.insert(page_id, data.into());
+    self.dirty.insert(page_id);
+    Ok(())
+  }
+
+  fn allocate(&mut self) -> paging::Result<u64, Self> {
+    if self.freelist == 0 {
+      self.page_count += 1;
+      return Ok(self.page_count - 1);
+    }
+    let page_id = self.freelist;
+    let page = self.get(page_id)?;
+    self.freelist = u64::from_le_bytes(page[0..8].try_into().unwrap());
+    Ok(page_id)
+  }
+
+  fn deallocate(&mut self, page_id: u64) -> paging::Result<(), Self> {
+    if page_id < 2 || page_id >= self.page_count {
+      return Err(paging::Error::Corrupt { page_id });
+    }
+    let mut page = vec![0; self.db.page_size];
+    page[0..8].copy_from_slice(&self.freelist.to_le_bytes());
+    self.write(page_id, &page)?;
+    self.freelist = page_id;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::storage::{bplus, map};
+  use map::Map;
+  use paging::Store;
+  use vfs::{File, FileSystem};
+
+  type TestDatabase = Database<vfs::MemoryFile>;
+
+  fn open(fs: &mut vfs::MemoryFileSystem) -> TestDatabase {
+    Database::open(fs.open("db").unwrap(), fs.open("db-wal").unwrap(), 512).unwrap()
+  }
+
+  /// Creates a table in a new transaction, returning its root page ID.
+  fn create(db: &TestDatabase, name: &str) -> u64 {
+    let mut transaction = db.begin_write().unwrap();
+    let root = bplus::BasicTree::create(&mut transaction).unwrap().root();
+    let schema = catalog::TableSchema {
+      name: name.into(),
+      tree: catalog::TreeKind::BPlus,
+      root: Some(root),
+      policy: "".into(),
+    };
+    let mut catalog = catalog::Catalog::open(&mut transaction).unwrap();
+    catalog.create_table(&mut transaction, &schema).unwrap();
+    transaction.commit().unwrap();
+    root
+  }
+
+  /// Returns the value of a key in a table.
+  fn get(db: &TestDatabase, name: &str, key: &[u8]) -> Option<Box<[u8]>> {
+    let mut transaction = db.begin_read().unwrap();
+    let catalog = catalog::Catalog::open(&mut transaction).unwrap();
+    let schema = catalog.table(&mut transaction, name).unwrap().unwrap();
+    let tree = bplus::BasicTree::open(schema.root.unwrap());
+    tree.get(&mut transaction, key).unwrap()
+  }
+
+  /// Sets a key in a table in a new transaction.
+  fn set(db: &TestDatabase, root: u64, key: &[u8], value: &[u8]) {
+    let mut transaction = db.begin_write().unwrap();
+    bplus::BasicTree::open(root).insert(&mut transaction, key, value).unwrap();
+    transaction.commit().unwrap();
+  }
+
+  #[test]
+  fn test_commit_and_reopen() {
+    let mut fs = vfs::MemoryFileSystem::default();
+    let db = open(&mut fs);
+    let root = create(&db, "users");
+    let mut transaction = db.begin_write().unwrap();
+    let mut tree = bplus::BasicTree::open(root);
+    for i in 0..200u32 {
+      tree.insert(&mut transaction, &i.to_be_bytes(), &[i as u8; 50]).unwrap();
+    }
+    transaction.commit().unwrap();
+    assert_eq!(get(&db, "users", &7u32.to_be_bytes()).as_deref(), Some(&[7; 50][..]));
+
+    // Committed transactions are recovered from the WAL.
+    drop(db);
+    let db = open(&mut fs);
+    assert_eq!(get(&db, "users", &7u32.to_be_bytes()).as_deref(), Some(&[7; 50][..]));
+    assert!(db.checkpoint().unwrap());
+    assert_eq!(fs.open("db-wal").unwrap().size().unwrap(), 0);
+    assert_eq!(get(&db, "users", &8u32.to_be_bytes()).as_deref(), Some(&[8; 50][..]));
+    drop(db);
+    let db = open(&mut fs);
+    assert_eq!(get(&db, "users", &9u32.to_be_bytes()).as_deref(), Some(&[9; 50][..]));
+  }
+
+  #[test]
+  fn test_rollback() {
+    let mut fs = vfs::MemoryFileSystem::default();
+    let db = open(&mut fs);
+    let root = create(&db, "users");
+    let mut transaction = db.begin_write().unwrap();
+    let page_id = transaction.allocate().unwrap();
+    let mut tree = bplus::BasicTree::open(root);
+    tree.insert(&mut transaction, b"a", b"1").unwrap();
+    transaction.rollback();
+    let mut transaction = db.begin_write().unwrap();
+    tree.insert(&mut transaction, b"b", b"2").unwrap();
+    drop(transaction);
+    assert_eq!(get(&db, "users", b"a"), None);
+    assert_eq!(get(&db, "users", b"b"), None);
+    // The allocator state is restored as well.
+    let mut transaction = db.begin_write().unwrap();
+    assert_eq!(transaction.allocate().unwrap(), page_id);
+  }
+
+  #[test]
+  fn test_savepoints() {
+    let mut fs = vfs::MemoryFileSystem::default();
+    let db = open(&mut fs);
+    let root = create(&db, "users");
+    let mut transaction = db.begin_write().unwrap();
+    let mut tree = bplus::BasicTree::open(root);
+    tree.insert(&mut transaction, b"a", b"1").unwrap();
+    transaction.savepoint("outer");
+    tree.insert(&mut transaction, b"b", b"1").unwrap();
+    transaction.savepoint("inner");
+    tree.insert(&mut transaction, b"c", b"1").unwrap();
+    assert!(transaction.release("inner"));
+    assert!(!transaction.release("inner"));
+
+    // Released changes are rolled back with the enclosing savepoint, which remains.
+    assert!(transaction.rollback_to("outer"));
+    assert_eq!(tree.get(&mut transaction, b"a").unwrap().as_deref(), Some(&b"1"[..]));
+    assert_eq!(tree.get(&mut transaction, b"b").unwrap(), None);
+    assert_eq!(tree.get(&mut transaction, b"c").unwrap(), None);
+    tree.insert(&mut transaction, b"d", b"1").unwrap();
+    transaction.savepoint("inner");
+    tree.insert(&mut transaction, b"e", b"1").unwrap();
+    assert!(transaction.rollback_to("outer"));
+    assert!(!transaction.rollback_to("inner"));
+    assert_eq!(tree.get(&mut transaction, b"d").unwrap(), None);
+    tree.insert(&mut transaction, b"f", b"1").unwrap();
+    transaction.commit().unwrap();
+    assert_eq!(get(&db, "users", b"a").as_deref(), Some(&b"1"[..]));
+    assert_eq!(get(&db, "users", b"e"), None);
+    assert_eq!(get(&db, "users", b"f").as_deref(), Some(&b"1"[..]));
+  }
+
+  #[test]
+  fn test_savepoint_allocator() {
+    let mut fs = vfs::MemoryFileSystem::default();
+    let db = open(&mut fs);
+    let mut transaction = db.begin_write().unwrap();
+    let first = transaction.allocate().unwrap();
+    let second = transaction.allocate().unwrap();
+    transaction.savepoint("a");
+    transaction.deallocate(first).unwrap();
+    transaction.savepoint("a");
+    assert_eq!(transaction.allocate().unwrap(), first);
+    let third = transaction.allocate().unwrap();
+    transaction.write(third, &[1; 512]).unwrap();
+
+    // The most recent savepoint with the name is rolled back to.
+    assert!(transaction.rollback_to("a"));
+    assert!(matches!(transaction.get(third), Err(paging::Error::Corrupt { .. })));
+    assert_eq!(transaction.allocate().unwrap(), first);
+    transaction.write(second, &[2; 512]).unwrap();
+    assert!(transaction.release("a"));
+    assert!(transaction.rollback_to("a"));
+    assert_eq!(transaction.get(second).unwrap(), &[0; 512]);
+    assert_eq!(transaction.allocate().unwrap(), third);
+    transaction.commit().unwrap();
+  }
+
+  #[test]
+  fn test_uncommitted_changes_invisible() {
+    let mut fs = vfs::MemoryFileSystem::default();
+    let db = open(&mut fs);
+    let root = create(&db, "users");
+    let mut transaction = db.begin_write().unwrap();
+    let mut tree = bplus::BasicTree::open(root);
+    tree.insert(&mut transaction, b"a", b"1").unwrap();
+    assert_eq!(get(&db, "users", b"a"), None);
+    transaction.commit().unwrap();
+    assert_eq!(get(&db, "users", b"a").as_deref(), Some(&b"1"[..]));
+  }
+
+  #[test]
+  fn test_single_writer() {
+    let mut fs = vfs::MemoryFileSystem::default();
+    let db = open(&mut fs);
+    let other = open(&mut fs);
+    let transaction = db.begin_write().unwrap();
+    assert!(matches!(other.begin_write(), Err(paging::Error::Busy)));
+    transaction.commit().unwrap();
+    let transaction = other.begin_write().unwrap();
+    assert!(matches!(db.begin_write(), Err(paging::Error::Busy)));
+    drop(transaction);
+    db.begin_write().unwrap();
+  }
+
+  #[test]
+  fn test_locked_database_file() {
+    let mut fs = vfs::MemoryFileSystem::default();
+    let db = open(&mut fs);
+    let root = create(&db, "users");
+    set(&db, root, b"a", b"1");
+    // Read transactions cannot start during a checkpoint of another handle.
+    let mut file = fs.open("db").unwrap();
+    file.lock().unwrap();
+    assert!(matches!(db.begin_read(), Err(paging::Error::Busy)));
+    // Checkpoints cannot run while another handle has an active read transaction.
+    file.unlock().unwrap();
+    assert!(file.try_lock_shared().unwrap());
+    assert!(!db.checkpoint().unwrap());
+    file.unlock().unwrap();
+    assert!(db.checkpoint().unwrap());
+  }
+
+  #[test]
+  fn test_read_only() {
+    let mut fs = vfs::MemoryFileSystem::default();
+    let db = open(&mut fs);
+    let root = create(&db, "users");
+    let mut transaction = db.begin_read().unwrap();
+    let mut tree = bplus::BasicTree::open(root);
+    assert!(matches!(transaction.allocate(), Err(paging::Error::ReadOnly)));
+    assert!(matches!(tree.insert(&mut transaction, b"a", b"1"), Err(paging::Error::ReadOnly)));
+  }
+
+  #[test]
+  fn test_multiple_handles() {
+    let mut fs = vfs::MemoryFileSystem::default();
+    let db = open(&mut fs);
+    let other = open(&mut fs);
+    let root = create(&db, "users");
+    set(&db, root, b"a", b"1");
+    assert_eq!(get(&other, "users", b"a").as_deref(), Some(&b"1"[..]));
+    set(&other, root, b"b", b"1");
+    assert_eq!(get(&db, "users", b"b").as_deref(), Some(&b"1"[..]));
+
+    // Checkpoints through one handle are noticed by the other.
+    assert!(other.checkpoint().unwrap());
+    set(&db, root, b"c", b"1");
+    assert_eq!(get(&other, "users", b"c").as_deref(), Some(&b"1"[..]));
+    set(&other, root, b"d", b"1");
+    assert!(db.checkpoint().unwrap());
+    set(&other, root, b"e", b"1");
+    drop((db, other));
+    let db = open(&mut fs);
+    for key in [b"a", b"b", b"c", b"d", b"e"] {
+      assert_eq!(get(&db, "users", key).as_deref(), Some(&b"1"[..]));
+    }
+  }
+
+  #[test]
+  fn test_torn_commit() {
+    let mut fs = vfs::MemoryFileSystem::default();
+    let db = open(&mut fs);
+    let root = create(&db, "users");
+    for key in [b"a", b"b"] {
+      let mut transaction = db.begin_write().unwrap();
+      bplus::BasicTree::open(root).insert(&mut transaction, key, b"1").unwrap();
+      transaction.commit().unwrap();
+    }
+    drop(db);
+    // Cut off the commit frame of the last transaction.
+    let mut wal = fs.open("db-wal").unwrap();
+    let size = wal.size().unwrap();
+    wal.truncate(size - 10).unwrap();
+    let db = open(&mut fs);
+    assert_eq!(get(&db, "users", b"a").as_deref(), Some(&b"1"[..]));
+    assert_eq!(get(&db, "users", b"b"), None);
+
+    // Frames following a corrupted one are ignored.
+    let mut transaction = db.begin_write().unwrap();
+    bplus::BasicTree::open(root).insert(&mut transaction, b"c", b"1").unwrap();
+    transaction.commit().unwrap();
+    drop(db);
+    let size = wal.size().unwrap();
+    let frame_size = (FRAME_HEADER_SIZE + 512) as u64;
+    wal.write(size - 2 * frame_size + 100, &[0xFF]).unwrap();
+    let db = open(&mut fs);
+    assert_eq!(get(&db, "users", b"a").as_deref(), Some(&b"1"[..]));
+    assert_eq!(get(&db, "users", b"c"), None);
+  }
+
+  #[test]
+  fn test_snapshot_isolation() {
+    let mut fs = vfs::MemoryFileSystem::default();
+    let db = open(&mut fs);
+    let root = create(&db, "users");
+    set(&db, root, b"a", b"1");
+    let mut reader = db.begin_read().unwrap();
+    let tree = bplus::BasicTree::open(root);
+    set(&db, root, b"a", b"2");
+    for i in 0..200u32 {
+      set(&db, root, &i.to_be_bytes(), &[i as u8; 50]);
+    }
+    assert_eq!(tree.get(&mut reader, b"a").unwrap().as_deref(), Some(&b"1"[..]));
+    assert_eq!(tree.get(&mut reader, &7u32.to_be_bytes()).unwrap(), None);
+    assert_eq!(get(&db, "users", b"a").as_deref(), Some(&b"2"[..]));
+    assert_eq!(get(&db, "users", &7u32.to_be_bytes()).as_deref(), Some(&[7; 50][..]));
+  }
+
+  #[test]
+  fn test_checkpoint_stops_at_oldest_reader() {
+    let mut fs = vfs::MemoryFileSystem::default();
+    let db = open(&mut fs);
+    let root = create(&db, "users");
+    set(&db, root, b"a", b"1");
+    let mut reader = db.begin_read().unwrap();
+    let tree = bplus::BasicTree::open(root);
+    set(&db, root, b"a", b"2");
+
+    // Pages written before the reader started are copied, but the WAL is kept.
+    assert!(!db.checkpoint().unwrap());
+    assert_ne!(fs.open("db-wal").unwrap().size().unwrap(), 0);
+    assert_eq!(tree.get(&mut reader, b"a").unwrap().as_deref(), Some(&b"1"[..]));
+    assert_eq!(get(&db, "users", b"a").as_deref(), Some(&b"2"[..]));
+
+    // Later readers do not hold back the checkpoint further than the oldest one.
+    let newer = db.begin_read().unwrap();
+    drop(reader);
+    assert!(!db.checkpoint().unwrap());
+    drop(newer);
+    assert!(db.checkpoint().unwrap());
+    assert_eq!(fs.open("db-wal").unwrap().size().unwrap(), 0);
+    assert_eq!(get(&db, "users", b"a").as_deref(), Some(&b"2"[..]));
+    drop(db);
+    let db = open(&mut fs);
+    assert_eq!(get(&db, "users", b"a").as_deref(), Some(&b"2"[..]));
+  }
+
+  #[test]
+  fn test_readers_of_other_handles() {
+    let mut fs = vfs::MemoryFileSystem::default();
+    let db = open(&mut fs);
+    let other = open(&mut fs);
+    let root = create(&db, "users");
+    set(&db, root, b"a", b"1");
+    let mut reader = other.begin_read().unwrap();
+    let tree = bplus::BasicTree::open(root);
+    set(&db, root, b"a", b"2");
+    for i in 0..50u32 {
+      set(&db, root, &i.to_be_bytes(), &[i as u8; 50]);
+    }
+
+    // The reader holds back checkpoints through both handles.
+    assert!(!db.checkpoint().unwrap());
+    assert!(!other.checkpoint().unwrap());
+    assert_ne!(fs.open("db-wal").unwrap().size().unwrap(), 0);
+    assert_eq!(tree.get(&mut reader, b"a").unwrap().as_deref(), Some(&b"1"[..]));
+    assert_eq!(tree.get(&mut reader, &7u32.to_be_bytes()).unwrap(), None);
+    assert_eq!(get(&db, "users", b"a").as_deref(), Some(&b"2"[..]));
+
+    // Once it finishes, the WAL is truncated, which the other handle notices.
+    drop(reader);
+    assert!(db.checkpoint().unwrap());
+    assert_eq!(fs.open("db-wal").unwrap().size().unwrap(), 0);
+    assert_eq!(get(&other, "users", b"a").as_deref(), Some(&b"2"[..]));
+    assert_eq!(get(&other, "users", &7u32.to_be_bytes()).as_deref(), Some(&[7; 50][..]));
+  }
+
+  #[test]
+  fn test_concurrent_readers() {
+    let mut fs = vfs::MemoryFileSystem::default();
+    let db = open(&mut fs);
+    let root = create(&db, "users");
+    set(&db, root, b"a", &0u32.to_be_bytes());
+    set(&db, root, b"b", &0u32.to_be_bytes());
+    std::thread::scope(|scope| {
+      scope.spawn(|| {
+        for i in 1..100u32 {
+          let mut transaction = db.begin_write().unwrap();
+          let mut tree = bplus::BasicTree::open(root);
+          tree.insert(&mut transaction, b"a", &i.to_be_bytes()).unwrap();
+          tree.insert(&mut transaction, b"b", &i.to_be_bytes()).unwrap();
+          transaction.commit().unwrap();
+          if i % 10 == 0 {
+            db.checkpoint().unwrap();
+          }
+        }
+      });
+      for _ in 0..2 {
+        scope.spawn(|| {
+          let mut last = 0;
+          while last < 99 {
+            let mut transaction = db.begin_read().unwrap();
+            let tree = bplus::BasicTree::open(root);
+            let a = tree.get(&mut transaction, b"a").unwrap().unwrap();
+            let b = tree.get(&mut transaction, b"b").unwrap().unwrap();
+            assert_eq!(a, b);
+            let current = u32::from_be_bytes(a[..].try_into().unwrap());
+            assert!(current >= last);
+            last = current;
+          }
+        });
+      }
+    });
+    assert!(db.checkpoint().unwrap());
+  }
+}
@@ -0,0 +1,172 @@
+//! # Ordered maps
+//!
+//! Interfaces shared by all tree kinds in the storage engine, so that code which only needs an
+//! ordered map from byte strings to byte strings can work with either [`super::bplus`] or
+//! [`super::prolly`] trees.
+
+use super::paging;
+use std::ops;
+
+/// # Ordered map interface
+///
+/// All methods report I/O errors and corrupted pages through [`paging::Error`]. Whether the map is
+/// left unchanged when a modifying method returns an error depends on the implementation.
+pub trait Map<Store: paging::Store> {
+  /// The type of cursors used by this map.
+  type Cursor: Cursor<Store>;
+
+  /// Returns a copy of the value corresponding to the key.
+  fn get(&self, store: &mut Store, key: &[u8]) -> paging::Result<Option<Box<[u8]>>, Store>;
+
+  /// Inserts or updates a key-value pair in the map. Returns whether the key was present.
+  fn insert(&mut self, store: &mut Store, key: &[u8], value: &[u8]) -> paging::Result<bool, Store>;
+
+  /// Removes a key-value pair from the map. Returns whether the key was present.
+  fn remove(&mut self, store: &mut Store, key: &[u8]) -> paging::Result<bool, Store>;
+
+  /// Returns a [`Cursor`] pointing at the gap after the greatest key smaller than the given bound.
+  fn upper_bound(
+    &self,
+    store: &mut Store,
+    bound: ops::Bound<&[u8]>,
+  ) -> paging::Result<Self::Cursor, Store>;
+
+  /// Returns a [`Cursor`] pointing at the gap before the smallest key greater than the given bound.
+  fn lower_bound(
+    &self,
+    store: &mut Store,
+    bound: ops::Bound<&[u8]>,
+  ) -> paging::Result<Self::Cursor, Store>;
+}
+
+/// A key and value borrowed from a map or cursor.
+pub type Element<'a> = (&'a [u8], &'a [u8]);
+
+/// A key and value copied out of a map.
+pub type OwnedElement = (Box<[u8]>, Box<[u8]>);
+
+/// # Cursor interface
+///
+/// A [`Cursor`] is like an iterator, except that it can freely seek back-and-forth.
+///
+/// Cursors always point to a gap between two elements in the map, and can operate on the two
+/// immediately adjacent elements.
+pub trait Cursor<Store: paging::Store> {
+  /// Advances the cursor to the next gap, returning the key and value of the element that it moved
+  /// over. If the cursor is already at the end of the map then `None` is returned and the cursor is
+  /// not moved.
+  fn next(&mut self, store: &mut Store) -> paging::Result<Option<Element<'_>>, Store>;
+
+  /// Advances the cursor to the previous gap, returning the key and value of the element that it
+  /// moved over. If the cursor is already at the start of the map then `None` is returned and the
+  /// cursor is not moved.
+  fn prev(&mut self, store: &mut Store) -> paging::Result<Option<Element<'_>>, Store>;
+
+  /// Returns a reference to the key and value of the next element without moving the cursor.
+  /// If the cursor is at the end of the map then `None` is returned.
+  fn peek_next(&mut self, store: &mut Store) -> paging::Result<Option<Element<'_>>, Store>;
+
+  /// Returns a reference to the key and value of the previous element without moving the cursor.
+  /// If the cursor is at the start of the map then `None` is returned.
+  fn peek_prev(&mut self, store: &mut Store) -> paging::Result<Option<Element<'_>>, Store>;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::storage::testing::{open_store, TestPolicy, TestStore};
+  use crate::storage::{bplus, prolly};
+  use rand::rngs::StdRng;
+  use rand::{Rng, SeedableRng};
+  use std::collections;
+
+  /// Collects the elements in the given range, scanning forward or backward.
+  fn scan<M: Map<TestStore>>(
+    store: &mut TestStore,
+    map: &M,
+    start: ops::Bound<&[u8]>,
+    end: ops::Bound<&[u8]>,
+    forward: bool,
+  ) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut res = Vec::new();
+    if forward {
+      let mut cursor = map.lower_bound(store, start).unwrap();
+      let end = (ops::Bound::Unbounded, end);
+      while let Some((key, value)) = cursor.next(store).unwrap() {
+        if !ops::RangeBounds::<[u8]>::contains(&end, key) {
+          break;
+        }
+        res.push((key.to_vec(), value.to_vec()));
+      }
+    } else {
+      let mut cursor = map.upper_bound(store, end).unwrap();
+      let start = (start, ops::Bound::Unbounded);
+      while let Some((key, value)) = cursor.prev(store).unwrap() {
+        if !ops::RangeBounds::<[u8]>::contains(&start, key) {
+          break;
+        }
+        res.push((key.to_vec(), value.to_vec()));
+      }
+      res.reverse();
+    }
+    res
+  }
+
+  /// Runs random operations and range scans against a reference implementation.
+  fn check_random_operations<M: Map<TestStore>>(store: &mut TestStore, map: &mut M) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut model = collections::BTreeMap::new();
+    for round in 0..2000u32 {
+      let key = format!("key{:04}", rng.gen_range(0..500)).into_bytes();
+      if rng.gen_bool(0.6) {
+        let value = vec![round as u8; rng.gen_range(0..300)];
+        let present = model.insert(key.clone(), value.clone()).is_some();
+        assert_eq!(map.insert(store, &key, &value).unwrap(), present);
+      } else {
+        assert_eq!(map.remove(store, &key).unwrap(), model.remove(&key).is_some());
+      }
+      assert_eq!(map.get(store, &key).unwrap().as_deref(), model.get(&key).map(|v| &v[..]));
+    }
+    for _ in 0..50 {
+      let mut bound = || {
+        let key = format!("key{:04}", rng.gen_range(0..500)).into_bytes();
+        match rng.gen_range(0..3) {
+          0 => ops::Bound::Included(key),
+          1 => ops::Bound::Excluded(key),
+          _ => ops::Bound::Unbounded,
+        }
+      };
+      let (start, end) = (bound(), bound());
+      if let (
+        ops::Bound::Included(s) | ops::Bound::Excluded(s),
+        ops::Bound::Included(e) | ops::Bound::Excluded(e),
+      ) = (&start, &end)
+      {
+        if s >= e {
+          continue;
+        }
+      }
+      let expected = model
+        .range((start.clone(), end.clone()))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect::<Vec<_>>();
+      let (start, end) = (start.as_ref().map(|k| &k[..]), end.as_ref().map(|k| &k[..]));
+      assert_eq!(scan(store, map, start, end, true), expected);
+      assert_eq!(scan(store, map, start, end, false), expected);
+    }
+  }
+
+  #[test]
+  fn test_bplus() {
+    let mut store = open_store();
+    let mut tree = bplus::BasicTree::create(&mut store).unwrap();
+    check_random_operations(&mut store, &mut tree);
+  }
+
+  #[test]
+  fn test_prolly() {
+    let mut store = open_store();
+    let mut tree = prolly::BasicTree::<TestStore, _>::new(TestPolicy);
+    check_random_operations(&mut store, &mut tree);
+  }
+}
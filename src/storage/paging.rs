@@ -5,6 +5,85 @@
 #![doc = include_str!("../../doc/file_format.md")]
 
 use super::vfs;
+use crate::encoding::crc64;
+use crate::encoding::prefix_varint;
+use std::collections;
+use std::fmt;
+use std::result;
+
+/// Page type of B+ tree internal node pages.
+pub const BPLUS_INTERNAL: u16 = 0;
+
+/// Page type of B+ tree leaf node pages.
+pub const BPLUS_LEAF: u16 = 1;
+
+/// Page type of Prolly tree internal node pages.
+pub const PROLLY_INTERNAL: u16 = 2;
+
+/// Page type of Prolly tree leaf node pages.
+pub const PROLLY_LEAF: u16 = 3;
+
+/// Page type of overflow pages.
+pub const OVERFLOW: u16 = 4;
+
+/// Page type of B+ tree leaf node pages with prefix compression.
+pub const BPLUS_PREFIXED_LEAF: u16 = 5;
+
+const MAGIC: u64 = 0x7365676150204244;
+const HEADER_SIZE: usize = 24;
+const NODE_HEADER_SIZE: usize = 4;
+const OVERFLOW_HEADER_SIZE: usize = 20;
+
+/// # Storage errors
+///
+/// Errors that can occur when accessing pages. Apart from errors reported by the underlying file,
+/// this also covers data which is found to be corrupted while it is being read, keys, content
+/// hashes and summaries which are too large to be stored, and stores which cannot be accessed as
+/// requested.
+#[derive(Debug)]
+pub enum Error<E> {
+  /// The underlying file reported an error.
+  Io(E),
+  /// The content of a page does not match its checksum.
+  Checksum { page_id: u64 },
+  /// A page is malformed, has an unexpected type, or is referenced while it should not be.
+  Corrupt { page_id: u64 },
+  /// A key of `len` bytes is longer than the maximum of `max` bytes allowed by the page size.
+  TooLarge { len: usize, max: usize },
+  /// A content hash or summary of `len` bytes, produced by a tree policy or aggregate, is longer
+  /// than the maximum of `max` bytes allowed by the page size.
+  DigestTooLarge { len: usize, max: usize },
+  /// The store is locked through another handle, possibly in another process.
+  Busy,
+  /// A page was modified through a store which only allows reading.
+  ReadOnly,
+}
+
+/// Conversion from file errors, so that they can be propagated with `?`.
+impl<E> From<E> for Error<E> {
+  fn from(error: E) -> Self {
+    Error::Io(error)
+  }
+}
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Error::Io(error) => write!(f, "I/O error: {error}"),
+      Error::Checksum { page_id } => write!(f, "checksum mismatch in page {page_id}"),
+      Error::Corrupt { page_id } => write!(f, "corrupt page {page_id}"),
+      Error::TooLarge { len, max } => write!(f, "key of {len} bytes exceeds maximum of {max}"),
+      Error::DigestTooLarge { len, max } => {
+        write!(f, "content hash or summary of {len} bytes exceeds maximum of {max}")
+      }
+      Error::Busy => write!(f, "store is locked"),
+      Error::ReadOnly => write!(f, "store is read-only"),
+    }
+  }
+}
+
+/// The result type of operations on a [`Store`] of type `S`.
+pub type Result<T, S> = result::Result<T, Error<<<S as Store>::File as vfs::File>::Error>>;
 
 /// # Slotted page store
 ///
@@ -20,15 +99,604 @@ pub trait Store {
   /// The type of files used to store pages.
   type File: vfs::File;
 
-  // /// Obtains a page from the store.
-  // fn get(&mut self, page_id: u64) -> Result<Self::Page, <Self::File as vfs::File>::Error>;
+  /// Returns the size of each page in bytes.
+  fn page_size(&self) -> usize;
+
+  /// Obtains a page from the store. The page stays in the buffer pool for as long as it is borrowed.
+  fn get(&mut self, page_id: u64) -> Result<&[u8], Self>;
+
+  /// Writes a page to the store. The length of `data` must be equal to the page size.
+  fn write(&mut self, page_id: u64, data: &[u8]) -> Result<(), Self>;
+
+  /// Allocates a new page in the store.
+  fn allocate(&mut self) -> Result<u64, Self>;
+
+  /// Deallocates a page in the store.
+  fn deallocate(&mut self, page_id: u64) -> Result<(), Self>;
+}
+
+/// Parses a node page, returning its page type and its cells.
+///
+/// Returns `None` if the page header or cell pointers are malformed.
+pub fn read_node_page(page: &[u8]) -> Option<(u16, Vec<&[u8]>)> {
+  let page_type = u16::from_le_bytes(page.get(0..2)?.try_into().ok()?);
+  let count = u16::from_le_bytes(page.get(2..4)?.try_into().ok()?) as usize;
+  let start = NODE_HEADER_SIZE + 2 * count;
+  let mut cells = Vec::with_capacity(count);
+  let mut end = page.len();
+  for i in 0..count {
+    let offset = NODE_HEADER_SIZE + 2 * i;
+    let pointer = u16::from_le_bytes(page.get(offset..offset + 2)?.try_into().ok()?) as usize;
+    if pointer < start || pointer > end {
+      return None;
+    }
+    cells.push(&page[pointer..end]);
+    end = pointer;
+  }
+  Some((page_type, cells))
+}
+
+/// Fills `page` with a node page of the given type, containing the given cells.
+///
+/// Returns `false` if the cells do not fit into the page.
+pub fn write_node_page<T: AsRef<[u8]>>(page: &mut [u8], page_type: u16, cells: &[T]) -> bool {
+  let used = cells.iter().map(|cell| cell.as_ref().len() + 2).sum::<usize>();
+  if cells.len() > u16::MAX as usize || NODE_HEADER_SIZE + used > page.len() {
+    return false;
+  }
+  page.fill(0);
+  page[0..2].copy_from_slice(&page_type.to_le_bytes());
+  page[2..4].copy_from_slice(&(cells.len() as u16).to_le_bytes());
+  let mut end = page.len();
+  for (i, cell) in cells.iter().enumerate() {
+    let cell = cell.as_ref();
+    let pointer = end - cell.len();
+    let offset = NODE_HEADER_SIZE + 2 * i;
+    page[offset..offset + 2].copy_from_slice(&(pointer as u16).to_le_bytes());
+    page[pointer..end].copy_from_slice(cell);
+    end = pointer;
+  }
+  true
+}
+
+/// Returns the number of cell bytes (including cell pointers) that fit into a node page.
+pub fn node_page_capacity(page_size: usize) -> usize {
+  page_size - NODE_HEADER_SIZE
+}
+
+/// Checks that a key can be stored in a tree node, which allows keys of up to an eighth of a page.
+pub(crate) fn check_key<S: Store>(store: &S, key: &[u8]) -> Result<(), S> {
+  let max = store.page_size() / 8;
+  if key.len() > max {
+    return Err(Error::TooLarge { len: key.len(), max });
+  }
+  Ok(())
+}
+
+/// Reads a prefix-varint encoded integer from the front of `cell`, advancing it.
+pub(crate) fn take_varint(cell: &mut &[u8]) -> Option<u64> {
+  let len = prefix_varint::length(*cell.first()?) as usize;
+  take_bytes(cell, len).map(prefix_varint::decode)
+}
+
+/// Reads a little-endian 64-bit unsigned integer from the front of `cell`, advancing it.
+pub(crate) fn take_u64(cell: &mut &[u8]) -> Option<u64> {
+  take_bytes(cell, 8).map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Reads `len` bytes from the front of `cell`, advancing it.
+pub(crate) fn take_bytes<'a>(cell: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+  if cell.len() < len {
+    return None;
+  }
+  let (head, tail) = cell.split_at(len);
+  *cell = tail;
+  Some(head)
+}
+
+/// Writes `data` into a newly allocated chain of overflow pages, returning the first page ID.
+pub fn write_overflow<S: Store>(store: &mut S, data: &[u8]) -> Result<u64, S> {
+  let page_size = store.page_size();
+  let chunk_size = page_size - OVERFLOW_HEADER_SIZE;
+  let chunks = data.chunks(chunk_size).collect::<Vec<_>>();
+  let mut page_ids = Vec::with_capacity(chunks.len().max(1));
+  for _ in 0..chunks.len().max(1) {
+    page_ids.push(store.allocate()?);
+  }
+  let mut page = vec![0; page_size];
+  for (i, &page_id) in page_ids.iter().enumerate() {
+    let chunk = chunks.get(i).copied().unwrap_or_default();
+    let next = page_ids.get(i + 1).copied().unwrap_or(0);
+    page.fill(0);
+    page[0..2].copy_from_slice(&OVERFLOW.to_le_bytes());
+    page[4..12].copy_from_slice(&next.to_le_bytes());
+    page[12..20].copy_from_slice(&crc64::checksum(chunk).to_le_bytes());
+    page[OVERFLOW_HEADER_SIZE..OVERFLOW_HEADER_SIZE + chunk.len()].copy_from_slice(chunk);
+    store.write(page_id, &page)?;
+  }
+  Ok(page_ids[0])
+}
+
+/// Reads `len` bytes from the chain of overflow pages starting at `page_id`, verifying checksums.
+pub fn read_overflow<S: Store>(store: &mut S, page_id: u64, len: u64) -> Result<Vec<u8>, S> {
+  // The length comes from a cell which may be corrupted, so the value is collected page by page
+  // instead of allocating it up front, and cycles in the chain are detected.
+  let mut chunk = vec![0; store.page_size() - OVERFLOW_HEADER_SIZE];
+  let mut data = Vec::new();
+  let mut visited = collections::HashSet::new();
+  let mut reader = OverflowReader::new(store, page_id, len);
+  while reader.remaining() > 0 {
+    // Each read ends at the end of a page.
+    let n = reader.read(&mut chunk)?;
+    if !visited.insert(reader.page_id) {
+      return Err(Error::Corrupt { page_id: reader.page_id });
+    }
+    data.extend_from_slice(&chunk[..n]);
+  }
+  Ok(data)
+}
+
+/// # Streaming reader for overflow pages
+///
+/// Reads a value stored in a chain of overflow pages incrementally, so that large values do not
+/// need to be copied into memory as a whole. The checksum of each page is verified before any part
+/// of it is returned.
+pub struct OverflowReader<'a, S: Store> {
+  store: &'a mut S,
+  page_id: u64,
+  offset: usize,
+  verified: bool,
+  remaining: u64,
+  len: u64,
+}
+
+impl<'a, S: Store> OverflowReader<'a, S> {
+  /// Creates a reader for the `len`-byte value stored in overflow pages starting at `page_id`.
+  pub fn new(store: &'a mut S, page_id: u64, len: u64) -> Self {
+    Self { store, page_id, offset: 0, verified: false, remaining: len, len }
+  }
+
+  /// Returns the total length of the value.
+  pub fn len(&self) -> u64 {
+    self.len
+  }
+
+  /// Returns whether the value is empty.
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  /// Returns the number of bytes which have not been read yet.
+  pub fn remaining(&self) -> u64 {
+    self.remaining
+  }
+
+  /// Reads some bytes of the value into `buf`, returning the number of bytes read. This is only
+  /// zero if `buf` is empty or the end of the value has been reached.
+  pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, S> {
+    if self.remaining == 0 || buf.is_empty() {
+      return Ok(0);
+    }
+    let chunk_size = self.store.page_size() - OVERFLOW_HEADER_SIZE;
+    if self.offset == chunk_size {
+      let page = self.store.get(self.page_id)?;
+      let next = u64::from_le_bytes(page[4..12].try_into().unwrap());
+      if next == 0 {
+        return Err(Error::Corrupt { page_id: self.page_id });
+      }
+      self.page_id = next;
+      self.offset = 0;
+      self.verified = false;
+    }
+    let page_id = self.page_id;
+    let len = (self.offset as u64 + self.remaining).min(chunk_size as u64) as usize;
+    let page = self.store.get(page_id)?;
+    if u16::from_le_bytes([page[0], page[1]]) != OVERFLOW {
+      return Err(Error::Corrupt { page_id });
+    }
+    let chunk = &page[OVERFLOW_HEADER_SIZE..OVERFLOW_HEADER_SIZE + len];
+    if !self.verified {
+      if crc64::checksum(chunk) != u64::from_le_bytes(page[12..20].try_into().unwrap()) {
+        return Err(Error::Checksum { page_id });
+      }
+      self.verified = true;
+    }
+    let n = buf.len().min(len - self.offset);
+    buf[..n].copy_from_slice(&chunk[self.offset..self.offset + n]);
+    self.offset += n;
+    self.remaining -= n as u64;
+    Ok(n)
+  }
+}
+
+/// Returns the page IDs in the chain of overflow pages starting at `page_id`, without verifying
+/// checksums.
+pub fn overflow_pages<S: Store>(store: &mut S, page_id: u64) -> Result<Vec<u64>, S> {
+  let mut page_ids = Vec::new();
+  let mut page_id = page_id;
+  while page_id != 0 {
+    let page = store.get(page_id)?;
+    if u16::from_le_bytes([page[0], page[1]]) != OVERFLOW || page_ids.contains(&page_id) {
+      return Err(Error::Corrupt { page_id });
+    }
+    page_ids.push(page_id);
+    page_id = u64::from_le_bytes(page[4..12].try_into().unwrap());
+  }
+  Ok(page_ids)
+}
+
+/// Deallocates the chain of overflow pages starting at `page_id`.
+pub fn free_overflow<S: Store>(store: &mut S, page_id: u64) -> Result<(), S> {
+  let mut page_id = page_id;
+  while page_id != 0 {
+    let page = store.get(page_id)?;
+    if u16::from_le_bytes([page[0], page[1]]) != OVERFLOW {
+      return Err(Error::Corrupt { page_id });
+    }
+    let next = u64::from_le_bytes(page[4..12].try_into().unwrap());
+    store.deallocate(page_id)?;
+    page_id = next;
+  }
+  Ok(())
+}
+
+/// Encodes the database header page, given the page size and the first free page ID.
+pub(crate) fn encode_header(page_size: usize, freelist: u64) -> Vec<u8> {
+  let mut header = vec![0; page_size];
+  header[0..8].copy_from_slice(&MAGIC.to_le_bytes());
+  header[12..14].copy_from_slice(&(page_size as u16).to_le_bytes());
+  header[16..24].copy_from_slice(&freelist.to_le_bytes());
+  header
+}
+
+/// Decodes the database header, returning the page size and the first free page ID.
+pub(crate) fn decode_header(header: &[u8]) -> Option<(usize, u64)> {
+  let magic = u64::from_le_bytes(header.get(0..8)?.try_into().unwrap());
+  let file_version = u16::from_le_bytes(header.get(8..10)?.try_into().unwrap());
+  let page_size = match u16::from_le_bytes(header.get(12..14)?.try_into().unwrap()) {
+    0 => 65536,
+    n => n as usize,
+  };
+  let freelist = u64::from_le_bytes(header.get(16..24)?.try_into().unwrap());
+  let valid = magic == MAGIC && file_version == 0 && page_size.is_power_of_two();
+  valid.then_some((page_size, freelist))
+}
+
+/// # Standard implementation for [`Store`]
+///
+/// Pages are read from a single [`vfs::File`] laid out as described in the file format, and cached
+/// in memory. Modified pages are kept in memory until [`BasicStore::flush`] is called.
+pub struct BasicStore<File: vfs::File> {
+  file: File,
+  page_size: usize,
+  page_count: u64,
+  freelist: u64,
+  cache: collections::HashMap<u64, Box<[u8]>>,
+  dirty: collections::BTreeSet<u64>,
+}
+
+impl<File: vfs::File> BasicStore<File> {
+  /// The default page size.
+  pub const DEFAULT_PAGE_SIZE: usize = 8192;
+
+  /// Opens the page store contained in `file`. If the file is empty, a new page store with the
+  /// given page size is initialised; otherwise the page size recorded in the file is used.
+  ///
+  /// Page `1` is reserved for the schema table, so the first allocated page is always page `2`.
+  ///
+  /// # Panics
+  ///
+  /// Panics if `page_size` is not a power of two between `512` and `65536`.
+  pub fn open(file: File, page_size: usize) -> Result<Self, Self> {
+    assert!(page_size.is_power_of_two() && (512..=65536).contains(&page_size));
+    let mut file = file;
+    let size = file.size()?;
+    if size == 0 {
+      let mut res = Self {
+        file,
+        page_size,
+        page_count: 2,
+        freelist: 0,
+        cache: collections::HashMap::new(),
+        dirty: collections::BTreeSet::new(),
+      };
+      res.write(1, &vec![0; page_size])?;
+      res.flush()?;
+      return Ok(res);
+    }
+    let mut header = [0; HEADER_SIZE];
+    file.read(0, &mut header)?;
+    let (page_size, freelist) = decode_header(&header).ok_or(Error::Corrupt { page_id: 0 })?;
+    if size % page_size as u64 != 0 {
+      return Err(Error::Corrupt { page_id: 0 });
+    }
+    Ok(Self {
+      file,
+      page_size,
+      page_count: size / page_size as u64,
+      freelist,
+      cache: collections::HashMap::new(),
+      dirty: collections::BTreeSet::new(),
+    })
+  }
+
+  /// Writes all modified pages and the database header back to the file, then synchronises it.
+  pub fn flush(&mut self) -> Result<(), Self> {
+    for &page_id in &self.dirty {
+      self.file.write(page_id * self.page_size as u64, &self.cache[&page_id])?;
+    }
+    self.dirty.clear();
+    self.file.write(0, &encode_header(self.page_size, self.freelist))?;
+    self.file.truncate(self.page_count * self.page_size as u64)?;
+    self.file.sync()?;
+    Ok(())
+  }
+
+  /// Returns the number of pages in the store, including the header page and free pages.
+  pub fn page_count(&self) -> u64 {
+    self.page_count
+  }
+}
+
+impl<File: vfs::File> Store for BasicStore<File> {
+  type File = File;
+
+  fn page_size(&self) -> usize {
+    self.page_size
+  }
+
+  fn get(&mut self, page_id: u64) -> Result<&[u8], Self> {
+    if page_id == 0 || page_id >= self.page_count {
+      return Err(Error::Corrupt { page_id });
+    }
+    if !self.cache.contains_key(&page_id) {
+      let mut page = vec![0; self.page_size].into_boxed_slice();
+      let offset = page_id * self.page_size as u64;
+      if offset < self.file.size()? {
+        self.file.read(offset, &mut page)?;
+      }
+      self.cache.insert(page_id, page);
+    }
+    Ok(&self.cache[&page_id])
+  }
+
+  fn write(&mut self, page_id: u64, data: &[u8]) -> Result<(), Self> {
+    assert_eq!(data.len(), self.page_size);
+    if page_id == 0 || page_id >= self.page_count {
+      return Err(Error::Corrupt { page_id });
+    }
+    self.cache.insert(page_id, data.into());
+    self.dirty.insert(page_id);
+    Ok(())
+  }
+
+  fn allocate(&mut self) -> Result<u64, Self> {
+    if self.freelist == 0 {
+      self.page_count += 1;
+      return Ok(self.page_count - 1);
+    }
+    let page_id = self.freelist;
+    let page = self.get(page_id)?;
+    self.freelist = u64::from_le_bytes(page[0..8].try_into().unwrap());
+    Ok(page_id)
+  }
+
+  fn deallocate(&mut self, page_id: u64) -> Result<(), Self> {
+    if page_id < 2 || page_id >= self.page_count {
+      return Err(Error::Corrupt { page_id });
+    }
+    let mut page = vec![0; self.page_size];
+    page[0..8].copy_from_slice(&self.freelist.to_le_bytes());
+    self.write(page_id, &page)?;
+    self.freelist = page_id;
+    Ok(())
+  }
+}
+
+/// # Scratch store
+///
+/// A store which keeps all modifications in memory on top of another store, discarding them when
+/// dropped. This allows computing the results of modifications without persisting them.
+///
+/// Pages allocated in a scratch store are given IDs counting down from `u64::MAX`, so that they
+/// never collide with pages of the underlying store. Deallocating pages of the underlying store has
+/// no effect.
+pub struct ScratchStore<'a, S: Store> {
+  base: &'a mut S,
+  pages: collections::HashMap<u64, Box<[u8]>>,
+  next: u64,
+}
+
+impl<'a, S: Store> ScratchStore<'a, S> {
+  /// Creates a scratch store on top of the given store.
+  pub fn new(base: &'a mut S) -> Self {
+    Self { base, pages: collections::HashMap::new(), next: u64::MAX }
+  }
+}
+
+impl<S: Store> Store for ScratchStore<'_, S> {
+  type File = S::File;
+
+  fn page_size(&self) -> usize {
+    self.base.page_size()
+  }
+
+  fn get(&mut self, page_id: u64) -> Result<&[u8], Self> {
+    match self.pages.get(&page_id) {
+      Some(page) => Ok(page),
+      None => self.base.get(page_id),
+    }
+  }
+
+  fn write(&mut self, page_id: u64, data: &[u8]) -> Result<(), Self> {
+    assert_eq!(data.len(), self.page_size());
+    self.pages.insert(page_id, data.into());
+    Ok(())
+  }
+
+  fn allocate(&mut self) -> Result<u64, Self> {
+    let page_id = self.next;
+    self.next -= 1;
+    self.pages.insert(page_id, vec![0; self.page_size()].into());
+    Ok(page_id)
+  }
+
+  fn deallocate(&mut self, page_id: u64) -> Result<(), Self> {
+    self.pages.remove(&page_id);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use vfs::FileSystem;
+
+  fn open_store(page_size: usize) -> BasicStore<vfs::MemoryFile> {
+    let mut fs = vfs::MemoryFileSystem::default();
+    BasicStore::open(fs.open("db").unwrap(), page_size).unwrap()
+  }
+
+  #[test]
+  fn test_node_page_round_trip() {
+    let mut page = vec![0; 512];
+    let cells: Vec<&[u8]> = vec![b"hello", b"", b"world!"];
+    assert!(write_node_page(&mut page, PROLLY_LEAF, &cells));
+    let (page_type, read) = read_node_page(&page).unwrap();
+    assert_eq!(page_type, PROLLY_LEAF);
+    assert_eq!(read, cells);
+  }
+
+  #[test]
+  fn test_node_page_overfull() {
+    let mut page = vec![0; 512];
+    let cells = vec![vec![0u8; 300], vec![0u8; 300]];
+    assert!(!write_node_page(&mut page, BPLUS_LEAF, &cells));
+  }
+
+  #[test]
+  fn test_node_page_malformed() {
+    let mut page = vec![0; 512];
+    // Claims to contain 300 cells, but the cell pointers are all zero.
+    page[2..4].copy_from_slice(&300u16.to_le_bytes());
+    assert!(read_node_page(&page).is_none());
+  }
+
+  #[test]
+  fn test_store_allocate_deallocate() {
+    let mut store = open_store(512);
+    let a = store.allocate().unwrap();
+    let b = store.allocate().unwrap();
+    assert_eq!((a, b), (2, 3));
+    store.deallocate(a).unwrap();
+    // Freed pages are reused before the file grows.
+    assert_eq!(store.allocate().unwrap(), a);
+    assert_eq!(store.allocate().unwrap(), 4);
+    store.deallocate(1).unwrap_err();
+    store.deallocate(5).unwrap_err();
+  }
+
+  #[test]
+  fn test_store_reopen() {
+    let mut fs = vfs::MemoryFileSystem::default();
+    let mut store = BasicStore::open(fs.open("db").unwrap(), 1024).unwrap();
+    let a = store.allocate().unwrap();
+    let b = store.allocate().unwrap();
+    store.write(a, &[1; 1024]).unwrap();
+    store.write(b, &[2; 1024]).unwrap();
+    store.deallocate(a).unwrap();
+    store.flush().unwrap();
+
+    // The page size recorded in the file takes precedence.
+    let mut store = BasicStore::open(fs.open("db").unwrap(), 8192).unwrap();
+    assert_eq!(store.page_size(), 1024);
+    assert_eq!(store.page_count(), 4);
+    assert_eq!(store.get(b).unwrap(), &[2; 1024]);
+    assert_eq!(store.allocate().unwrap(), a);
+  }
+
+  #[test]
+  fn test_store_corrupt_header() {
+    let mut fs = vfs::MemoryFileSystem::default();
+    let mut file = fs.open("db").unwrap();
+    vfs::File::write(&mut file, 0, &[0xCC; 1024]).unwrap();
+    assert!(matches!(BasicStore::open(file, 1024), Err(Error::Corrupt { page_id: 0 })));
+  }
+
+  #[test]
+  fn test_scratch_store() {
+    let mut store = open_store(512);
+    let page_id = store.allocate().unwrap();
+    store.write(page_id, &[1; 512]).unwrap();
+    let mut scratch = ScratchStore::new(&mut store);
+    let new_page_id = scratch.allocate().unwrap();
+    scratch.write(new_page_id, &[2; 512]).unwrap();
+    scratch.write(page_id, &[3; 512]).unwrap();
+    scratch.deallocate(page_id).unwrap();
+    assert_eq!(scratch.get(page_id).unwrap(), &[1; 512]);
+    assert_eq!(scratch.get(new_page_id).unwrap(), &[2; 512]);
+    // Modifications are not visible in the underlying store.
+    assert_eq!(store.get(page_id).unwrap(), &[1; 512]);
+    assert!(store.get(new_page_id).is_err());
+    assert_eq!(store.allocate().unwrap(), page_id + 1);
+  }
+
+  #[test]
+  fn test_overflow_round_trip() {
+    let mut store = open_store(512);
+    for len in [0usize, 1, 491, 492, 493, 5000] {
+      let data = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+      let page_id = write_overflow(&mut store, &data).unwrap();
+      assert_eq!(read_overflow(&mut store, page_id, len as u64).unwrap(), data);
+      let pages = overflow_pages(&mut store, page_id).unwrap();
+      assert_eq!(pages.len(), len.div_ceil(492).max(1));
+      assert_eq!(pages[0], page_id);
+      free_overflow(&mut store, page_id).unwrap();
+    }
+  }
 
-  // /// Writes a page to the store.
-  // fn write(&mut self, page_id: u64, slot_id: u16) -> Result<(), <Self::File as vfs::File>::Error>;
+  #[test]
+  fn test_overflow_reader() {
+    let mut store = open_store(512);
+    let data = (0..3000).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+    let page_id = write_overflow(&mut store, &data).unwrap();
+    let mut reader = OverflowReader::new(&mut store, page_id, data.len() as u64);
+    let mut read = Vec::new();
+    let mut buf = [0; 100];
+    loop {
+      let n = reader.read(&mut buf).unwrap();
+      if n == 0 {
+        break;
+      }
+      read.extend_from_slice(&buf[..n]);
+      assert_eq!(reader.remaining(), (data.len() - read.len()) as u64);
+    }
+    assert_eq!(read, data);
+  }
 
-  // /// Allocates a new page in the store.
-  // fn allocate(&mut self) -> Result<u64, <Self::File as vfs::File>::Error>;
+  #[test]
+  fn test_overflow_checksum_mismatch() {
+    let mut store = open_store(512);
+    let page_id = write_overflow(&mut store, &[42; 1000]).unwrap();
+    let mut page = store.get(page_id).unwrap().to_vec();
+    page[100] ^= 1;
+    store.write(page_id, &page).unwrap();
+    assert!(matches!(read_overflow(&mut store, page_id, 1000), Err(Error::Checksum { .. })));
+  }
 
-  // /// Deallocates a page in the store.
-  // fn deallocate(&mut self, id: u64) -> Result<(), <Self::File as vfs::File>::Error>;
+  #[test]
+  fn test_overflow_corrupt_length() {
+    let mut store = open_store(512);
+    let page_id = write_overflow(&mut store, &[42; 1000]).unwrap();
+    // The last page only has a checksum of its actual content.
+    let res = read_overflow(&mut store, page_id, 1 << 40);
+    assert!(matches!(res, Err(Error::Checksum { .. })));
+    // Chains pointing back to themselves are not followed forever.
+    let pages = overflow_pages(&mut store, page_id).unwrap();
+    let mut page = store.get(pages[1]).unwrap().to_vec();
+    page[4..12].copy_from_slice(&page_id.to_le_bytes());
+    store.write(pages[1], &page).unwrap();
+    let res = read_overflow(&mut store, page_id, 1 << 40);
+    assert!(matches!(res, Err(Error::Corrupt { .. })));
+  }
 }
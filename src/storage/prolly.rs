@@ -9,56 +9,123 @@
 //! two trees containing the same set of keys will be structurally identical; this property is
 //! called *unicity*), which is crucial for amortized near-O(d) diffing between trees.
 
+use super::map;
 use super::paging;
+use crate::encoding::prefix_varint;
+use std::collections;
+use std::iter;
+use std::marker;
+use std::mem;
 use std::ops;
+use std::sync;
 
 /// # Prolly tree interface
-pub trait Tree<Store: paging::Store> {
-  /// The type of cursors used by this tree.
-  type Cursor: Cursor<Store>;
+///
+/// All methods report I/O errors and corrupted pages through [`paging::Error`]. If an error is
+/// returned by a modifying method, the tree is left unchanged.
+pub trait Tree<Store: paging::Store>: map::Map<Store> {
+  /// Returns the value corresponding to the key without copying it. See [`ValueRef`].
+  fn get_ref<'a>(
+    &self,
+    store: &'a mut Store,
+    key: &[u8],
+  ) -> paging::Result<Option<ValueRef<'a, Store>>, Store>;
+
+  /// Applies a batch of insertions, updates and removals. The result is the same as applying them
+  /// one at a time, but nodes affected by multiple mutations are only rewritten once. Returns the
+  /// number of mutations whose keys were present.
+  ///
+  /// # Panics
+  ///
+  /// Panics if the keys of the mutations are not strictly increasing.
+  fn apply<'a>(
+    &mut self,
+    store: &mut Store,
+    mutations: impl IntoIterator<Item = Mutation<'a>>,
+  ) -> paging::Result<usize, Store>;
 
-  /// Returns a copy of the value corresponding to the key.
-  fn get(&self, store: &mut Store, key: &[u8]) -> Option<Box<[u8]>>;
+  /// Removes all key-value pairs whose keys fall into the given range. Returns the number of pairs
+  /// removed.
+  fn remove_range<'a>(
+    &mut self,
+    store: &mut Store,
+    range: impl ops::RangeBounds<&'a [u8]>,
+  ) -> paging::Result<u64, Store>;
 
-  /// Inserts or updates a key-value pair in the map. Returns whether the key was present.
-  fn insert(&mut self, store: &mut Store, key: &[u8], value: &[u8]) -> bool;
+  /// Returns the summary (see [`Aggregate`]) of all key-value pairs whose keys fall into the given
+  /// range.
+  fn aggregate<'a>(
+    &self,
+    store: &mut Store,
+    range: impl ops::RangeBounds<&'a [u8]>,
+  ) -> paging::Result<Box<[u8]>, Store>;
 
-  /// Removes a key-value pair from the map. Returns whether the key was present.
-  fn remove(&mut self, store: &mut Store, key: &[u8]) -> bool;
+  /// Returns the content hash of the whole tree, or `None` if it is empty. Trees with the same
+  /// policy have equal root hashes iff they contain the same key-value pairs.
+  fn root_hash(&self, store: &mut Store) -> paging::Result<Option<Box<[u8]>>, Store>;
 
-  /// Returns a [`Cursor`] pointing at the gap after the greatest key smaller than the given bound.
-  fn upper_bound(&self, store: &mut Store, bound: ops::Bound<&[u8]>) -> Self::Cursor;
+  /// Returns the content hash of the key-value pairs whose keys fall into the given range, or
+  /// `None` if there are no such pairs. This is equal to the root hash of a tree containing only
+  /// these pairs, so it can be compared across trees regardless of the keys outside the range.
+  fn range_hash<'a>(
+    &self,
+    store: &mut Store,
+    range: impl ops::RangeBounds<&'a [u8]>,
+  ) -> paging::Result<Option<Box<[u8]>>, Store>;
 
-  /// Returns a [`Cursor`] pointing at the gap before the smallest key greater than the given bound.
-  fn lower_bound(&self, store: &mut Store, bound: ops::Bound<&[u8]>) -> Self::Cursor;
+  /// Returns `k` distinct key-value pairs drawn uniformly at random, ordered by key. If the tree
+  /// contains at most `k` pairs, all of them are returned.
+  fn sample(
+    &self,
+    store: &mut Store,
+    rng: &mut impl rand::Rng,
+    k: usize,
+  ) -> paging::Result<Vec<map::OwnedElement>, Store>;
 
-  // TODO: diffing
+  /// Returns a Bernoulli sample of the key-value pairs whose keys fall into the given range, ordered
+  /// by key: each pair is included independently with the given probability.
+  ///
+  /// # Panics
+  ///
+  /// Panics if `probability` is not between `0` and `1`.
+  fn sample_range<'a>(
+    &self,
+    store: &mut Store,
+    rng: &mut impl rand::Rng,
+    range: impl ops::RangeBounds<&'a [u8]>,
+    probability: f64,
+  ) -> paging::Result<Vec<map::OwnedElement>, Store>;
 }
 
-/// # Prolly tree cursor interface
+/// # Mutation
 ///
-/// A [`Cursor`] is like an iterator, except that it can freely seek back-and-forth.
-///
-/// Cursors always point to a gap between two elements in the map, and can operate on the two
-/// immediately adjacent elements.
-pub trait Cursor<Store: paging::Store> {
-  /// Advances the cursor to the next gap, returning the key and value of the element that it moved
-  /// over. If the cursor is already at the end of the map then `None` is returned and the cursor is
-  /// not moved.
-  fn next(&mut self, store: &mut Store) -> Option<(&[u8], &[u8])>;
-
-  /// Advances the cursor to the previous gap, returning the key and value of the element that it
-  /// moved over. If the cursor is already at the start of the map then `None` is returned and the
-  /// cursor is not moved.
-  fn prev(&mut self, store: &mut Store) -> Option<(&[u8], &[u8])>;
+/// A change to a single key, as part of a batch passed to [`Tree::apply`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mutation<'a> {
+  /// Inserts or updates the value of a key.
+  Put(&'a [u8], &'a [u8]),
+  /// Removes a key.
+  Delete(&'a [u8]),
+}
 
-  /// Returns a reference to the key and value of the next element without moving the cursor.
-  /// If the cursor is at the end of the map then `None` is returned.
-  fn peek_next(&mut self, store: &mut Store) -> Option<(&[u8], &[u8])>;
+impl Mutation<'_> {
+  /// Returns the key which this mutation applies to.
+  pub fn key(&self) -> &[u8] {
+    match self {
+      Mutation::Put(key, _) | Mutation::Delete(key) => key,
+    }
+  }
+}
 
-  /// Returns a reference to the key and value of the previous element without moving the cursor.
-  /// If the cursor is at the start of the map then `None` is returned.
-  fn peek_prev(&mut self, store: &mut Store) -> Option<(&[u8], &[u8])>;
+/// # Borrowed value
+///
+/// A value returned by [`Tree::get_ref`]. It borrows the store, so that the page containing it
+/// cannot be evicted from the buffer pool or modified until the value is dropped.
+pub enum ValueRef<'a, Store: paging::Store> {
+  /// A value stored in a leaf page, borrowed directly from the buffer pool.
+  Inline(&'a [u8]),
+  /// A value stored in overflow pages, which can be read incrementally.
+  Overflow(paging::OverflowReader<'a, Store>),
 }
 
 /// # Prolly tree policy interface
@@ -85,9 +152,49 @@ pub trait Policy {
   ///
   /// This will be called on either the values in leaf nodes, or the `(key, hash)` pairs in
   /// internal nodes.
+  ///
+  /// Hashes are stored next to child pointers, so they should be short compared to the page size
+  /// (at most 1/32 of it). Modifications producing longer hashes fail with
+  /// [`paging::Error::DigestTooLarge`].
   fn content_hash(&self, content: &[u8]) -> Box<[u8]>;
 }
 
+/// # Prolly tree aggregate interface
+///
+/// An aggregate specifies a monoid over sequences of key-value pairs, such as the sum, minimum or
+/// maximum of some field of the values. Summaries of whole subtrees are kept in internal nodes, so
+/// that [`Tree::aggregate`] only needs to visit the two paths to the ends of the range.
+///
+/// Summaries are stored next to child pointers, so they should be short compared to the page size
+/// (at most 1/32 of it). Modifications producing longer summaries fail with
+/// [`paging::Error::DigestTooLarge`].
+pub trait Aggregate {
+  /// Returns the summary of a single key-value pair.
+  fn summarize(&self, key: &[u8], value: &[u8]) -> Box<[u8]>;
+
+  /// Returns the summary of the concatenation of two sequences, given their summaries. This must be
+  /// associative, with [`Aggregate::empty`] as the identity.
+  fn combine(&self, left: &[u8], right: &[u8]) -> Box<[u8]>;
+
+  /// Returns the summary of the empty sequence.
+  fn empty(&self) -> Box<[u8]>;
+}
+
+/// The trivial aggregate, whose summaries are always empty.
+impl Aggregate for () {
+  fn summarize(&self, _key: &[u8], _value: &[u8]) -> Box<[u8]> {
+    Box::new([])
+  }
+
+  fn combine(&self, _left: &[u8], _right: &[u8]) -> Box<[u8]> {
+    Box::new([])
+  }
+
+  fn empty(&self) -> Box<[u8]> {
+    Box::new([])
+  }
+}
+
 /// # Standard implementation for [`Tree`]
 ///
 /// ## Implementation notes
@@ -98,22 +205,2510 @@ pub trait Policy {
 ///
 /// - All internal nodes have the same key as its leftmost child.
 ///
-/// - For the `i`-th entry in a node with height `height` and child key list `keys`,
-///   `boundary_decision(height, keys[i], i + 1) == true` iff `i + 1 == size`.
-///   
+/// - For the `i`-th entry in a node with height `height` and child key list `keys`, which is not
+///   the last node in its layer, `boundary_decision(height, keys[i], i + 1) == true` iff
+///   `i + 1 == size`. In order to guarantee that nodes fit into pages, a node is also split after
+///   the `i`-th entry if the first `i + 1` entries occupy so much space that another entry of the
+///   maximum size might not fit.
+///
 ///   - Note that the first three invariants uniquely determine the tree's structure from a list of
 ///     keys: imagine constructing the tree layer-by-layer starting from the leaves. In the first
 ///     layer, traverse the list of keys, adding keys to the current node until `boundary_decision`
 ///     returns `true`, at which point a new node is started at the next key. Once all keys are
 ///     grouped into nodes, use the first key in each group as the node's key. Repeat this process
 ///     until only one node remains in a layer.
-pub struct BasicTree<Store: paging::Store, Policy: self::Policy> {
-  _store: std::marker::PhantomData<Store>,
-  _policy: std::marker::PhantomData<Policy>,
-  // TODO: implement
+///
+/// Nodes are never modified in place: modifying methods write the affected nodes into newly
+/// allocated pages. This means that a root page ID obtained through [`BasicTree::root`] keeps
+/// referring to an unchanged version of the tree, until [`BasicTree::release`] is called to
+/// deallocate pages which are no longer referenced by the current version. Previous versions can
+/// be kept readable across calls to [`BasicTree::release`] by pinning them (see [`BasicTree::pin`]).
+///
+/// Modifications are applied one layer at a time. In each layer, the affected nodes are re-chunked
+/// starting from the first affected node, until a node boundary coincides with an existing one; the
+/// replaced and newly created nodes then become deletions and insertions in the layer above.
+///
+/// Range removals only re-chunk the two nodes containing the ends of the range in each layer. The
+/// nodes in between are skipped, and removed from the layer above along with their subtrees, whose
+/// sizes are known from the entry counts stored in internal nodes.
+///
+/// Internal nodes also store the summary of each child under the tree's [`Aggregate`], and leaf
+/// cells store the summaries of values moved into overflow pages, so that summaries of rewritten
+/// nodes never require reading overflow pages.
+pub struct BasicTree<Store: paging::Store, Policy: self::Policy, Aggregate: self::Aggregate = ()> {
+  policy: Policy,
+  aggregate: Aggregate,
+  root: Option<u64>,
+  garbage: Vec<Garbage>,
+  pins: sync::Arc<()>,
+  _store: marker::PhantomData<Store>,
+}
+
+/// # Pinned versions
+///
+/// A guard returned by [`BasicTree::pin`]. While it is alive, [`BasicTree::release`] keeps all
+/// replaced pages allocated, so that root page IDs of previous versions stay valid.
+pub struct Pin {
+  _pins: sync::Arc<()>,
+}
+
+/// Pages which are no longer referenced by the current version of a [`BasicTree`].
+enum Garbage {
+  /// A node page.
+  Page(u64),
+  /// The first page of an overflow chain.
+  Overflow(u64),
+  /// The root page of a subtree, including all pages reachable from it.
+  Subtree(u64),
+}
+
+impl<Store: paging::Store, Policy: self::Policy, Aggregate: self::Aggregate + Default>
+  BasicTree<Store, Policy, Aggregate>
+{
+  /// Creates an empty tree.
+  pub fn new(policy: Policy) -> Self {
+    Self::open(policy, None)
+  }
+
+  /// Opens an existing tree given its root page ID, or `None` for an empty tree.
+  pub fn open(policy: Policy, root: Option<u64>) -> Self {
+    Self::open_with(policy, Aggregate::default(), root)
+  }
+}
+
+impl<Store: paging::Store, Policy: self::Policy, Aggregate: self::Aggregate>
+  BasicTree<Store, Policy, Aggregate>
+{
+  /// Opens an existing tree with the given aggregate. The aggregate must be the same one which the
+  /// tree was created with.
+  pub fn open_with(policy: Policy, aggregate: Aggregate, root: Option<u64>) -> Self {
+    let pins = sync::Arc::new(());
+    Self { policy, aggregate, root, garbage: Vec::new(), pins, _store: marker::PhantomData }
+  }
+
+  /// Returns the root page ID of the current version of the tree, or `None` if it is empty.
+  pub fn root(&self) -> Option<u64> {
+    self.root
+  }
+
+  /// Returns the policy of the tree.
+  pub fn policy(&self) -> &Policy {
+    &self.policy
+  }
+
+  /// Deallocates all pages which were replaced by modifications so far, unless the tree is pinned.
+  ///
+  /// Root page IDs of previous versions obtained through [`BasicTree::root`] become invalid.
+  pub fn release(&mut self, store: &mut Store) -> paging::Result<(), Store> {
+    if sync::Arc::strong_count(&self.pins) > 1 {
+      return Ok(());
+    }
+    free(store, &mut self.garbage)
+  }
+
+  /// Pins all versions of the tree, keeping [`BasicTree::release`] from deallocating any pages
+  /// until the returned guard is dropped.
+  pub fn pin(&self) -> Pin {
+    Pin { _pins: self.pins.clone() }
+  }
+
+  /// Creates a leaf item for `value`, moving it into overflow pages if it is too large.
+  fn make_item(&self, store: &mut Store, key: &[u8], value: &[u8]) -> paging::Result<Item, Store> {
+    paging::check_key(store, key)?;
+    let page_size = store.page_size();
+    let item = Item::Inline(value.into());
+    if Entry::cell_size_of(key, &item) + 2 <= max_cell_size(page_size) {
+      return Ok(item);
+    }
+    let hash = check_digest(store, self.policy.content_hash(value))?;
+    let summary = check_digest(store, self.aggregate.summarize(key, value))?;
+    let page_id = paging::write_overflow(store, value)?;
+    Ok(Item::Overflow { page_id, len: value.len() as u64, hash, summary })
+  }
+
+  /// Removes the keys in `range` (if any), then applies a list of mutations (strictly ordered by
+  /// key) to the version of the tree with the given root. The tree itself is not changed; see
+  /// [`BasicTree::update`].
+  fn splice<S: paging::Store>(
+    &self,
+    store: &mut S,
+    root: Option<u64>,
+    mutations: Vec<(Box<[u8]>, Option<Item>)>,
+    range: Option<&Range>,
+  ) -> paging::Result<Splice, S> {
+    let old_root = match root {
+      Some(page_id) => Some((page_id, BasicNode::read(store, page_id)?.height)),
+      None => None,
+    };
+    let mut splice = Splice { root: None, garbage: Vec::new(), found: 0, removed: 0 };
+    let mut mutations = mutations;
+    let mut height = 0;
+    let mut new_root = loop {
+      let level = self.splice_level(store, old_root, height, &mutations, range, &mut splice)?;
+      // Nodes skipped by a range removal are only removed in the layers above.
+      let skipped = range.is_some() && old_root.is_some_and(|(_, h)| height < h);
+      if level.parent.is_empty() && !skipped {
+        break root;
+      }
+      if old_root.is_none_or(|(_, root_height)| height >= root_height) {
+        // The new content of this layer is known in full.
+        match level.emitted.as_slice() {
+          [] => break None,
+          [entry] => break Some(entry.child_page_id()),
+          _ => {}
+        }
+      }
+      mutations = level.parent;
+      height += 1;
+    };
+    // Remove root nodes with a single child. These may belong to previous versions.
+    while let Some(page_id) = new_root {
+      let node = BasicNode::read(store, page_id)?;
+      if node.height == 0 || node.entries.len() > 1 {
+        break;
+      }
+      splice.garbage.push(Garbage::Page(page_id));
+      new_root = Some(node.entries[0].child_page_id());
+    }
+    splice.root = new_root;
+    Ok(splice)
+  }
+
+  /// Makes the result of a [`BasicTree::splice`] on the current version the current version.
+  fn update(&mut self, mut splice: Splice) -> Splice {
+    self.root = splice.root;
+    self.garbage.append(&mut splice.garbage);
+    splice
+  }
+
+  /// Applies a list of mutations to a single layer of the tree. See [`BasicTree::splice`].
+  fn splice_level<S: paging::Store>(
+    &self,
+    store: &mut S,
+    old_root: Option<(u64, u8)>,
+    height: u8,
+    mutations: &[(Box<[u8]>, Option<Item>)],
+    range: Option<&Range>,
+    splice: &mut Splice,
+  ) -> paging::Result<Level, S> {
+    let page_size = store.page_size();
+    let mut level = Level { parent: Vec::new(), emitted: Vec::new() };
+    let root_page_id = match old_root {
+      Some((page_id, root_height)) if height <= root_height => page_id,
+      _ => {
+        // This layer is above the old root, so it only contains the new entries.
+        let mut chunker = Chunker::new(&self.policy, height, page_size);
+        for (key, item) in mutations {
+          if let Some(item) = item {
+            chunker.push(Entry { key: key.clone(), item: item.clone() });
+          }
+        }
+        chunker.finish();
+        level.emitted = self.write_nodes(store, chunker.nodes)?;
+        level.parent =
+          level.emitted.iter().map(|entry| (entry.key.clone(), Some(entry.item.clone()))).collect();
+        return Ok(level);
+      }
+    };
+    let mut parent = collections::BTreeMap::new();
+    let mut i = 0;
+    let mut range_done = range.is_none();
+    while i < mutations.len() || !range_done {
+      let start = match range {
+        Some(range) if !range_done && i == mutations.len() => range.start_key(),
+        Some(range) if !range_done && *range.start_key() <= *mutations[i].0 => range.start_key(),
+        _ => &mutations[i].0,
+      };
+      let mut path = Path::locate(store, root_page_id, height, start)?;
+      let mut chunker = Chunker::new(&self.policy, height, page_size);
+      let mut consumed = Vec::new();
+      let mut changed = false;
+      loop {
+        let (page_id, node) = path.target();
+        consumed.push((node.key().into(), page_id));
+        for entry in &node.entries {
+          while i < mutations.len() && mutations[i].0 < entry.key {
+            if let Some(item) = &mutations[i].1 {
+              chunker.push(Entry { key: mutations[i].0.clone(), item: item.clone() });
+              changed = true;
+            }
+            i += 1;
+          }
+          if i < mutations.len() && mutations[i].0 == entry.key {
+            if height == 0 {
+              splice.found += 1;
+            }
+            if mutations[i].1.as_ref() != Some(&entry.item) {
+              changed = true;
+              if let Item::Overflow { page_id, .. } = entry.item {
+                splice.garbage.push(Garbage::Overflow(page_id));
+              }
+            }
+            if let Some(item) = &mutations[i].1 {
+              chunker.push(Entry { key: entry.key.clone(), item: item.clone() });
+            }
+            i += 1;
+          } else if range.is_some_and(|range| range.contains(&entry.key)) {
+            // In layers above the leaves, these are the roots of skipped subtrees.
+            changed = true;
+            match entry.item {
+              Item::Inline(_) => splice.removed += 1,
+              Item::Overflow { page_id, .. } => {
+                splice.removed += 1;
+                splice.garbage.push(Garbage::Overflow(page_id));
+              }
+              Item::Child { page_id, count, .. } => {
+                splice.removed += count;
+                splice.garbage.push(Garbage::Subtree(page_id));
+              }
+            }
+          } else {
+            chunker.push(entry.clone());
+          }
+        }
+        // Skip the nodes lying entirely inside the range.
+        let last = node.last_key();
+        let skip = match range {
+          Some(range) if !range_done && range.starts_before(last) && range.ends_after(last) => {
+            let target = match &range.end {
+              ops::Bound::Included(key) | ops::Bound::Excluded(key) => {
+                Path::locate(store, root_page_id, height, key)?
+              }
+              ops::Bound::Unbounded => Path::locate_last(store, root_page_id, height)?,
+            };
+            (target.target().0 != page_id).then_some(target)
+          }
+          _ => None,
+        };
+        let next = match skip {
+          Some(target) => {
+            path = target;
+            true
+          }
+          None => path.next(store)?,
+        };
+        let limit = next.then(|| path.target().1.key());
+        while i < mutations.len() && limit.is_none_or(|limit| *mutations[i].0 < *limit) {
+          if let Some(item) = &mutations[i].1 {
+            chunker.push(Entry { key: mutations[i].0.clone(), item: item.clone() });
+            changed = true;
+          }
+          i += 1;
+        }
+        if !next {
+          chunker.finish();
+          range_done = true;
+          break;
+        }
+        // Stop at a common boundary, unless the next mutation or the range falls into the next
+        // node.
+        let (_, node) = path.target();
+        let in_range = !range_done && range.is_some_and(|range| range.contains(node.key()));
+        if chunker.is_synced()
+          && (i == mutations.len() || *mutations[i].0 > *node.last_key())
+          && !in_range
+        {
+          // The rest of the range is either empty or beyond the next node.
+          range_done |= range.is_some_and(|range| range.is_after_start(node.key()));
+          break;
+        }
+      }
+      if changed {
+        for (key, page_id) in consumed {
+          parent.insert(key, None);
+          splice.garbage.push(Garbage::Page(page_id));
+        }
+        for entry in self.write_nodes(store, chunker.nodes)? {
+          parent.insert(entry.key.clone(), Some(entry.item.clone()));
+          level.emitted.push(entry);
+        }
+      }
+    }
+    level.parent = parent.into_iter().collect();
+    Ok(level)
+  }
+
+  /// Writes the given nodes into newly allocated pages, returning the entries pointing to them.
+  fn write_nodes<S: paging::Store>(
+    &self,
+    store: &mut S,
+    nodes: Vec<BasicNode>,
+  ) -> paging::Result<Vec<Entry>, S> {
+    let mut res = Vec::with_capacity(nodes.len());
+    for node in nodes {
+      let hash = check_digest(store, node.hash(&self.policy))?;
+      let summary = check_digest(store, node.summary(&self.aggregate))?;
+      let page_id = store.allocate()?;
+      node.write(store, page_id)?;
+      res.push(Entry {
+        key: node.key().into(),
+        item: Item::Child { page_id, count: node.count(), hash, summary },
+      });
+    }
+    Ok(res)
+  }
+
+  /// Returns the number of key-value pairs whose keys are smaller than `key` (or not greater than
+  /// `key`, if `inclusive` is `true`).
+  fn rank(&self, store: &mut Store, key: &[u8], inclusive: bool) -> paging::Result<u64, Store> {
+    let Some(root) = self.root else { return Ok(0) };
+    let mut node = BasicNode::read(store, root)?;
+    let mut res = 0;
+    while node.height > 0 {
+      res += node.entries[..node.child_index(key)].iter().map(Entry::count).sum::<u64>();
+      node = node.descend(store, key)?;
+    }
+    let index = match inclusive {
+      true => node.entries.partition_point(|entry| *entry.key <= *key),
+      false => node.entries.partition_point(|entry| *entry.key < *key),
+    };
+    Ok(res + index as u64)
+  }
+
+  /// Appends the key-value pairs with the given ranks (strictly increasing) to `res`. Ranks are
+  /// relative to the start of the tree, and `offset` is the rank of the first pair in the subtree
+  /// rooted at the given page.
+  fn select(
+    &self,
+    store: &mut Store,
+    page_id: u64,
+    parent_height: Option<u8>,
+    offset: u64,
+    ranks: &[u64],
+    res: &mut Vec<map::OwnedElement>,
+  ) -> paging::Result<(), Store> {
+    let node = match parent_height {
+      Some(height) => BasicNode::read_child(store, page_id, height)?,
+      None => BasicNode::read(store, page_id)?,
+    };
+    let corrupt = || paging::Error::Corrupt { page_id };
+    if node.height == 0 {
+      for &rank in ranks {
+        let entry = usize::try_from(rank - offset).ok().and_then(|i| node.entries.get(i));
+        let entry = entry.ok_or_else(corrupt)?;
+        res.push((entry.key.clone(), resolve(store, &entry.item)?.into()));
+      }
+      return Ok(());
+    }
+    let (mut offset, mut ranks) = (offset, ranks);
+    for entry in &node.entries {
+      let count = entry.count();
+      let n = ranks.partition_point(|&rank| rank < offset + count);
+      if n > 0 {
+        let child = entry.child_page_id();
+        self.select(store, child, Some(node.height), offset, &ranks[..n], res)?;
+      }
+      (offset, ranks) = (offset + count, &ranks[n..]);
+    }
+    // Fails if the subtree counts are inconsistent.
+    ranks.is_empty().then_some(()).ok_or_else(corrupt)
+  }
+
+  /// Returns the summary of the key-value pairs in the given range, within the subtree rooted at
+  /// the given page, whose keys are all smaller than `upper` (if given).
+  fn summarize_range(
+    &self,
+    store: &mut Store,
+    page_id: u64,
+    parent_height: Option<u8>,
+    upper: Option<&[u8]>,
+    range: &Range,
+  ) -> paging::Result<Box<[u8]>, Store> {
+    let node = match parent_height {
+      Some(height) => BasicNode::read_child(store, page_id, height)?,
+      None => BasicNode::read(store, page_id)?,
+    };
+    let mut res = self.aggregate.empty();
+    for (i, entry) in node.entries.iter().enumerate() {
+      let next = node.entries.get(i + 1).map(|entry| &*entry.key).or(upper);
+      let summary = if node.height == 0 {
+        if !range.contains(&entry.key) {
+          continue;
+        }
+        entry.summary(&self.aggregate)
+      } else if range.covers(&entry.key, next) {
+        entry.summary(&self.aggregate)
+      } else if range.overlaps(&entry.key, next) {
+        let child = entry.child_page_id();
+        self.summarize_range(store, child, Some(node.height), next, range)?
+      } else {
+        continue;
+      };
+      res = self.aggregate.combine(&res, &summary);
+    }
+    Ok(res)
+  }
+}
+
+impl<Store: paging::Store, Policy: self::Policy, Aggregate: self::Aggregate> map::Map<Store>
+  for BasicTree<Store, Policy, Aggregate>
+{
+  type Cursor = BasicCursor;
+
+  fn get(&self, store: &mut Store, key: &[u8]) -> paging::Result<Option<Box<[u8]>>, Store> {
+    let Some(root) = self.root else { return Ok(None) };
+    match BasicNode::lookup(store, root, key)? {
+      Some(item) => Ok(Some(resolve(store, &item)?.into())),
+      None => Ok(None),
+    }
+  }
+
+  fn insert(&mut self, store: &mut Store, key: &[u8], value: &[u8]) -> paging::Result<bool, Store> {
+    let item = self.make_item(store, key, value)?;
+    let splice = self.splice(store, self.root, vec![(key.into(), Some(item))], None)?;
+    Ok(self.update(splice).found > 0)
+  }
+
+  fn remove(&mut self, store: &mut Store, key: &[u8]) -> paging::Result<bool, Store> {
+    let splice = self.splice(store, self.root, vec![(key.into(), None)], None)?;
+    Ok(self.update(splice).found > 0)
+  }
+
+  fn upper_bound(
+    &self,
+    store: &mut Store,
+    bound: ops::Bound<&[u8]>,
+  ) -> paging::Result<Self::Cursor, Store> {
+    BasicCursor::seek(store, self.root, |node| match bound {
+      ops::Bound::Included(key) if node.height == 0 => {
+        node.entries.partition_point(|e| *e.key <= *key)
+      }
+      ops::Bound::Excluded(key) if node.height == 0 => {
+        node.entries.partition_point(|e| *e.key < *key)
+      }
+      ops::Bound::Included(key) | ops::Bound::Excluded(key) => node.child_index(key),
+      ops::Bound::Unbounded if node.height == 0 => node.entries.len(),
+      ops::Bound::Unbounded => node.entries.len() - 1,
+    })
+  }
+
+  fn lower_bound(
+    &self,
+    store: &mut Store,
+    bound: ops::Bound<&[u8]>,
+  ) -> paging::Result<Self::Cursor, Store> {
+    BasicCursor::seek(store, self.root, |node| match bound {
+      ops::Bound::Included(key) if node.height == 0 => {
+        node.entries.partition_point(|e| *e.key < *key)
+      }
+      ops::Bound::Excluded(key) if node.height == 0 => {
+        node.entries.partition_point(|e| *e.key <= *key)
+      }
+      ops::Bound::Included(key) | ops::Bound::Excluded(key) => node.child_index(key),
+      ops::Bound::Unbounded => 0,
+    })
+  }
 }
 
-pub struct BasicNode<Store: paging::Store> {
-  _store: std::marker::PhantomData<Store>,
-  // TODO: implement
+impl<Store: paging::Store, Policy: self::Policy, Aggregate: self::Aggregate> Tree<Store>
+  for BasicTree<Store, Policy, Aggregate>
+{
+  fn get_ref<'a>(
+    &self,
+    store: &'a mut Store,
+    key: &[u8],
+  ) -> paging::Result<Option<ValueRef<'a, Store>>, Store> {
+    let Some(mut page_id) = self.root else { return Ok(None) };
+    let mut parent_height = None;
+    // Descend without decoding whole nodes, then return the value range within the leaf page.
+    let (start, tag) = loop {
+      let current = page_id;
+      let corrupt = || paging::Error::Corrupt { page_id: current };
+      let page = store.get(page_id)?;
+      let (height, cells) = BasicNode::decode_cells(page).ok_or_else(corrupt)?;
+      if parent_height.is_some_and(|parent_height| parent_height != height + 1) {
+        return Err(corrupt());
+      }
+      let i = cells.partition_point(|cell| Entry::split_cell(cell).is_some_and(|(k, _)| k <= key));
+      if height > 0 {
+        let (_, mut rest) = Entry::split_cell(cells[i.saturating_sub(1)]).ok_or_else(corrupt)?;
+        page_id = paging::take_u64(&mut rest).ok_or_else(corrupt)?;
+        parent_height = Some(height);
+        continue;
+      }
+      let Some(i) = i.checked_sub(1) else { return Ok(None) };
+      let (k, mut rest) = Entry::split_cell(cells[i]).ok_or_else(corrupt)?;
+      if k != key {
+        return Ok(None);
+      }
+      let tag = paging::take_varint(&mut rest).ok_or_else(corrupt)?;
+      break (rest.as_ptr() as usize - page.as_ptr() as usize, tag);
+    };
+    let corrupt = || paging::Error::Corrupt { page_id };
+    if tag % 2 == 0 {
+      let page = store.get(page_id)?;
+      let end = usize::try_from(tag / 2).ok().and_then(|len| start.checked_add(len));
+      let value = end.and_then(|end| page.get(start..end)).ok_or_else(corrupt)?;
+      Ok(Some(ValueRef::Inline(value)))
+    } else {
+      let mut rest = store.get(page_id)?.get(start..).ok_or_else(corrupt)?;
+      let overflow = paging::take_u64(&mut rest).ok_or_else(corrupt)?;
+      Ok(Some(ValueRef::Overflow(paging::OverflowReader::new(store, overflow, tag / 2))))
+    }
+  }
+
+  fn apply<'a>(
+    &mut self,
+    store: &mut Store,
+    mutations: impl IntoIterator<Item = Mutation<'a>>,
+  ) -> paging::Result<usize, Store> {
+    let mutations = mutations.into_iter().collect::<Vec<_>>();
+    // Check all keys before writing overflow pages, which would be lost if a later key failed.
+    for mutation in &mutations {
+      paging::check_key(store, mutation.key())?;
+    }
+    let mut list: Vec<(Box<[u8]>, Option<Item>)> = Vec::new();
+    for mutation in mutations {
+      if let Some((last, _)) = list.last() {
+        assert!(**last < *mutation.key(), "mutation keys must be strictly increasing");
+      }
+      let item = match mutation {
+        Mutation::Put(key, value) => match self.make_item(store, key, value) {
+          Ok(item) => Some(item),
+          Err(error) => {
+            // Hashes and summaries of large values can only be checked here.
+            for (_, item) in list {
+              if let Some(Item::Overflow { page_id, .. }) = item {
+                paging::free_overflow(store, page_id)?;
+              }
+            }
+            return Err(error);
+          }
+        },
+        Mutation::Delete(_) => None,
+      };
+      list.push((mutation.key().into(), item));
+    }
+    if list.is_empty() {
+      return Ok(0);
+    }
+    let splice = self.splice(store, self.root, list, None)?;
+    Ok(self.update(splice).found)
+  }
+
+  fn remove_range<'a>(
+    &mut self,
+    store: &mut Store,
+    range: impl ops::RangeBounds<&'a [u8]>,
+  ) -> paging::Result<u64, Store> {
+    let range = Range::new(range);
+    if self.root.is_none() || range.is_empty() {
+      return Ok(0);
+    }
+    let splice = self.splice(store, self.root, Vec::new(), Some(&range))?;
+    Ok(self.update(splice).removed)
+  }
+
+  fn aggregate<'a>(
+    &self,
+    store: &mut Store,
+    range: impl ops::RangeBounds<&'a [u8]>,
+  ) -> paging::Result<Box<[u8]>, Store> {
+    let range = Range::new(range);
+    match self.root {
+      Some(root) if !range.is_empty() => self.summarize_range(store, root, None, None, &range),
+      _ => Ok(self.aggregate.empty()),
+    }
+  }
+
+  fn root_hash(&self, store: &mut Store) -> paging::Result<Option<Box<[u8]>>, Store> {
+    match self.root {
+      Some(root) => Ok(Some(BasicNode::read(store, root)?.hash(&self.policy))),
+      None => Ok(None),
+    }
+  }
+
+  fn range_hash<'a>(
+    &self,
+    store: &mut Store,
+    range: impl ops::RangeBounds<&'a [u8]>,
+  ) -> paging::Result<Option<Box<[u8]>>, Store> {
+    let range = Range::new(range);
+    if range.is_empty() {
+      return Ok(None);
+    }
+    // Remove the keys on both sides of the range without persisting the changes. This only
+    // re-chunks the paths to the ends of the range, reusing the hashes of all other nodes.
+    let mut scratch = paging::ScratchStore::new(store);
+    let mut root = self.root;
+    for outside in range.complement() {
+      if root.is_some() {
+        root = self.splice(&mut scratch, root, Vec::new(), Some(&outside))?.root;
+      }
+    }
+    match root {
+      Some(root) => Ok(Some(BasicNode::read(&mut scratch, root)?.hash(&self.policy))),
+      None => Ok(None),
+    }
+  }
+
+  fn sample(
+    &self,
+    store: &mut Store,
+    rng: &mut impl rand::Rng,
+    k: usize,
+  ) -> paging::Result<Vec<map::OwnedElement>, Store> {
+    let Some(root) = self.root else { return Ok(Vec::new()) };
+    let len = BasicNode::read(store, root)?.count();
+    let len = usize::try_from(len).map_err(|_| paging::Error::Corrupt { page_id: root })?;
+    let mut ranks = rand::seq::index::sample(rng, len, k.min(len)).into_vec();
+    ranks.sort_unstable();
+    let ranks = ranks.into_iter().map(|rank| rank as u64).collect::<Vec<_>>();
+    let mut res = Vec::with_capacity(ranks.len());
+    self.select(store, root, None, 0, &ranks, &mut res)?;
+    Ok(res)
+  }
+
+  fn sample_range<'a>(
+    &self,
+    store: &mut Store,
+    rng: &mut impl rand::Rng,
+    range: impl ops::RangeBounds<&'a [u8]>,
+    probability: f64,
+  ) -> paging::Result<Vec<map::OwnedElement>, Store> {
+    assert!((0.0..=1.0).contains(&probability), "probability must be between 0 and 1");
+    let range = Range::new(range);
+    let Some(root) = self.root else { return Ok(Vec::new()) };
+    if range.is_empty() || probability == 0.0 {
+      return Ok(Vec::new());
+    }
+    let start = match &range.start {
+      ops::Bound::Included(key) => self.rank(store, key, false)?,
+      ops::Bound::Excluded(key) => self.rank(store, key, true)?,
+      ops::Bound::Unbounded => 0,
+    };
+    let end = match &range.end {
+      ops::Bound::Included(key) => self.rank(store, key, true)?,
+      ops::Bound::Excluded(key) => self.rank(store, key, false)?,
+      ops::Bound::Unbounded => BasicNode::read(store, root)?.count(),
+    };
+    // Skip over the pairs which are not included, whose numbers follow a geometric distribution.
+    let mut ranks = Vec::new();
+    let mut rank = start;
+    loop {
+      let uniform = rng.gen::<f64>();
+      let skip = if probability < 1.0 {
+        ((1.0 - uniform).ln() / (1.0 - probability).ln()) as u64
+      } else {
+        0
+      };
+      rank = rank.saturating_add(skip);
+      if rank >= end {
+        break;
+      }
+      ranks.push(rank);
+      rank += 1;
+    }
+    let mut res = Vec::with_capacity(ranks.len());
+    self.select(store, root, None, 0, &ranks, &mut res)?;
+    Ok(res)
+  }
+}
+
+/// # Standard implementation for [`map::Cursor`]
+///
+/// The cursor holds copies of all nodes on the path from the root to the current leaf, so that
+/// moving to an adjacent leaf only reads the nodes which are not shared between the two paths.
+pub struct BasicCursor {
+  /// Nodes on the current path, along with the index of the next child (in internal nodes) or the
+  /// index of the element after the gap (in the leaf).
+  path: Vec<(BasicNode, usize)>,
+  /// Buffer for values stored in overflow pages.
+  buffer: Vec<u8>,
+}
+
+impl BasicCursor {
+  /// Creates a cursor by descending from the root, using `index` to choose the child index in each
+  /// internal node and the gap index in the leaf.
+  fn seek<Store: paging::Store>(
+    store: &mut Store,
+    root: Option<u64>,
+    index: impl Fn(&BasicNode) -> usize,
+  ) -> paging::Result<Self, Store> {
+    let mut path = Vec::new();
+    if let Some(page_id) = root {
+      let mut node = BasicNode::read(store, page_id)?;
+      while node.height > 0 {
+        let i = index(&node);
+        let child = BasicNode::read_child(store, node.entries[i].child_page_id(), node.height)?;
+        path.push((node, i));
+        node = child;
+      }
+      let i = index(&node);
+      path.push((node, i));
+    }
+    Ok(Self { path, buffer: Vec::new() })
+  }
+
+  /// Moves the cursor to the start of the next leaf (if `forward` is `true`) or to the end of the
+  /// previous leaf (otherwise). Returns `false` if there is no such leaf.
+  fn step<Store: paging::Store>(
+    &mut self,
+    store: &mut Store,
+    forward: bool,
+  ) -> paging::Result<bool, Store> {
+    let leaf = self.path.len() - 1;
+    let Some(level) = (0..leaf).rev().find(|&level| {
+      let (node, i) = &self.path[level];
+      if forward {
+        i + 1 < node.entries.len()
+      } else {
+        *i > 0
+      }
+    }) else {
+      return Ok(false);
+    };
+    // Read the new nodes first, so that the cursor is unchanged if an error occurs.
+    let (node, i) = &self.path[level];
+    let i = if forward { i + 1 } else { i - 1 };
+    let mut nodes = Vec::new();
+    let mut parent = (node.height, node.entries[i].child_page_id());
+    while parent.0 > 0 {
+      let node = BasicNode::read_child(store, parent.1, parent.0)?;
+      let j = if forward || node.height == 0 { 0 } else { node.entries.len() - 1 };
+      if node.height > 0 {
+        parent = (node.height, node.entries[j].child_page_id());
+      } else {
+        parent = (0, 0);
+      }
+      nodes.push(node);
+    }
+    self.path.truncate(level + 1);
+    self.path[level].1 = i;
+    for node in nodes {
+      let j = match (forward, node.height) {
+        (true, _) => 0,
+        (false, 0) => node.entries.len(),
+        (false, _) => node.entries.len() - 1,
+      };
+      self.path.push((node, j));
+    }
+    Ok(true)
+  }
+
+  /// Loads the value of the `i`-th element in the current leaf, if it is stored in overflow pages.
+  fn load<Store: paging::Store>(
+    &mut self,
+    store: &mut Store,
+    i: usize,
+  ) -> paging::Result<(), Store> {
+    let (leaf, _) = self.path.last().unwrap();
+    if let Item::Overflow { page_id, len, .. } = leaf.entries[i].item {
+      self.buffer = paging::read_overflow(store, page_id, len)?;
+    }
+    Ok(())
+  }
+
+  /// Returns the key and value of the `i`-th element in the current leaf, which must be loaded.
+  fn element(&self, i: usize) -> (&[u8], &[u8]) {
+    let (leaf, _) = self.path.last().unwrap();
+    let entry = &leaf.entries[i];
+    match &entry.item {
+      Item::Inline(value) => (&entry.key, value),
+      Item::Overflow { .. } => (&entry.key, &self.buffer),
+      Item::Child { .. } => unreachable!(),
+    }
+  }
+}
+
+impl<Store: paging::Store> map::Cursor<Store> for BasicCursor {
+  fn next(&mut self, store: &mut Store) -> paging::Result<Option<map::Element<'_>>, Store> {
+    let Some((leaf, i)) = self.path.last() else { return Ok(None) };
+    if *i == leaf.entries.len() && !self.step(store, true)? {
+      return Ok(None);
+    }
+    let i = self.path.last().unwrap().1;
+    self.load(store, i)?;
+    self.path.last_mut().unwrap().1 = i + 1;
+    Ok(Some(self.element(i)))
+  }
+
+  fn prev(&mut self, store: &mut Store) -> paging::Result<Option<map::Element<'_>>, Store> {
+    let Some((_, i)) = self.path.last() else { return Ok(None) };
+    if *i == 0 && !self.step(store, false)? {
+      return Ok(None);
+    }
+    let i = self.path.last().unwrap().1 - 1;
+    self.load(store, i)?;
+    self.path.last_mut().unwrap().1 = i;
+    Ok(Some(self.element(i)))
+  }
+
+  fn peek_next(&mut self, store: &mut Store) -> paging::Result<Option<map::Element<'_>>, Store> {
+    let Some((leaf, i)) = self.path.last() else { return Ok(None) };
+    if *i == leaf.entries.len() && !self.step(store, true)? {
+      return Ok(None);
+    }
+    let i = self.path.last().unwrap().1;
+    self.load(store, i)?;
+    Ok(Some(self.element(i)))
+  }
+
+  fn peek_prev(&mut self, store: &mut Store) -> paging::Result<Option<map::Element<'_>>, Store> {
+    let Some((_, i)) = self.path.last() else { return Ok(None) };
+    if *i == 0 && !self.step(store, false)? {
+      return Ok(None);
+    }
+    let i = self.path.last().unwrap().1 - 1;
+    self.load(store, i)?;
+    Ok(Some(self.element(i)))
+  }
+}
+
+/// # Change to a key
+///
+/// A commit which changed the value of a key, as reported by [`History`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change<Commit> {
+  /// The commit which made the change.
+  pub commit: Commit,
+  /// The value of the key after the commit, or `None` if the key was removed.
+  pub value: Option<Box<[u8]>>,
+}
+
+/// # Key history
+///
+/// Walks a sequence of commits from the newest to the oldest, yielding the commits which changed
+/// the value of a key. Each commit is given by an identifier of any type, along with the root page
+/// ID of its version of a [`BasicTree`] (or `None` if the tree was empty). The oldest commit is
+/// compared against an empty tree.
+///
+/// Adjacent versions are compared by descending along the paths to the key in both trees at once,
+/// stopping as soon as the child entries on both paths have equal hashes. Unchanged commits
+/// therefore usually cost reading a few nodes near the roots, or nothing at all if the roots are
+/// the same page.
+pub struct History<Commit, Commits: Iterator<Item = (Commit, Option<u64>)>> {
+  key: Box<[u8]>,
+  current: Option<(Commit, Option<u64>)>,
+  commits: iter::Peekable<Commits>,
+}
+
+impl<Commit, Commits: Iterator<Item = (Commit, Option<u64>)>> History<Commit, Commits> {
+  /// Creates a walk over the given commits, ordered from the newest to the oldest.
+  pub fn new(key: &[u8], commits: impl IntoIterator<IntoIter = Commits>) -> Self {
+    let mut commits = commits.into_iter().peekable();
+    Self { key: key.into(), current: commits.next(), commits }
+  }
+
+  /// Returns the next commit which changed the value of the key, or `None` if there are no more
+  /// such commits. If an error is returned, the walk is left unchanged.
+  pub fn next<Store: paging::Store>(
+    &mut self,
+    store: &mut Store,
+  ) -> paging::Result<Option<Change<Commit>>, Store> {
+    while let Some((_, root)) = self.current {
+      let parent = self.commits.peek().and_then(|(_, root)| *root);
+      let value = match Self::compare(store, root, parent, &self.key)? {
+        Some(Some(item)) => Some(Some(resolve(store, &item)?.into())),
+        Some(None) => Some(None),
+        None => None,
+      };
+      let (commit, _) = mem::replace(&mut self.current, self.commits.next()).unwrap();
+      if let Some(value) = value {
+        return Ok(Some(Change { commit, value }));
+      }
+    }
+    Ok(None)
+  }
+
+  /// Compares the values of a key in two versions of a tree. Returns the item in the `new` version
+  /// (or `None` if the key is absent from it) if they are different, or `None` otherwise.
+  fn compare<Store: paging::Store>(
+    store: &mut Store,
+    new: Option<u64>,
+    old: Option<u64>,
+    key: &[u8],
+  ) -> paging::Result<Option<Option<Item>>, Store> {
+    let (mut new, mut old) = match (new, old) {
+      (Some(new), Some(old)) if new == old => return Ok(None),
+      (Some(new), Some(old)) => (BasicNode::read(store, new)?, BasicNode::read(store, old)?),
+      (Some(root), None) => return Ok(BasicNode::lookup(store, root, key)?.map(Some)),
+      (None, Some(root)) => return Ok(BasicNode::lookup(store, root, key)?.map(|_| None)),
+      (None, None) => return Ok(None),
+    };
+    loop {
+      // Descend in the taller tree until both paths reach the same height.
+      while new.height > old.height {
+        new = new.descend(store, key)?;
+      }
+      while old.height > new.height {
+        old = old.descend(store, key)?;
+      }
+      if new.height == 0 {
+        let (new, old) = (new.find(key), old.find(key));
+        let same = match (new, old) {
+          (Some(new), Some(old)) => new.same_value(old),
+          (None, None) => true,
+          _ => false,
+        };
+        return Ok((!same).then(|| new.cloned()));
+      }
+      let (a, b) = (&new.entries[new.child_index(key)], &old.entries[old.child_index(key)]);
+      if let (
+        Item::Child { page_id: a, hash: a_hash, .. },
+        Item::Child { page_id: b, hash: b_hash, .. },
+      ) = (&a.item, &b.item)
+      {
+        if a == b || a_hash == b_hash {
+          return Ok(None);
+        }
+      }
+      (new, old) = (new.descend(store, key)?, old.descend(store, key)?);
+    }
+  }
+}
+
+/// # Difference between versions
+///
+/// A key whose value differs between two versions of a tree, as reported by [`Diff`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Difference {
+  /// The key.
+  pub key: Box<[u8]>,
+  /// The value of the key in the old version, or `None` if the key was absent.
+  pub old: Option<Box<[u8]>>,
+  /// The value of the key in the new version, or `None` if the key was absent.
+  pub new: Option<Box<[u8]>>,
+}
+
+/// # Tree diff
+///
+/// Walks two versions of a [`BasicTree`] with the same policy in increasing order of keys, yielding
+/// the keys whose values differ between them. Each version is given by its root page ID (or `None`
+/// if the tree was empty), and must not be deallocated during the walk.
+///
+/// Both trees are expanded in lockstep, and pairs of subtrees with the same height and equal hashes
+/// are skipped without reading them. By unicity, unchanged regions of the two versions consist of
+/// such subtrees, so the cost is roughly proportional to the number of differences times the
+/// height of the trees.
+pub struct Diff {
+  old: Vec<(u8, Entry)>,
+  new: Vec<(u8, Entry)>,
+}
+
+impl Diff {
+  /// Creates a walk over the differences from the `old` version to the `new` version.
+  pub fn new<Store: paging::Store>(
+    store: &mut Store,
+    old: Option<u64>,
+    new: Option<u64>,
+  ) -> paging::Result<Self, Store> {
+    if old.is_some() && old == new {
+      return Ok(Self { old: Vec::new(), new: Vec::new() });
+    }
+    let mut frontier = |root| -> paging::Result<_, Store> {
+      let mut res = Vec::new();
+      if let Some(root) = root {
+        let node = BasicNode::read(store, root)?;
+        res.extend(node.entries.into_iter().rev().map(|entry| (node.height, entry)));
+      }
+      Ok(res)
+    };
+    Ok(Self { old: frontier(old)?, new: frontier(new)? })
+  }
+
+  /// Returns the next difference, or `None` if there are no more differences. If an error is
+  /// returned, the walk is left unchanged.
+  pub fn next<Store: paging::Store>(
+    &mut self,
+    store: &mut Store,
+  ) -> paging::Result<Option<Difference>, Store> {
+    loop {
+      let (old_height, new_height) =
+        (self.old.last().map_or(0, |(h, _)| *h), self.new.last().map_or(0, |(h, _)| *h));
+      if let (Some((_, old)), Some((_, new))) = (self.old.last(), self.new.last()) {
+        if let (
+          Item::Child { page_id: a, hash: a_hash, .. },
+          Item::Child { page_id: b, hash: b_hash, .. },
+        ) = (&old.item, &new.item)
+        {
+          if old_height == new_height && (a == b || a_hash == b_hash) {
+            self.old.pop();
+            self.new.pop();
+            continue;
+          }
+        }
+      }
+      // Expand the taller subtrees at the fronts until both are leaf entries.
+      if old_height > 0 || new_height > 0 {
+        let old = match old_height >= new_height {
+          true => Some(Self::expand(store, &self.old)?),
+          false => None,
+        };
+        let new = match new_height >= old_height {
+          true => Some(Self::expand(store, &self.new)?),
+          false => None,
+        };
+        for (frontier, children) in [(&mut self.old, old), (&mut self.new, new)] {
+          if let Some(children) = children {
+            frontier.pop();
+            frontier.extend(children);
+          }
+        }
+        continue;
+      }
+      let (old, new) = match (self.old.last(), self.new.last()) {
+        (None, None) => return Ok(None),
+        (Some((_, old)), Some((_, new))) if old.key < new.key => (Some(old), None),
+        (Some((_, old)), Some((_, new))) if old.key > new.key => (None, Some(new)),
+        (old, new) => (old.map(|(_, old)| old), new.map(|(_, new)| new)),
+      };
+      let same = matches!((old, new), (Some(old), Some(new)) if old.item.same_value(&new.item));
+      let difference = match same {
+        true => None,
+        false => Some(Difference {
+          key: old.or(new).unwrap().key.clone(),
+          old: old.map(|old| resolve(store, &old.item)).transpose()?.map(Into::into),
+          new: new.map(|new| resolve(store, &new.item)).transpose()?.map(Into::into),
+        }),
+      };
+      if old.is_some() {
+        self.old.pop();
+      }
+      if new.is_some() {
+        self.new.pop();
+      }
+      if difference.is_some() {
+        return Ok(difference);
+      }
+    }
+  }
+
+  /// Reads the child at the front of a frontier, returning its entries in reverse order.
+  fn expand<Store: paging::Store>(
+    store: &mut Store,
+    frontier: &[(u8, Entry)],
+  ) -> paging::Result<Vec<(u8, Entry)>, Store> {
+    let (height, entry) = frontier.last().unwrap();
+    let node = BasicNode::read_child(store, entry.child_page_id(), *height)?;
+    Ok(node.entries.into_iter().rev().map(|entry| (node.height, entry)).collect())
+  }
+}
+
+/// Deallocates all pages of a tree given its root page ID, including overflow pages. The tree must
+/// not be used afterwards.
+pub fn destroy<Store: paging::Store>(store: &mut Store, root: u64) -> paging::Result<(), Store> {
+  free(store, &mut vec![Garbage::Subtree(root)])
+}
+
+/// Deallocates garbage pages, removing them from the list as they are deallocated.
+fn free<Store: paging::Store>(
+  store: &mut Store,
+  garbage: &mut Vec<Garbage>,
+) -> paging::Result<(), Store> {
+  while let Some(last) = garbage.last() {
+    match *last {
+      Garbage::Page(page_id) => store.deallocate(page_id)?,
+      Garbage::Overflow(page_id) => paging::free_overflow(store, page_id)?,
+      Garbage::Subtree(page_id) => {
+        let node = BasicNode::read(store, page_id)?;
+        store.deallocate(page_id)?;
+        garbage.pop();
+        for entry in node.entries {
+          match entry.item {
+            Item::Inline(_) => {}
+            Item::Overflow { page_id, .. } => garbage.push(Garbage::Overflow(page_id)),
+            Item::Child { page_id, .. } => garbage.push(Garbage::Subtree(page_id)),
+          }
+        }
+        continue;
+      }
+    }
+    garbage.pop();
+  }
+  Ok(())
+}
+
+/// Checks that a content hash or summary can be stored in a tree node, which allows up to a 32nd of
+/// a page.
+fn check_digest<Store: paging::Store>(
+  store: &Store,
+  digest: Box<[u8]>,
+) -> paging::Result<Box<[u8]>, Store> {
+  let max = store.page_size() / 32;
+  if digest.len() > max {
+    return Err(paging::Error::DigestTooLarge { len: digest.len(), max });
+  }
+  Ok(digest)
+}
+
+/// Returns the maximum size of a cell (including its cell pointer) in a node page.
+fn max_cell_size(page_size: usize) -> usize {
+  paging::node_page_capacity(page_size) / 4
+}
+
+/// Returns the content of a leaf item.
+fn resolve<Store: paging::Store>(store: &mut Store, item: &Item) -> paging::Result<Vec<u8>, Store> {
+  match item {
+    Item::Inline(value) => Ok(value.to_vec()),
+    Item::Overflow { page_id, len, .. } => paging::read_overflow(store, *page_id, *len),
+    Item::Child { .. } => unreachable!(),
+  }
+}
+
+/// An entry in a [`BasicNode`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Entry {
+  key: Box<[u8]>,
+  item: Item,
+}
+
+/// The content of an [`Entry`], apart from its key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Item {
+  /// A value stored in a leaf cell.
+  Inline(Box<[u8]>),
+  /// A value stored in a chain of overflow pages, along with its length, content hash and summary.
+  Overflow { page_id: u64, len: u64, hash: Box<[u8]>, summary: Box<[u8]> },
+  /// A child pointer in an internal node, along with the number of leaf entries in the subtree, the
+  /// content hash of the child and the summary of the subtree.
+  Child { page_id: u64, count: u64, hash: Box<[u8]>, summary: Box<[u8]> },
+}
+
+impl Item {
+  /// Returns whether two leaf items hold the same value, possibly stored in different pages.
+  fn same_value(&self, other: &Self) -> bool {
+    match (self, other) {
+      (
+        Item::Overflow { len, hash, .. },
+        Item::Overflow { len: other_len, hash: other_hash, .. },
+      ) => len == other_len && hash == other_hash,
+      _ => self == other,
+    }
+  }
+}
+
+impl Entry {
+  /// Returns the number of leaf entries in the subtree of an internal node entry.
+  fn count(&self) -> u64 {
+    match self.item {
+      Item::Child { count, .. } => count,
+      _ => unreachable!(),
+    }
+  }
+
+  /// Returns the child page ID of an internal node entry.
+  fn child_page_id(&self) -> u64 {
+    match self.item {
+      Item::Child { page_id, .. } => page_id,
+      _ => unreachable!(),
+    }
+  }
+
+  /// Returns the size of the cell encoding an entry.
+  fn cell_size_of(key: &[u8], item: &Item) -> usize {
+    let mut cell = Vec::new();
+    Self::encode_parts(key, item, &mut cell);
+    cell.len()
+  }
+
+  /// Encodes an entry into a cell.
+  ///
+  /// - Leaf cells contain `(key, value)` pairs, where the value length is multiplied by two. Large
+  ///   values are replaced by `(first page ID, hash, summary)` triples and indicated by an odd
+  ///   length.
+  /// - Internal cells contain `(key, pointer, count, hash, summary)` tuples.
+  fn encode_parts(key: &[u8], item: &Item, output: &mut Vec<u8>) {
+    prefix_varint::encode(key.len() as u64, output);
+    output.extend_from_slice(key);
+    match item {
+      Item::Inline(value) => {
+        prefix_varint::encode(2 * value.len() as u64, output);
+        output.extend_from_slice(value);
+      }
+      Item::Overflow { page_id, len, hash, summary } => {
+        prefix_varint::encode(2 * len + 1, output);
+        output.extend_from_slice(&page_id.to_le_bytes());
+        prefix_varint::encode(hash.len() as u64, output);
+        output.extend_from_slice(hash);
+        prefix_varint::encode(summary.len() as u64, output);
+        output.extend_from_slice(summary);
+      }
+      Item::Child { page_id, count, hash, summary } => {
+        output.extend_from_slice(&page_id.to_le_bytes());
+        prefix_varint::encode(*count, output);
+        prefix_varint::encode(hash.len() as u64, output);
+        output.extend_from_slice(hash);
+        prefix_varint::encode(summary.len() as u64, output);
+        output.extend_from_slice(summary);
+      }
+    }
+  }
+
+  /// Splits a cell into the key and the remaining part.
+  fn split_cell(cell: &[u8]) -> Option<(&[u8], &[u8])> {
+    let mut cell = cell;
+    let len = paging::take_varint(&mut cell)?;
+    let key = paging::take_bytes(&mut cell, usize::try_from(len).ok()?)?;
+    Some((key, cell))
+  }
+
+  /// Decodes an entry from a cell of a node with the given height.
+  fn decode(height: u8, cell: &[u8]) -> Option<Self> {
+    let (key, mut cell) = Self::split_cell(cell)?;
+    let item = if height == 0 {
+      let tag = paging::take_varint(&mut cell)?;
+      if tag % 2 == 0 {
+        Item::Inline(paging::take_bytes(&mut cell, usize::try_from(tag / 2).ok()?)?.into())
+      } else {
+        let page_id = paging::take_u64(&mut cell)?;
+        let len = paging::take_varint(&mut cell)?;
+        let hash = paging::take_bytes(&mut cell, usize::try_from(len).ok()?)?.into();
+        let len = paging::take_varint(&mut cell)?;
+        let summary = paging::take_bytes(&mut cell, usize::try_from(len).ok()?)?.into();
+        Item::Overflow { page_id, len: tag / 2, hash, summary }
+      }
+    } else {
+      let page_id = paging::take_u64(&mut cell)?;
+      let count = paging::take_varint(&mut cell)?;
+      let len = paging::take_varint(&mut cell)?;
+      let hash = paging::take_bytes(&mut cell, usize::try_from(len).ok()?)?.into();
+      let len = paging::take_varint(&mut cell)?;
+      let summary = paging::take_bytes(&mut cell, usize::try_from(len).ok()?)?.into();
+      Item::Child { page_id, count, hash, summary }
+    };
+    cell.is_empty().then_some(Self { key: key.into(), item })
+  }
+
+  /// Returns the content hash of the value or child which this entry refers to.
+  fn hash<Policy: self::Policy>(&self, policy: &Policy) -> Box<[u8]> {
+    match &self.item {
+      Item::Inline(value) => policy.content_hash(value),
+      Item::Overflow { hash, .. } | Item::Child { hash, .. } => hash.clone(),
+    }
+  }
+
+  /// Returns the summary of the value or subtree which this entry refers to.
+  fn summary<Aggregate: self::Aggregate>(&self, aggregate: &Aggregate) -> Box<[u8]> {
+    match &self.item {
+      Item::Inline(value) => aggregate.summarize(&self.key, value),
+      Item::Overflow { summary, .. } | Item::Child { summary, .. } => summary.clone(),
+    }
+  }
+}
+
+/// # Node of a [`BasicTree`]
+///
+/// Leaf nodes are stored in pages of type `3`, and internal nodes are stored in pages of type `2`.
+/// Internal node pages contain an additional last cell holding the height of the node.
+#[derive(Clone, Debug)]
+struct BasicNode {
+  height: u8,
+  entries: Vec<Entry>,
+}
+
+impl BasicNode {
+  /// Reads a node from a page.
+  fn read<Store: paging::Store>(store: &mut Store, page_id: u64) -> paging::Result<Self, Store> {
+    let page = store.get(page_id)?;
+    Self::decode(page).ok_or(paging::Error::Corrupt { page_id })
+  }
+
+  /// Reads a child node from a page, checking that it has the expected height.
+  fn read_child<Store: paging::Store>(
+    store: &mut Store,
+    page_id: u64,
+    parent_height: u8,
+  ) -> paging::Result<Self, Store> {
+    let node = Self::read(store, page_id)?;
+    if node.height + 1 != parent_height {
+      return Err(paging::Error::Corrupt { page_id });
+    }
+    Ok(node)
+  }
+
+  /// Splits a page into the height of the node and the cells of its entries.
+  fn decode_cells(page: &[u8]) -> Option<(u8, Vec<&[u8]>)> {
+    let (page_type, mut cells) = paging::read_node_page(page)?;
+    let height = match page_type {
+      paging::PROLLY_LEAF => 0,
+      paging::PROLLY_INTERNAL => match cells.pop()? {
+        &[height] if height > 0 => height,
+        _ => return None,
+      },
+      _ => return None,
+    };
+    (!cells.is_empty()).then_some((height, cells))
+  }
+
+  /// Decodes a node from a page.
+  fn decode(page: &[u8]) -> Option<Self> {
+    let (height, cells) = Self::decode_cells(page)?;
+    let entries =
+      cells.into_iter().map(|cell| Entry::decode(height, cell)).collect::<Option<Vec<_>>>()?;
+    let sorted = entries.windows(2).all(|pair| pair[0].key < pair[1].key);
+    sorted.then_some(Self { height, entries })
+  }
+
+  /// Writes a node into a page.
+  fn write<Store: paging::Store>(
+    &self,
+    store: &mut Store,
+    page_id: u64,
+  ) -> paging::Result<(), Store> {
+    let mut cells = Vec::with_capacity(self.entries.len() + 1);
+    for entry in &self.entries {
+      let mut cell = Vec::new();
+      Entry::encode_parts(&entry.key, &entry.item, &mut cell);
+      cells.push(cell);
+    }
+    let page_type = if self.height == 0 { paging::PROLLY_LEAF } else { paging::PROLLY_INTERNAL };
+    if self.height > 0 {
+      cells.push(vec![self.height]);
+    }
+    let mut page = vec![0; store.page_size()];
+    assert!(paging::write_node_page(&mut page, page_type, &cells));
+    store.write(page_id, &page)
+  }
+
+  /// Returns the key of the node, i.e. the key of its first entry.
+  fn key(&self) -> &[u8] {
+    &self.entries[0].key
+  }
+
+  /// Returns the key of the last entry in the node.
+  fn last_key(&self) -> &[u8] {
+    &self.entries[self.entries.len() - 1].key
+  }
+
+  /// Returns the number of leaf entries in the subtree rooted at the node.
+  fn count(&self) -> u64 {
+    match self.height {
+      0 => self.entries.len() as u64,
+      _ => self.entries.iter().map(Entry::count).sum(),
+    }
+  }
+
+  /// Returns the summary of the key-value pairs in the subtree rooted at the node.
+  fn summary<Aggregate: self::Aggregate>(&self, aggregate: &Aggregate) -> Box<[u8]> {
+    let mut res = aggregate.empty();
+    for entry in &self.entries {
+      res = aggregate.combine(&res, &entry.summary(aggregate));
+    }
+    res
+  }
+
+  /// Returns the index of the child which may contain the given key.
+  fn child_index(&self, key: &[u8]) -> usize {
+    self.entries.partition_point(|entry| *entry.key <= *key).saturating_sub(1)
+  }
+
+  /// Reads the child which may contain the given key.
+  fn descend<Store: paging::Store>(
+    &self,
+    store: &mut Store,
+    key: &[u8],
+  ) -> paging::Result<Self, Store> {
+    Self::read_child(store, self.entries[self.child_index(key)].child_page_id(), self.height)
+  }
+
+  /// Returns the item of the given key in a leaf node.
+  fn find(&self, key: &[u8]) -> Option<&Item> {
+    let index = self.entries.binary_search_by(|entry| (*entry.key).cmp(key)).ok()?;
+    Some(&self.entries[index].item)
+  }
+
+  /// Returns the leaf item of a key in the tree with the given root.
+  fn lookup<Store: paging::Store>(
+    store: &mut Store,
+    root: u64,
+    key: &[u8],
+  ) -> paging::Result<Option<Item>, Store> {
+    let mut node = Self::read(store, root)?;
+    while node.height > 0 {
+      node = node.descend(store, key)?;
+    }
+    Ok(node.find(key).cloned())
+  }
+
+  /// Returns the content hash of the node, computed over the `(key, hash)` pairs of its entries.
+  fn hash<Policy: self::Policy>(&self, policy: &Policy) -> Box<[u8]> {
+    let mut content = Vec::new();
+    for entry in &self.entries {
+      let hash = entry.hash(policy);
+      prefix_varint::encode(entry.key.len() as u64, &mut content);
+      content.extend_from_slice(&entry.key);
+      prefix_varint::encode(hash.len() as u64, &mut content);
+      content.extend_from_slice(&hash);
+    }
+    policy.content_hash(&content)
+  }
+}
+
+/// Splits a sequence of entries in a layer into nodes.
+struct Chunker<'a, Policy: self::Policy> {
+  policy: &'a Policy,
+  height: u8,
+  capacity: usize,
+  max_cell_size: usize,
+  entries: Vec<Entry>,
+  size: usize,
+  nodes: Vec<BasicNode>,
+}
+
+impl<'a, Policy: self::Policy> Chunker<'a, Policy> {
+  fn new(policy: &'a Policy, height: u8, page_size: usize) -> Self {
+    Self {
+      policy,
+      height,
+      // Reserve space for the height cell in internal nodes.
+      capacity: paging::node_page_capacity(page_size) - 3,
+      max_cell_size: max_cell_size(page_size),
+      entries: Vec::new(),
+      size: 0,
+      nodes: Vec::new(),
+    }
+  }
+
+  /// Appends an entry to the current node, ending the node if this is a boundary.
+  fn push(&mut self, entry: Entry) {
+    let size = Entry::cell_size_of(&entry.key, &entry.item) + 2;
+    // Keys, hashes and summaries of new entries are checked by `BasicTree::make_item` and
+    // `BasicTree::write_nodes`, and other entries are read from valid nodes.
+    debug_assert!(size <= self.max_cell_size, "entry too large for page size");
+    self.size += size;
+    let boundary =
+      self.policy.boundary_decision(self.height as usize, &entry.key, self.entries.len() + 1)
+        || self.size + self.max_cell_size > self.capacity;
+    self.entries.push(entry);
+    if boundary {
+      self.nodes.push(BasicNode { height: self.height, entries: mem::take(&mut self.entries) });
+      self.size = 0;
+    }
+  }
+
+  /// Ends the current node, if it is not empty.
+  fn finish(&mut self) {
+    if !self.entries.is_empty() {
+      self.nodes.push(BasicNode { height: self.height, entries: mem::take(&mut self.entries) });
+      self.size = 0;
+    }
+  }
+
+  /// Returns whether the last entry pushed was a boundary.
+  fn is_synced(&self) -> bool {
+    self.entries.is_empty()
+  }
+}
+
+/// A range of keys to be removed by [`BasicTree::splice`].
+struct Range {
+  start: ops::Bound<Box<[u8]>>,
+  end: ops::Bound<Box<[u8]>>,
+}
+
+impl Range {
+  /// Creates an owned copy of the given range.
+  fn new<'a>(range: impl ops::RangeBounds<&'a [u8]>) -> Self {
+    let (start, end) = (range.start_bound(), range.end_bound());
+    Self { start: start.map(|key| (*key).into()), end: end.map(|key| (*key).into()) }
+  }
+
+  /// Returns the ranges of keys before and after the range.
+  fn complement(&self) -> impl Iterator<Item = Self> {
+    let before = match &self.start {
+      ops::Bound::Included(key) => Some(ops::Bound::Excluded(key.clone())),
+      ops::Bound::Excluded(key) => Some(ops::Bound::Included(key.clone())),
+      ops::Bound::Unbounded => None,
+    };
+    let after = match &self.end {
+      ops::Bound::Included(key) => Some(ops::Bound::Excluded(key.clone())),
+      ops::Bound::Excluded(key) => Some(ops::Bound::Included(key.clone())),
+      ops::Bound::Unbounded => None,
+    };
+    let before = before.map(|end| Self { start: ops::Bound::Unbounded, end });
+    let after = after.map(|start| Self { start, end: ops::Bound::Unbounded });
+    before.into_iter().chain(after)
+  }
+
+  /// Returns whether the range contains no keys.
+  fn is_empty(&self) -> bool {
+    match (&self.start, &self.end) {
+      (ops::Bound::Included(start), ops::Bound::Included(end)) => start > end,
+      (ops::Bound::Included(start) | ops::Bound::Excluded(start), ops::Bound::Excluded(end))
+      | (ops::Bound::Excluded(start), ops::Bound::Included(end)) => start >= end,
+      _ => false,
+    }
+  }
+
+  /// Returns whether the given key satisfies the start bound of the range.
+  fn is_after_start(&self, key: &[u8]) -> bool {
+    match &self.start {
+      ops::Bound::Included(start) => **start <= *key,
+      ops::Bound::Excluded(start) => **start < *key,
+      ops::Bound::Unbounded => true,
+    }
+  }
+
+  /// Returns whether the range contains the given key.
+  fn contains(&self, key: &[u8]) -> bool {
+    let bounds = (self.start.as_ref().map(|k| &**k), self.end.as_ref().map(|k| &**k));
+    ops::RangeBounds::<[u8]>::contains(&bounds, key)
+  }
+
+  /// Returns a key which is not greater than any key in the range.
+  fn start_key(&self) -> &[u8] {
+    match &self.start {
+      ops::Bound::Included(key) | ops::Bound::Excluded(key) => key,
+      ops::Bound::Unbounded => &[],
+    }
+  }
+
+  /// Returns whether all keys between the given key and the range are in the range.
+  fn starts_before(&self, key: &[u8]) -> bool {
+    match &self.start {
+      ops::Bound::Included(start) | ops::Bound::Excluded(start) => **start <= *key,
+      ops::Bound::Unbounded => true,
+    }
+  }
+
+  /// Returns whether the range is known to contain all keys from `lower` (inclusive) to `upper`
+  /// (exclusive, if given).
+  fn covers(&self, lower: &[u8], upper: Option<&[u8]>) -> bool {
+    let end = match (&self.end, upper) {
+      (ops::Bound::Included(end) | ops::Bound::Excluded(end), Some(upper)) => *upper <= **end,
+      (ops::Bound::Included(_) | ops::Bound::Excluded(_), None) => false,
+      (ops::Bound::Unbounded, _) => true,
+    };
+    self.is_after_start(lower) && end
+  }
+
+  /// Returns whether some keys from `lower` (inclusive) to `upper` (exclusive, if given) may be in
+  /// the range.
+  fn overlaps(&self, lower: &[u8], upper: Option<&[u8]>) -> bool {
+    let start = match (&self.start, upper) {
+      (ops::Bound::Included(start) | ops::Bound::Excluded(start), Some(upper)) => **start < *upper,
+      _ => true,
+    };
+    let end = match &self.end {
+      ops::Bound::Included(end) => *lower <= **end,
+      ops::Bound::Excluded(end) => *lower < **end,
+      ops::Bound::Unbounded => true,
+    };
+    start && end
+  }
+
+  /// Returns whether the range contains keys greater than the given key.
+  fn ends_after(&self, key: &[u8]) -> bool {
+    match &self.end {
+      ops::Bound::Included(end) | ops::Bound::Excluded(end) => **end > *key,
+      ops::Bound::Unbounded => true,
+    }
+  }
+}
+
+/// The effects of a [`BasicTree::splice`].
+struct Splice {
+  /// The root page ID of the new version.
+  root: Option<u64>,
+  /// Pages which are no longer referenced.
+  garbage: Vec<Garbage>,
+  /// The number of mutations whose keys were present.
+  found: usize,
+  /// The number of entries removed by the range.
+  removed: u64,
+}
+
+/// The result of applying mutations to a single layer.
+struct Level {
+  /// Mutations to be applied to the layer above, ordered by key.
+  parent: Vec<(Box<[u8]>, Option<Item>)>,
+  /// Entries pointing to the newly written nodes, ordered by key.
+  emitted: Vec<Entry>,
+}
+
+/// A path from the root to a node at a given height.
+struct Path {
+  /// Internal nodes on the path, along with the index of the child taken.
+  ancestors: Vec<(BasicNode, usize)>,
+  /// The node at the end of the path, and its page ID.
+  target: (u64, BasicNode),
+}
+
+impl Path {
+  /// Descends from the root to the node at the given height which may contain the given key.
+  fn locate<Store: paging::Store>(
+    store: &mut Store,
+    root: u64,
+    height: u8,
+    key: &[u8],
+  ) -> paging::Result<Self, Store> {
+    Self::descend(store, root, height, |node| node.child_index(key))
+  }
+
+  /// Descends from the root to the last node at the given height.
+  fn locate_last<Store: paging::Store>(
+    store: &mut Store,
+    root: u64,
+    height: u8,
+  ) -> paging::Result<Self, Store> {
+    Self::descend(store, root, height, |node| node.entries.len() - 1)
+  }
+
+  /// Descends from the root to a node at the given height, using `index` to choose the child.
+  fn descend<Store: paging::Store>(
+    store: &mut Store,
+    root: u64,
+    height: u8,
+    index: impl Fn(&BasicNode) -> usize,
+  ) -> paging::Result<Self, Store> {
+    let mut ancestors = Vec::new();
+    let mut page_id = root;
+    let mut node = BasicNode::read(store, page_id)?;
+    while node.height > height {
+      let i = index(&node);
+      page_id = node.entries[i].child_page_id();
+      let child = BasicNode::read_child(store, page_id, node.height)?;
+      ancestors.push((node, i));
+      node = child;
+    }
+    Ok(Self { ancestors, target: (page_id, node) })
+  }
+
+  /// Returns the node at the end of the path, and its page ID.
+  fn target(&self) -> (u64, &BasicNode) {
+    (self.target.0, &self.target.1)
+  }
+
+  /// Moves to the next node at the same height. Returns `false` if there is no such node, in which
+  /// case the path is left unchanged.
+  fn next<Store: paging::Store>(&mut self, store: &mut Store) -> paging::Result<bool, Store> {
+    let Some(level) = self.ancestors.iter().rposition(|(node, i)| i + 1 < node.entries.len())
+    else {
+      return Ok(false);
+    };
+    let height = self.target.1.height;
+    let (node, i) = &self.ancestors[level];
+    let mut ancestors = Vec::new();
+    let mut page_id = node.entries[i + 1].child_page_id();
+    let mut node = BasicNode::read_child(store, page_id, node.height)?;
+    while node.height > height {
+      page_id = node.entries[0].child_page_id();
+      let child = BasicNode::read_child(store, page_id, node.height)?;
+      ancestors.push((node, 0));
+      node = child;
+    }
+    self.ancestors.truncate(level + 1);
+    self.ancestors[level].1 += 1;
+    self.ancestors.append(&mut ancestors);
+    self.target = (page_id, node);
+    Ok(true)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::storage::testing::{open_store, TestPolicy, TestStore};
+  use crate::storage::vfs;
+  use map::{Cursor as _, Map as _};
+  use paging::Store as _;
+  use rand::rngs::StdRng;
+  use rand::seq::SliceRandom;
+  use rand::{Rng, SeedableRng};
+
+  type TestTree = BasicTree<TestStore, TestPolicy>;
+
+  fn key(i: u32) -> Vec<u8> {
+    format!("key{:06}", i).into_bytes()
+  }
+
+  fn value(i: u32, len: usize) -> Vec<u8> {
+    (0..len).map(|j| (i as usize + j) as u8).collect()
+  }
+
+  /// Builds the canonical tree for the given entries layer-by-layer, returning its root hash.
+  fn build_hash(store: &mut TestStore, entries: &[(Vec<u8>, Vec<u8>)]) -> Option<Box<[u8]>> {
+    let tree = TestTree::new(TestPolicy);
+    let mut layer = Vec::new();
+    for (key, value) in entries {
+      layer.push(Entry { key: key[..].into(), item: tree.make_item(store, key, value).unwrap() });
+    }
+    let mut height = 0;
+    loop {
+      let mut chunker = Chunker::new(&TestPolicy, height, store.page_size());
+      layer.into_iter().for_each(|entry| chunker.push(entry));
+      chunker.finish();
+      match chunker.nodes.as_slice() {
+        [] => return None,
+        [node] => return Some(node.hash(&TestPolicy)),
+        _ => layer = tree.write_nodes(store, chunker.nodes).unwrap(),
+      }
+      height += 1;
+    }
+  }
+
+  fn collect(store: &mut TestStore, tree: &TestTree) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut cursor = tree.lower_bound(store, ops::Bound::Unbounded).unwrap();
+    let mut res = Vec::new();
+    while let Some((key, value)) = cursor.next(store).unwrap() {
+      res.push((key.to_vec(), value.to_vec()));
+    }
+    res
+  }
+
+  #[test]
+  fn test_empty() {
+    let mut store = open_store();
+    let mut tree = TestTree::new(TestPolicy);
+    assert_eq!(tree.get(&mut store, b"a").unwrap(), None);
+    assert!(!tree.remove(&mut store, b"a").unwrap());
+    assert_eq!(tree.root(), None);
+    let mut cursor = tree.lower_bound(&mut store, ops::Bound::Unbounded).unwrap();
+    assert!(cursor.next(&mut store).unwrap().is_none());
+    assert!(cursor.prev(&mut store).unwrap().is_none());
+  }
+
+  #[test]
+  fn test_insert_get_remove() {
+    let mut store = open_store();
+    let mut tree = TestTree::new(TestPolicy);
+    for i in 0..500 {
+      assert!(!tree.insert(&mut store, &key(i * 7 % 500), &value(i, 10)).unwrap());
+    }
+    for i in 0..500 {
+      assert_eq!(
+        tree.get(&mut store, &key(i * 7 % 500)).unwrap().as_deref(),
+        Some(&value(i, 10)[..])
+      );
+    }
+    assert!(tree.insert(&mut store, &key(42), b"updated").unwrap());
+    assert_eq!(tree.get(&mut store, &key(42)).unwrap().as_deref(), Some(&b"updated"[..]));
+    for i in 0..500 {
+      assert!(tree.remove(&mut store, &key(i)).unwrap());
+      assert!(!tree.remove(&mut store, &key(i)).unwrap());
+      assert_eq!(tree.get(&mut store, &key(i)).unwrap(), None);
+    }
+    assert_eq!(tree.root(), None);
+  }
+
+  #[test]
+  fn test_random_operations() {
+    // Test against a reference implementation, including unicity.
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut store = open_store();
+    let mut tree = TestTree::new(TestPolicy);
+    let mut model = collections::BTreeMap::new();
+    for round in 0..3000 {
+      let i = rng.gen_range(0..400);
+      if rng.gen_bool(0.6) {
+        let value = value(round, rng.gen_range(0..40));
+        let present = model.insert(key(i), value.clone()).is_some();
+        assert_eq!(tree.insert(&mut store, &key(i), &value).unwrap(), present);
+      } else {
+        let present = model.remove(&key(i)).is_some();
+        assert_eq!(tree.remove(&mut store, &key(i)).unwrap(), present);
+      }
+    }
+    let expected = model.iter().map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>();
+    assert_eq!(collect(&mut store, &tree), expected);
+    assert_eq!(tree.root_hash(&mut store).unwrap(), build_hash(&mut store, &expected));
+
+    let mut entries = expected.clone();
+    entries.shuffle(&mut rng);
+    let mut other = TestTree::new(TestPolicy);
+    for (key, value) in &entries {
+      other.insert(&mut store, key, value).unwrap();
+    }
+    assert_eq!(tree.root_hash(&mut store).unwrap(), other.root_hash(&mut store).unwrap());
+  }
+
+  #[test]
+  fn test_apply() {
+    // Test that batches produce the same trees as individual mutations.
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut store = open_store();
+    let mut batched = TestTree::new(TestPolicy);
+    let mut single = TestTree::new(TestPolicy);
+    for round in 0..40 {
+      let mut batch = collections::BTreeMap::new();
+      for _ in 0..rng.gen_range(0..60) {
+        let value = rng.gen_bool(0.7).then(|| value(round, rng.gen_range(0..300)));
+        batch.insert(key(rng.gen_range(0..500)), value);
+      }
+      let mut found = 0;
+      for (key, value) in &batch {
+        let present = match value {
+          Some(value) => single.insert(&mut store, key, value).unwrap(),
+          None => single.remove(&mut store, key).unwrap(),
+        };
+        found += present as usize;
+      }
+      let mutations = batch.iter().map(|(key, value)| match value {
+        Some(value) => Mutation::Put(key, value),
+        None => Mutation::Delete(key),
+      });
+      assert_eq!(batched.apply(&mut store, mutations).unwrap(), found);
+      assert_eq!(batched.root_hash(&mut store).unwrap(), single.root_hash(&mut store).unwrap());
+      assert_eq!(collect(&mut store, &batched), collect(&mut store, &single));
+    }
+  }
+
+  #[test]
+  fn test_apply_rewrites_less() {
+    let mut store = open_store();
+    let mut tree = TestTree::new(TestPolicy);
+    let keys = (0..300).map(key).collect::<Vec<_>>();
+    tree.apply(&mut store, keys.iter().map(|key| Mutation::Put(key, b"old"))).unwrap();
+    tree.release(&mut store).unwrap();
+    let before = store.page_count();
+    tree.apply(&mut store, keys.iter().map(|key| Mutation::Put(key, b"new"))).unwrap();
+    let batched = store.page_count() - before;
+    tree.release(&mut store).unwrap();
+    let before = store.page_count();
+    for key in &keys {
+      tree.insert(&mut store, key, b"old").unwrap();
+    }
+    let single = store.page_count() - before;
+    assert!(batched * 4 < single, "batched: {batched}, single: {single}");
+  }
+
+  #[test]
+  fn test_key_too_large() {
+    let mut store = open_store();
+    let mut tree = TestTree::new(TestPolicy);
+    let res = tree.insert(&mut store, &[0; 65], b"value");
+    assert!(matches!(res, Err(paging::Error::TooLarge { len: 65, max: 64 })));
+    // No overflow pages are written for earlier mutations.
+    let page_count = store.page_count();
+    let mutations = [Mutation::Put(b"a", &[0; 1000]), Mutation::Put(&[1; 65], b"value")];
+    assert!(matches!(tree.apply(&mut store, mutations), Err(paging::Error::TooLarge { .. })));
+    assert_eq!(store.page_count(), page_count);
+    assert_eq!(tree.root(), None);
+  }
+
+  /// A policy producing content hashes too long for nodes in pages of 512 bytes.
+  struct LongHashPolicy;
+
+  impl Policy for LongHashPolicy {
+    fn boundary_decision(&self, height: usize, key: &[u8], size: usize) -> bool {
+      TestPolicy.boundary_decision(height, key, size)
+    }
+
+    fn content_hash(&self, _content: &[u8]) -> Box<[u8]> {
+      Box::new([0; 17])
+    }
+  }
+
+  /// An aggregate producing summaries which grow with the values, so that summaries of values of
+  /// more than 849 bytes are too long for nodes in pages of 512 bytes.
+  #[derive(Default)]
+  struct LongAggregate;
+
+  impl Aggregate for LongAggregate {
+    fn summarize(&self, _key: &[u8], value: &[u8]) -> Box<[u8]> {
+      vec![0; value.len() / 50].into()
+    }
+
+    fn combine(&self, left: &[u8], right: &[u8]) -> Box<[u8]> {
+      left.max(right).into()
+    }
+
+    fn empty(&self) -> Box<[u8]> {
+      Box::new([])
+    }
+  }
+
+  #[test]
+  fn test_digest_too_large() {
+    let mut store = open_store();
+    let mut tree = BasicTree::<TestStore, LongHashPolicy>::new(LongHashPolicy);
+    let res = tree.insert(&mut store, b"a", b"value");
+    assert!(matches!(res, Err(paging::Error::DigestTooLarge { len: 17, max: 16 })));
+    let mut tree = BasicTree::<TestStore, TestPolicy, LongAggregate>::new(TestPolicy);
+    let res = tree.insert(&mut store, b"a", &[0; 1000]);
+    assert!(matches!(res, Err(paging::Error::DigestTooLarge { len: 20, max: 16 })));
+    // Overflow pages written for earlier mutations are deallocated, and can be reused.
+    let mutations = [Mutation::Put(b"a", &[0; 500]), Mutation::Put(b"b", &[0; 1000])];
+    let res = tree.apply(&mut store, mutations);
+    assert!(matches!(res, Err(paging::Error::DigestTooLarge { .. })));
+    let page_count = store.page_count();
+    store.allocate().unwrap();
+    store.allocate().unwrap();
+    assert_eq!(store.page_count(), page_count);
+  }
+
+  #[test]
+  #[should_panic]
+  fn test_apply_unsorted() {
+    let mut store = open_store();
+    let mut tree = TestTree::new(TestPolicy);
+    tree.apply(&mut store, [Mutation::Put(b"b", b""), Mutation::Delete(b"a")]).unwrap();
+  }
+
+  #[test]
+  fn test_remove_range() {
+    // Test against a reference implementation, including unicity.
+    let mut rng = StdRng::seed_from_u64(2);
+    let mut store = open_store();
+    let mut tree = TestTree::new(TestPolicy);
+    let mut model = collections::BTreeMap::new();
+    let bound = |rng: &mut StdRng| match rng.gen_range(0..5) {
+      0 => ops::Bound::Unbounded,
+      1 | 2 => ops::Bound::Included(key(rng.gen_range(0..2000))),
+      _ => ops::Bound::Excluded(key(rng.gen_range(0..2000))),
+    };
+    for round in 0..60 {
+      let mut batch = collections::BTreeMap::new();
+      for _ in 0..rng.gen_range(0..300) {
+        batch.insert(key(rng.gen_range(0..2000)), value(round, rng.gen_range(0..200)));
+      }
+      tree.apply(&mut store, batch.iter().map(|(key, value)| Mutation::Put(key, value))).unwrap();
+      model.extend(batch);
+      let (start, end) = (bound(&mut rng), bound(&mut rng));
+      let range = (start.as_ref().map(|k| &k[..]), end.as_ref().map(|k| &k[..]));
+      let valid = match (&start, &end) {
+        (ops::Bound::Included(s), ops::Bound::Included(e)) => s <= e,
+        (ops::Bound::Included(s) | ops::Bound::Excluded(s), ops::Bound::Excluded(e))
+        | (ops::Bound::Excluded(s), ops::Bound::Included(e)) => s < e,
+        _ => true,
+      };
+      let len = model.len();
+      if valid {
+        model.retain(|k, _| !ops::RangeBounds::<[u8]>::contains(&range, k));
+      }
+      let removed = len - model.len();
+      assert_eq!(tree.remove_range(&mut store, range).unwrap(), removed as u64);
+      let expected = model.iter().map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>();
+      assert_eq!(collect(&mut store, &tree), expected);
+      assert_eq!(tree.root_hash(&mut store).unwrap(), build_hash(&mut store, &expected));
+    }
+  }
+
+  #[test]
+  fn test_remove_range_skips_subtrees() {
+    let mut store = open_store();
+    let mut tree = TestTree::new(TestPolicy);
+    let keys = (0..2000).map(key).collect::<Vec<_>>();
+    tree.apply(&mut store, keys.iter().map(|key| Mutation::Put(key, &[42; 100]))).unwrap();
+    let before = store.page_count();
+    let range = (ops::Bound::Included(&keys[10][..]), ops::Bound::Excluded(&keys[1990][..]));
+    assert_eq!(tree.remove_range(&mut store, range).unwrap(), 1980);
+    let written = store.page_count() - before;
+    assert!(written < 20, "written: {written}");
+    let expected = keys.iter().enumerate().filter(|(i, _)| *i < 10 || *i >= 1990);
+    let expected = expected.map(|(_, k)| (k.clone(), vec![42; 100])).collect::<Vec<_>>();
+    assert_eq!(collect(&mut store, &tree), expected);
+    // All pages of the skipped subtrees are released.
+    assert_eq!(tree.remove_range(&mut store, ..).unwrap(), 20);
+    assert_eq!(tree.root(), None);
+    tree.release(&mut store).unwrap();
+    let page_count = store.page_count();
+    tree.apply(&mut store, keys.iter().map(|key| Mutation::Put(key, &[42; 100]))).unwrap();
+    tree.remove_range(&mut store, &keys[500][..]..).unwrap();
+    tree.remove_range(&mut store, ..).unwrap();
+    tree.release(&mut store).unwrap();
+    assert_eq!(store.page_count(), page_count);
+  }
+
+  #[test]
+  fn test_pin() {
+    let mut store = open_store();
+    let mut tree = TestTree::new(TestPolicy);
+    let keys = (0..500).map(key).collect::<Vec<_>>();
+    tree.apply(&mut store, keys.iter().map(|key| Mutation::Put(key, b"old"))).unwrap();
+    let old = TestTree::open(TestPolicy, tree.root());
+    let expected = collect(&mut store, &old);
+    // Pinned versions stay readable after releasing.
+    let pin = tree.pin();
+    tree.apply(&mut store, keys.iter().map(|key| Mutation::Put(key, b"new"))).unwrap();
+    tree.release(&mut store).unwrap();
+    let page_count = store.page_count();
+    store.allocate().unwrap();
+    assert_eq!(store.page_count(), page_count + 1);
+    assert_eq!(collect(&mut store, &old), expected);
+    // Replaced pages are deallocated by the first release after unpinning.
+    drop(pin);
+    tree.release(&mut store).unwrap();
+    store.allocate().unwrap();
+    assert_eq!(store.page_count(), page_count + 1);
+  }
+
+  /// Sums the first bytes of all values.
+  #[derive(Default)]
+  struct SumAggregate;
+
+  impl Aggregate for SumAggregate {
+    fn summarize(&self, _key: &[u8], value: &[u8]) -> Box<[u8]> {
+      u64::from(value.first().copied().unwrap_or(0)).to_le_bytes().into()
+    }
+
+    fn combine(&self, left: &[u8], right: &[u8]) -> Box<[u8]> {
+      let [left, right] = [left, right].map(|s| u64::from_le_bytes(s.try_into().unwrap()));
+      (left + right).to_le_bytes().into()
+    }
+
+    fn empty(&self) -> Box<[u8]> {
+      0u64.to_le_bytes().into()
+    }
+  }
+
+  #[test]
+  fn test_aggregate() {
+    let mut rng = StdRng::seed_from_u64(3);
+    let mut store = open_store();
+    let mut tree = BasicTree::<TestStore, TestPolicy, SumAggregate>::new(TestPolicy);
+    let mut model = collections::BTreeMap::new();
+    let bound = |rng: &mut StdRng| match rng.gen_range(0..5) {
+      0 => ops::Bound::Unbounded,
+      1 | 2 => ops::Bound::Included(key(rng.gen_range(0..1000))),
+      _ => ops::Bound::Excluded(key(rng.gen_range(0..1000))),
+    };
+    for round in 0..1500 {
+      let i = rng.gen_range(0..1000);
+      match rng.gen_range(0..10) {
+        0 => {
+          let (start, end) = (key(i), key(i + rng.gen_range(0..50)));
+          model.retain(|k, _| *k < start || *k >= end);
+          tree.remove_range(&mut store, &start[..]..&end[..]).unwrap();
+        }
+        1..4 => {
+          model.remove(&key(i));
+          tree.remove(&mut store, &key(i)).unwrap();
+        }
+        _ => {
+          // Include values stored in overflow pages.
+          let value = value(round, rng.gen_range(1..300));
+          model.insert(key(i), value.clone());
+          tree.insert(&mut store, &key(i), &value).unwrap();
+        }
+      }
+      let (start, end) = (bound(&mut rng), bound(&mut rng));
+      let range = (start.as_ref().map(|k| &k[..]), end.as_ref().map(|k| &k[..]));
+      let valid = match (&start, &end) {
+        (ops::Bound::Included(s), ops::Bound::Included(e)) => s <= e,
+        (ops::Bound::Included(s) | ops::Bound::Excluded(s), ops::Bound::Excluded(e))
+        | (ops::Bound::Excluded(s), ops::Bound::Included(e)) => s < e,
+        _ => true,
+      };
+      let expected = match valid {
+        true => model.range::<[u8], _>(range).map(|(_, v)| u64::from(v[0])).sum(),
+        false => 0u64,
+      };
+      let sum = tree.aggregate(&mut store, range).unwrap();
+      assert_eq!(u64::from_le_bytes((*sum).try_into().unwrap()), expected);
+    }
+    let total = model.values().map(|v| u64::from(v[0])).sum::<u64>();
+    assert_eq!(*tree.aggregate(&mut store, ..).unwrap(), total.to_le_bytes());
+    let other = BasicTree::<TestStore, TestPolicy>::open(TestPolicy, tree.root());
+    assert_eq!(*other.aggregate(&mut store, ..).unwrap(), []);
+  }
+
+  #[test]
+  fn test_range_hash() {
+    let mut rng = StdRng::seed_from_u64(5);
+    let mut store = open_store();
+    let mut tree = TestTree::new(TestPolicy);
+    let mut other = TestTree::new(TestPolicy);
+    let mut entries = Vec::new();
+    for i in 0..1500 {
+      let value = value(i, rng.gen_range(0..100));
+      tree.insert(&mut store, &key(i), &value).unwrap();
+      // The other tree only differs outside of `key(500)..key(1000)`.
+      if (500..1000).contains(&i) || rng.gen_bool(0.5) {
+        other.insert(&mut store, &key(i), &value).unwrap();
+      }
+      entries.push((key(i), value));
+    }
+    let (start, end) = (key(500), key(1000));
+    let hash = tree.range_hash(&mut store, &start[..]..&end[..]).unwrap();
+    assert_eq!(hash, build_hash(&mut store, &entries[500..1000]));
+    assert_eq!(other.range_hash(&mut store, &start[..]..&end[..]).unwrap(), hash);
+    assert_ne!(tree.root_hash(&mut store).unwrap(), other.root_hash(&mut store).unwrap());
+    let bound = |rng: &mut StdRng| match rng.gen_range(0..5) {
+      0 => ops::Bound::Unbounded,
+      1 | 2 => ops::Bound::Included(key(rng.gen_range(0..1600))),
+      _ => ops::Bound::Excluded(key(rng.gen_range(0..1600))),
+    };
+    for _ in 0..100 {
+      let (start, end) = (bound(&mut rng), bound(&mut rng));
+      let range = (start.as_ref().map(|k| &k[..]), end.as_ref().map(|k| &k[..]));
+      let expected = entries.iter().filter(|(k, _)| ops::RangeBounds::contains(&range, &&k[..]));
+      let expected = expected.cloned().collect::<Vec<_>>();
+      let page_count = store.page_count();
+      let hash = tree.range_hash(&mut store, range).unwrap();
+      // The store is left unchanged.
+      assert_eq!(store.page_count(), page_count);
+      assert_eq!(hash, build_hash(&mut store, &expected));
+    }
+    let hash = tree.range_hash(&mut store, ..).unwrap();
+    assert_eq!(hash, tree.root_hash(&mut store).unwrap());
+  }
+
+  #[test]
+  fn test_sample() {
+    let mut rng = StdRng::seed_from_u64(6);
+    let mut store = open_store();
+    let mut tree = TestTree::new(TestPolicy);
+    for i in 0..200 {
+      tree.insert(&mut store, &key(i), &value(i, i as usize * 3)).unwrap();
+    }
+    let mut counts = collections::HashMap::new();
+    for _ in 0..2000 {
+      let sample = tree.sample(&mut store, &mut rng, 10).unwrap();
+      assert_eq!(sample.len(), 10);
+      assert!(sample.windows(2).all(|pair| pair[0].0 < pair[1].0));
+      for (k, v) in sample {
+        let i = std::str::from_utf8(&k[3..]).unwrap().parse::<u32>().unwrap();
+        assert_eq!(*v, value(i, i as usize * 3));
+        *counts.entry(i).or_insert(0) += 1;
+      }
+    }
+    // Each entry is expected to be drawn 100 times.
+    assert!(counts.values().all(|&count| (50..150).contains(&count)), "{counts:?}");
+    assert_eq!(tree.sample(&mut store, &mut rng, 1000).unwrap().len(), 200);
+    // Results are reproducible given the same seed.
+    let first = tree.sample(&mut store, &mut StdRng::seed_from_u64(7), 5).unwrap();
+    let second = tree.sample(&mut store, &mut StdRng::seed_from_u64(7), 5).unwrap();
+    assert_eq!(first, second);
+  }
+
+  #[test]
+  fn test_sample_range() {
+    let mut rng = StdRng::seed_from_u64(8);
+    let mut store = open_store();
+    let mut tree = TestTree::new(TestPolicy);
+    for i in 0..3000 {
+      tree.insert(&mut store, &key(2 * i), &value(i, 4)).unwrap();
+    }
+    let (start, end) = (key(1001), key(5001));
+    let range = || (ops::Bound::Excluded(&start[..]), ops::Bound::Included(&end[..]));
+    let all = tree.sample_range(&mut store, &mut rng, range(), 1.0).unwrap();
+    let expected =
+      (501..=2500).map(|i| (key(2 * i).into(), value(i, 4).into())).collect::<Vec<_>>();
+    assert_eq!(all, expected);
+    assert!(tree.sample_range(&mut store, &mut rng, range(), 0.0).unwrap().is_empty());
+    let mut total = 0;
+    for _ in 0..20 {
+      let sample = tree.sample_range(&mut store, &mut rng, range(), 0.1).unwrap();
+      assert!(sample.iter().all(|element| all.contains(element)));
+      assert!(sample.windows(2).all(|pair| pair[0].0 < pair[1].0));
+      total += sample.len();
+    }
+    // Each sample is expected to contain 200 entries.
+    assert!((3600..4400).contains(&total), "{total}");
+  }
+
+  #[test]
+  fn test_history() {
+    let mut rng = StdRng::seed_from_u64(4);
+    let mut store = open_store();
+    let mut tree = TestTree::new(TestPolicy);
+    let mut model = collections::BTreeMap::new();
+    let mut commits = Vec::new();
+    let mut states = Vec::new();
+    for commit in 0..40 {
+      // Some commits only touch other keys, or do nothing at all.
+      for _ in 0..rng.gen_range(0..20) {
+        let i = rng.gen_range(0..200);
+        if rng.gen_bool(0.7) {
+          let value = value(rng.gen_range(0..3), rng.gen_range(0..150));
+          model.insert(key(i), value.clone());
+          tree.insert(&mut store, &key(i), &value).unwrap();
+        } else {
+          model.remove(&key(i));
+          tree.remove(&mut store, &key(i)).unwrap();
+        }
+      }
+      commits.push((commit, tree.root()));
+      states.push(model.clone());
+    }
+    for i in 0..200 {
+      let mut expected = Vec::new();
+      for commit in (0..40).rev() {
+        let value = states[commit].get(&key(i));
+        let parent = commit.checked_sub(1).and_then(|parent| states[parent].get(&key(i)));
+        if value != parent {
+          expected.push(Change { commit, value: value.map(|value| value[..].into()) });
+        }
+      }
+      let mut history = History::new(&key(i), commits.iter().rev().copied());
+      let mut changes = Vec::new();
+      while let Some(change) = history.next(&mut store).unwrap() {
+        changes.push(change);
+      }
+      assert_eq!(changes, expected);
+    }
+  }
+
+  #[test]
+  fn test_diff() {
+    let mut rng = StdRng::seed_from_u64(5);
+    let mut store = open_store();
+    let mut tree = TestTree::new(TestPolicy);
+    let mut model = collections::BTreeMap::new();
+    let mut versions = vec![(None, model.clone())];
+    for _ in 0..20 {
+      for _ in 0..rng.gen_range(0..30) {
+        let i = rng.gen_range(0..300);
+        if rng.gen_bool(0.7) {
+          let value = value(rng.gen_range(0..3), rng.gen_range(0..150));
+          model.insert(key(i), value.clone());
+          tree.insert(&mut store, &key(i), &value).unwrap();
+        } else {
+          model.remove(&key(i));
+          tree.remove(&mut store, &key(i)).unwrap();
+        }
+      }
+      versions.push((tree.root(), model.clone()));
+    }
+    for (old_root, old) in &versions {
+      for (new_root, new) in &versions {
+        let keys = old.keys().chain(new.keys()).collect::<collections::BTreeSet<_>>();
+        let expected = keys
+          .into_iter()
+          .filter(|key| old.get(*key) != new.get(*key))
+          .map(|key| Difference {
+            key: key[..].into(),
+            old: old.get(key).map(|value| value[..].into()),
+            new: new.get(key).map(|value| value[..].into()),
+          })
+          .collect::<Vec<_>>();
+        let mut diff = Diff::new(&mut store, *old_root, *new_root).unwrap();
+        let mut differences = Vec::new();
+        while let Some(difference) = diff.next(&mut store).unwrap() {
+          differences.push(difference);
+        }
+        assert_eq!(differences, expected);
+      }
+    }
+  }
+
+  /// Counts the pages read from another store.
+  struct CountingStore<'a> {
+    base: &'a mut TestStore,
+    reads: usize,
+  }
+
+  impl paging::Store for CountingStore<'_> {
+    type File = vfs::MemoryFile;
+
+    fn page_size(&self) -> usize {
+      self.base.page_size()
+    }
+
+    fn get(&mut self, page_id: u64) -> paging::Result<&[u8], Self> {
+      self.reads += 1;
+      self.base.get(page_id)
+    }
+
+    fn write(&mut self, page_id: u64, data: &[u8]) -> paging::Result<(), Self> {
+      self.base.write(page_id, data)
+    }
+
+    fn allocate(&mut self) -> paging::Result<u64, Self> {
+      self.base.allocate()
+    }
+
+    fn deallocate(&mut self, page_id: u64) -> paging::Result<(), Self> {
+      self.base.deallocate(page_id)
+    }
+  }
+
+  #[test]
+  fn test_diff_skips_subtrees() {
+    // Trees built separately share no pages, so equal subtrees can only be detected by hashes.
+    let mut store = open_store();
+    let (mut old, mut new) = (TestTree::new(TestPolicy), TestTree::new(TestPolicy));
+    let keys = (0..2000).map(key).collect::<Vec<_>>();
+    old.apply(&mut store, keys.iter().map(|key| Mutation::Put(key, &[42; 100]))).unwrap();
+    new.apply(&mut store, keys.iter().map(|key| Mutation::Put(key, &[42; 100]))).unwrap();
+    new.insert(&mut store, &keys[1000], b"changed").unwrap();
+    let mut store = CountingStore { base: &mut store, reads: 0 };
+    let mut diff = Diff::new(&mut store, old.root(), new.root()).unwrap();
+    let expected = Difference {
+      key: keys[1000][..].into(),
+      old: Some([42; 100][..].into()),
+      new: Some(b"changed"[..].into()),
+    };
+    assert_eq!(diff.next(&mut store).unwrap(), Some(expected));
+    assert_eq!(diff.next(&mut store).unwrap(), None);
+    assert!(store.reads < 20, "reads: {}", store.reads);
+  }
+
+  #[test]
+  fn test_overflow_values() {
+    let mut store = open_store();
+    let mut tree = TestTree::new(TestPolicy);
+    for i in 0..50 {
+      tree.insert(&mut store, &key(i), &value(i, i as usize * 37)).unwrap();
+    }
+    for i in 0..50 {
+      assert_eq!(
+        tree.get(&mut store, &key(i)).unwrap().as_deref(),
+        Some(&value(i, i as usize * 37)[..])
+      );
+    }
+    let expected = (0..50).map(|i| (key(i), value(i, i as usize * 37))).collect::<Vec<_>>();
+    assert_eq!(collect(&mut store, &tree), expected);
+  }
+
+  #[test]
+  fn test_get_ref() {
+    let mut store = open_store();
+    let mut tree = TestTree::new(TestPolicy);
+    for i in 0..100 {
+      tree.insert(&mut store, &key(i), &value(i, i as usize * 13)).unwrap();
+    }
+    for i in 0..100 {
+      let expected = value(i, i as usize * 13);
+      match tree.get_ref(&mut store, &key(i)).unwrap().unwrap() {
+        ValueRef::Inline(value) => assert_eq!(value, &expected[..]),
+        ValueRef::Overflow(mut reader) => {
+          assert_eq!(reader.len(), expected.len() as u64);
+          let mut read = Vec::new();
+          let mut buf = [0; 77];
+          while let n @ 1.. = reader.read(&mut buf).unwrap() {
+            read.extend_from_slice(&buf[..n]);
+          }
+          assert_eq!(read, expected);
+        }
+      }
+    }
+    assert!(tree.get_ref(&mut store, b"absent").unwrap().is_none());
+    assert!(tree.get_ref(&mut store, &key(1000)).unwrap().is_none());
+    // Small values are borrowed from the page; large values are streamed.
+    assert!(matches!(tree.get_ref(&mut store, &key(1)).unwrap(), Some(ValueRef::Inline(_))));
+    assert!(matches!(tree.get_ref(&mut store, &key(99)).unwrap(), Some(ValueRef::Overflow(_))));
+  }
+
+  #[test]
+  fn test_cursor_bounds() {
+    let mut store = open_store();
+    let mut tree = TestTree::new(TestPolicy);
+    for i in 0..200 {
+      tree.insert(&mut store, &key(2 * i), &value(i, 4)).unwrap();
+    }
+    let k = |i: u32| key(i);
+    // Included and excluded bounds, on both present and absent keys.
+    let cases = vec![
+      (ops::Bound::Included(k(100)), Some(98), Some(100)),
+      (ops::Bound::Excluded(k(100)), Some(100), Some(102)),
+      (ops::Bound::Included(k(101)), Some(100), Some(102)),
+      (ops::Bound::Excluded(k(101)), Some(100), Some(102)),
+      (ops::Bound::Included(k(0)), None, Some(0)),
+      (ops::Bound::Included(k(398)), Some(396), Some(398)),
+      (ops::Bound::Excluded(k(398)), Some(398), None),
+    ];
+    for (bound, before, after) in cases {
+      let mut cursor = tree.lower_bound(&mut store, bound.as_ref().map(|k| &k[..])).unwrap();
+      assert_eq!(cursor.peek_prev(&mut store).unwrap().map(|(k, _)| k.to_vec()), before.map(key));
+      assert_eq!(cursor.peek_next(&mut store).unwrap().map(|(k, _)| k.to_vec()), after.map(key));
+    }
+    let cases = vec![
+      (ops::Bound::Included(k(100)), Some(100), Some(102)),
+      (ops::Bound::Excluded(k(100)), Some(98), Some(100)),
+      (ops::Bound::Included(k(101)), Some(100), Some(102)),
+      (ops::Bound::Excluded(k(0)), None, Some(0)),
+      (ops::Bound::Included(k(999)), Some(398), None),
+    ];
+    for (bound, before, after) in cases {
+      let mut cursor = tree.upper_bound(&mut store, bound.as_ref().map(|k| &k[..])).unwrap();
+      assert_eq!(cursor.peek_prev(&mut store).unwrap().map(|(k, _)| k.to_vec()), before.map(key));
+      assert_eq!(cursor.peek_next(&mut store).unwrap().map(|(k, _)| k.to_vec()), after.map(key));
+    }
+  }
+
+  #[test]
+  fn test_cursor_back_and_forth() {
+    let mut store = open_store();
+    let mut tree = TestTree::new(TestPolicy);
+    for i in 0..300 {
+      tree.insert(&mut store, &key(i), &value(i, 4)).unwrap();
+    }
+    let mut cursor = tree.upper_bound(&mut store, ops::Bound::Unbounded).unwrap();
+    assert!(cursor.next(&mut store).unwrap().is_none());
+    for i in (0..300).rev() {
+      assert_eq!(cursor.prev(&mut store).unwrap().unwrap().0, &key(i)[..]);
+    }
+    assert!(cursor.prev(&mut store).unwrap().is_none());
+    for i in 0..150 {
+      assert_eq!(cursor.next(&mut store).unwrap().unwrap().0, &key(i)[..]);
+    }
+    assert_eq!(cursor.prev(&mut store).unwrap().unwrap().0, &key(149)[..]);
+    assert_eq!(cursor.next(&mut store).unwrap().unwrap().0, &key(149)[..]);
+  }
+
+  #[test]
+  fn test_release() {
+    let mut store = open_store();
+    let mut tree = TestTree::new(TestPolicy);
+    let mut page_count = 0;
+    for round in 0..5 {
+      for i in 0..200 {
+        tree.insert(&mut store, &key(i), &value(round, 100)).unwrap();
+      }
+      for i in 0..200 {
+        tree.remove(&mut store, &key(i)).unwrap();
+      }
+      tree.release(&mut store).unwrap();
+      // All pages are reused after the first round.
+      if round == 0 {
+        page_count = store.page_count();
+      }
+      assert_eq!(store.page_count(), page_count);
+    }
+  }
+
+  #[test]
+  fn test_old_versions_after_removes() {
+    // Removes which shrink the tree must not free pages still used by previous versions, even
+    // when the new root is an unchanged node with a single child.
+    for seed in 0..20 {
+      let mut rng = StdRng::seed_from_u64(seed);
+      let mut store = open_store();
+      let mut tree = TestTree::new(TestPolicy);
+      let mut model = collections::BTreeMap::new();
+      for i in 0..60 {
+        tree.insert(&mut store, &key(i), &value(i, 20)).unwrap();
+        model.insert(key(i), value(i, 20));
+      }
+      let mut versions = Vec::new();
+      while !model.is_empty() {
+        let i = rng.gen_range(0..60);
+        if model.remove(&key(i)).is_some() {
+          tree.remove(&mut store, &key(i)).unwrap();
+          versions.push((tree.root(), model.clone()));
+        }
+      }
+      for (root, model) in versions {
+        let expected = model.into_iter().collect::<Vec<_>>();
+        assert_eq!(collect(&mut store, &TestTree::open(TestPolicy, root)), expected);
+      }
+    }
+  }
+
+  #[test]
+  fn test_corrupt_page() {
+    let mut store = open_store();
+    let mut tree = TestTree::new(TestPolicy);
+    for i in 0..100 {
+      tree.insert(&mut store, &key(i), &value(i, 4)).unwrap();
+    }
+    let root = tree.root().unwrap();
+    store.write(root, &[0xCC; 512]).unwrap();
+    assert!(
+      matches!(tree.get(&mut store, &key(0)), Err(paging::Error::Corrupt { page_id }) if page_id == root)
+    );
+    assert!(matches!(tree.insert(&mut store, &key(0), b""), Err(paging::Error::Corrupt { .. })));
+    assert!(tree.lower_bound(&mut store, ops::Bound::Unbounded).is_err());
+    assert_eq!(tree.root(), Some(root));
+  }
+
+  #[test]
+  fn test_overflow_checksum_mismatch() {
+    let mut store = open_store();
+    let mut tree = TestTree::new(TestPolicy);
+    tree.insert(&mut store, b"key", &[42; 2000]).unwrap();
+    let leaf = BasicNode::read(&mut store, tree.root().unwrap()).unwrap();
+    let Item::Overflow { page_id, .. } = leaf.entries[0].item else { panic!() };
+    let mut page = store.get(page_id).unwrap().to_vec();
+    page[100] ^= 1;
+    store.write(page_id, &page).unwrap();
+    assert!(matches!(tree.get(&mut store, b"key"), Err(paging::Error::Checksum { .. })));
+    let Some(ValueRef::Overflow(mut reader)) = tree.get_ref(&mut store, b"key").unwrap() else {
+      panic!()
+    };
+    assert!(matches!(reader.read(&mut [0; 10]), Err(paging::Error::Checksum { .. })));
+  }
 }
@@ -0,0 +1,1418 @@
+//! # Tables
+//!
+//! A table consists of a primary index, which maps primary keys to values (typically stored in a
+//! Prolly tree), and any number of secondary indices, which map keys extracted from each row back
+//! to its primary key (stored in B+ trees). The secondary indices are kept up to date whenever the
+//! table is modified through [`Table`].
+
+use super::bplus;
+use super::map;
+use super::paging;
+use super::prolly;
+use super::vfs;
+use crate::encoding::prefix_varint;
+use std::cmp;
+use std::collections;
+use std::fmt;
+use std::mem;
+use std::ops;
+use std::result;
+
+/// # Table errors
+///
+/// Errors that can occur when modifying a table. Apart from storage errors, this covers rows which
+/// violate the constraints of some secondary index.
+#[derive(Debug)]
+pub enum Error<E> {
+  /// The underlying store reported an error.
+  Storage(paging::Error<E>),
+  /// A unique index already contains the index key of the row, for the row with the given other
+  /// primary key.
+  UniqueViolation { index: Box<str>, primary_key: Box<[u8]> },
+}
+
+/// Conversion from storage errors, so that they can be propagated with `?`.
+impl<E> From<paging::Error<E>> for Error<E> {
+  fn from(error: paging::Error<E>) -> Self {
+    Error::Storage(error)
+  }
+}
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Error::Storage(error) => write!(f, "{error}"),
+      Error::UniqueViolation { index, primary_key } => {
+        write!(
+          f,
+          "duplicate key in unique index {index}, conflicting with row with primary key {}",
+          primary_key.escape_ascii()
+        )
+      }
+    }
+  }
+}
+
+/// The result type of operations on a [`Table`] in a store of type `S`.
+pub type Result<T, S> = result::Result<T, Error<<<S as paging::Store>::File as vfs::File>::Error>>;
+
+/// # Index key extractor interface
+///
+/// Computes the secondary index key of a row, given its primary key and value.
+pub trait Extractor {
+  /// Returns the index key of the row.
+  fn extract(&self, key: &[u8], value: &[u8]) -> Box<[u8]>;
+}
+
+impl<F: Fn(&[u8], &[u8]) -> Box<[u8]>> Extractor for F {
+  fn extract(&self, key: &[u8], value: &[u8]) -> Box<[u8]> {
+    self(key, value)
+  }
+}
+
+/// # Row filter interface
+///
+/// Decides whether a row has an entry in a partial secondary index, given its primary key and value.
+pub trait Filter {
+  /// Returns whether the row is indexed.
+  fn matches(&self, key: &[u8], value: &[u8]) -> bool;
+}
+
+impl<F: Fn(&[u8], &[u8]) -> bool> Filter for F {
+  fn matches(&self, key: &[u8], value: &[u8]) -> bool {
+    self(key, value)
+  }
+}
+
+/// # Kind of secondary index
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+  /// Multiple rows may share the same index key.
+  NonUnique,
+  /// No two rows may share the same index key.
+  Unique,
+  /// Multiple rows may share the same index key, and their primary keys are stored together in a
+  /// compact posting list. This is suitable for index keys with few distinct values.
+  Postings,
+}
+
+/// # Secondary index definition
+///
+/// Describes how the entries of a secondary index are derived from the rows of its table. The index
+/// key of a row is computed by an [`Extractor`], which may derive it from the row in arbitrary ways
+/// (e.g. lowercasing a field). A partial index additionally has a [`Filter`], and only contains
+/// entries for the rows it matches.
+///
+/// A covering index also stores a projection of each row (e.g. an encoding of a few fields) in its
+/// entry, so that queries which only need these fields can be answered from the index alone,
+/// without looking up the primary index.
+pub struct Definition {
+  kind: Kind,
+  extractor: Box<dyn Extractor>,
+  filter: Option<Box<dyn Filter>>,
+  projection: Option<Box<dyn Extractor>>,
+}
+
+impl Definition {
+  /// Creates the definition of an index over all rows.
+  pub fn new(kind: Kind, extractor: impl Extractor + 'static) -> Self {
+    Self { kind, extractor: Box::new(extractor), filter: None, projection: None }
+  }
+
+  /// Creates the definition of a partial index over the rows matching the filter.
+  pub fn partial(
+    kind: Kind,
+    extractor: impl Extractor + 'static,
+    filter: impl Filter + 'static,
+  ) -> Self {
+    let filter = Some(Box::new(filter) as Box<dyn Filter>);
+    Self { kind, extractor: Box::new(extractor), filter, projection: None }
+  }
+
+  /// Makes the index covering, including the projection of each row in its entry.
+  pub fn include(self, projection: impl Extractor + 'static) -> Self {
+    Self { projection: Some(Box::new(projection)), ..self }
+  }
+
+  /// Returns the kind of the index.
+  pub fn kind(&self) -> Kind {
+    self.kind
+  }
+
+  /// Returns whether this is a partial index.
+  pub fn is_partial(&self) -> bool {
+    self.filter.is_some()
+  }
+
+  /// Returns whether this is a covering index.
+  pub fn is_covering(&self) -> bool {
+    self.projection.is_some()
+  }
+
+  /// Returns the index key of a row, or `None` if the row is excluded by the filter.
+  pub fn index_key(&self, key: &[u8], value: &[u8]) -> Option<Box<[u8]>> {
+    match &self.filter {
+      Some(filter) if !filter.matches(key, value) => None,
+      _ => Some(self.extractor.extract(key, value)),
+    }
+  }
+
+  /// Returns the entry of a row, or `None` if the row is excluded by the filter.
+  pub fn entry(&self, key: &[u8], value: &[u8]) -> Option<Entry> {
+    let index_key = self.index_key(key, value)?;
+    let included = match &self.projection {
+      Some(projection) => projection.extract(key, value),
+      None => Box::default(),
+    };
+    Some(Entry { key: index_key, primary_key: key.into(), included })
+  }
+}
+
+/// # Index entry
+///
+/// The entry of a row in a secondary index. Entries are ordered by index key first, and then by
+/// primary key.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Entry {
+  /// The index key of the row.
+  pub key: Box<[u8]>,
+  /// The primary key of the row.
+  pub primary_key: Box<[u8]>,
+  /// The projection of the row in a covering index, or empty otherwise.
+  pub included: Box<[u8]>,
+}
+
+/// # Secondary index
+///
+/// A B+ tree containing one entry for each row of the indexed table, or for each row matching the
+/// filter of a partial index.
+///
+/// ## Implementation notes
+///
+/// In non-unique indices, the key of each entry in the B+ tree is the index key followed by the
+/// primary key, so that entries are unique and ordered by index key first. To keep this order, zero
+/// bytes in the index key are escaped as `[0x00, 0x01]`, and the index key is terminated by
+/// `[0x00, 0x00]`. The values of entries are the projections of rows in covering indices, and empty
+/// otherwise.
+///
+/// In unique indices, the key of each entry is the index key, and the value is the primary key. In
+/// covering indices, the primary key is preceded by its length, and followed by the projection.
+///
+/// In posting list indices, the sorted list of primary keys of all rows with the same index key is
+/// split into segments, so that changing a row only rewrites the segment containing it. The key of
+/// each entry is built as in non-unique indices, from the index key and the first primary key of a
+/// segment, and the value is the segment. Each primary key is stored as the length of its common
+/// prefix with the previous one, followed by the length and content of the remaining suffix. In
+/// covering indices, this is followed by the length and content of the projection. All lengths are
+/// encoded as prefix varints. Segments are split in halves when their encoding grows beyond the
+/// page size, and are stored in overflow pages by the B+ tree if they do not fit into a leaf.
+pub struct Index<Store: paging::Store> {
+  name: Box<str>,
+  definition: Definition,
+  tree: bplus::BasicTree<Store>,
+}
+
+impl<Store: paging::Store> Index<Store> {
+  /// Creates an empty index in a newly allocated page.
+  pub fn create(
+    store: &mut Store,
+    name: &str,
+    definition: Definition,
+  ) -> paging::Result<Self, Store> {
+    let tree = bplus::BasicTree::create(store)?;
+    Ok(Self { name: name.into(), definition, tree })
+  }
+
+  /// Opens an existing index given its root page ID.
+  pub fn open(name: &str, definition: Definition, root: u64) -> Self {
+    let tree = bplus::BasicTree::open(root);
+    Self { name: name.into(), definition, tree }
+  }
+
+  /// Returns the name of the index.
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Returns the kind of the index.
+  pub fn kind(&self) -> Kind {
+    self.definition.kind
+  }
+
+  /// Returns the definition of the index.
+  pub fn definition(&self) -> &Definition {
+    &self.definition
+  }
+
+  /// Returns the root page ID of the underlying B+ tree.
+  pub fn root(&self) -> u64 {
+    self.tree.root()
+  }
+
+  /// Deallocates all pages of the index.
+  pub fn destroy(self, store: &mut Store) -> paging::Result<(), Store> {
+    self.tree.destroy(store)
+  }
+
+  /// Returns the primary keys of all rows with the given index key, in increasing order.
+  pub fn get(&self, store: &mut Store, key: &[u8]) -> paging::Result<Vec<Box<[u8]>>, Store> {
+    let entries = self.entries(store, key..=key)?;
+    Ok(entries.into_iter().map(|entry| entry.primary_key).collect())
+  }
+
+  /// Returns the `(index key, primary key)` pairs of all rows whose index keys fall into the given
+  /// range, ordered by index key and then by primary key.
+  pub fn range<'a>(
+    &self,
+    store: &mut Store,
+    range: impl ops::RangeBounds<&'a [u8]>,
+  ) -> paging::Result<Vec<map::OwnedElement>, Store> {
+    let entries = self.entries(store, range)?;
+    Ok(entries.into_iter().map(|entry| (entry.key, entry.primary_key)).collect())
+  }
+
+  /// Returns the entries of all rows whose index keys fall into the given range, including the
+  /// projections of rows in covering indices, ordered by index key and then by primary key.
+  pub fn entries<'a>(
+    &self,
+    store: &mut Store,
+    range: impl ops::RangeBounds<&'a [u8]>,
+  ) -> paging::Result<Vec<Entry>, Store> {
+    use map::{Cursor, Map};
+    // Entries with an excluded index key are all smaller than the key followed by `[0x00, 0x01]`.
+    let start = match (self.kind(), range.start_bound()) {
+      (Kind::Unique, bound) => bound.map(|key| key.to_vec()),
+      (_, ops::Bound::Included(key)) => ops::Bound::Included(entry_key(key, &[])),
+      (_, ops::Bound::Excluded(key)) => {
+        let mut start = entry_key(key, &[]);
+        *start.last_mut().unwrap() = 0x01;
+        ops::Bound::Included(start)
+      }
+      (_, ops::Bound::Unbounded) => ops::Bound::Unbounded,
+    };
+    let end = (ops::Bound::Unbounded, range.end_bound().map(|key| &key[..]));
+    let mut cursor = self.tree.lower_bound(store, start.as_ref().map(|key| &key[..]))?;
+    let mut res = Vec::new();
+    let covering = self.definition.is_covering();
+    let corrupt = || paging::Error::Corrupt { page_id: self.tree.root() };
+    while let Some((entry, value)) = cursor.next(store)? {
+      // For non-unique indices, `data` is the primary key, and `value` is the projection. For
+      // posting list indices, `data` is the first primary key of a segment.
+      let (key, data) = match self.kind() {
+        Kind::Unique => (entry.to_vec(), value),
+        Kind::NonUnique | Kind::Postings => split_entry_key(entry).ok_or_else(corrupt)?,
+      };
+      if !ops::RangeBounds::<[u8]>::contains(&end, &key[..]) {
+        break;
+      }
+      let rows = match self.kind() {
+        Kind::Unique => vec![decode_unique(data, covering).ok_or_else(corrupt)?],
+        Kind::NonUnique => vec![(data.into(), value.into())],
+        Kind::Postings => decode_postings(value, covering).ok_or_else(corrupt)?,
+      };
+      let key = Box::<[u8]>::from(key);
+      res.extend(rows.into_iter().map(|(primary_key, included)| Entry {
+        key: key.clone(),
+        primary_key,
+        included,
+      }));
+    }
+    Ok(res)
+  }
+
+  /// Returns the segment of the posting list of an index key which a primary key belongs to in a
+  /// posting list index, as its B+ tree key and its `(primary key, projection)` pairs. This is the
+  /// last segment starting at or before the primary key, or the first segment if there is none.
+  fn segment(
+    &self,
+    store: &mut Store,
+    key: &[u8],
+    primary_key: &[u8],
+  ) -> paging::Result<Option<Segment>, Store> {
+    use map::{Cursor, Map};
+    let prefix = entry_key(key, &[]);
+    let bound = entry_key(key, primary_key);
+    let mut cursor = self.tree.upper_bound(store, ops::Bound::Included(&bound))?;
+    let (entry, list) = match cursor.peek_prev(store)? {
+      Some((entry, list)) if entry.starts_with(&prefix) => (entry, list),
+      _ => match cursor.peek_next(store)? {
+        Some((entry, list)) if entry.starts_with(&prefix) => (entry, list),
+        _ => return Ok(None),
+      },
+    };
+    let corrupt = paging::Error::Corrupt { page_id: self.tree.root() };
+    let list = decode_postings(list, self.definition.is_covering()).ok_or(corrupt)?;
+    Ok(Some((entry.into(), list)))
+  }
+
+  /// Replaces a segment of the posting list of an index key, stored under the given B+ tree key
+  /// (if any), by the given pairs.
+  fn replace_segment(
+    &mut self,
+    store: &mut Store,
+    key: &[u8],
+    old: Option<&[u8]>,
+    list: &[map::OwnedElement],
+  ) -> paging::Result<(), Store> {
+    use map::Map;
+    let new = list.first().map(|(primary_key, _)| entry_key(key, primary_key));
+    if let Some(old) = old.filter(|old| Some(*old) != new.as_deref()) {
+      self.tree.remove(store, old)?;
+    }
+    if !list.is_empty() {
+      self.write_segments(store, key, list)?;
+    }
+    Ok(())
+  }
+
+  /// Writes a non-empty segment of the posting list of an index key, splitting it in halves while
+  /// its encoding is larger than the page size.
+  fn write_segments(
+    &mut self,
+    store: &mut Store,
+    key: &[u8],
+    list: &[map::OwnedElement],
+  ) -> paging::Result<(), Store> {
+    use map::Map;
+    let data = encode_postings(list, self.definition.is_covering());
+    if data.len() > store.page_size() && list.len() > 1 {
+      let (left, right) = list.split_at(list.len() / 2);
+      self.write_segments(store, key, left)?;
+      return self.write_segments(store, key, right);
+    }
+    self.tree.insert(store, &entry_key(key, &list[0].0), &data)?;
+    Ok(())
+  }
+
+  /// Returns the primary key of another row which has the same index key as the given row, if this
+  /// is a unique index and such a row exists.
+  fn conflict(
+    &self,
+    store: &mut Store,
+    key: &[u8],
+    value: &[u8],
+  ) -> paging::Result<Option<Box<[u8]>>, Store> {
+    use map::Map;
+    if self.kind() != Kind::Unique {
+      return Ok(None);
+    }
+    let Some(index_key) = self.definition.index_key(key, value) else { return Ok(None) };
+    let Some(existing) = self.tree.get(store, &index_key)? else { return Ok(None) };
+    let corrupt = paging::Error::Corrupt { page_id: self.tree.root() };
+    let (primary_key, _) =
+      decode_unique(&existing, self.definition.is_covering()).ok_or(corrupt)?;
+    Ok(Some(primary_key).filter(|primary_key| **primary_key != *key))
+  }
+
+  /// Adds the entry of a row, if it is included in the index.
+  fn insert(&mut self, store: &mut Store, key: &[u8], value: &[u8]) -> paging::Result<(), Store> {
+    match self.definition.entry(key, value) {
+      Some(entry) => self.insert_entry(store, &entry),
+      None => Ok(()),
+    }
+  }
+
+  /// Adds an entry computed from a row.
+  fn insert_entry(&mut self, store: &mut Store, entry: &Entry) -> paging::Result<(), Store> {
+    use map::Map;
+    let Entry { key, primary_key, included } = entry;
+    let covering = self.definition.is_covering();
+    match self.kind() {
+      Kind::Unique => {
+        self.tree.insert(store, key, &encode_unique(primary_key, included, covering))?
+      }
+      Kind::NonUnique => self.tree.insert(store, &entry_key(key, primary_key), included)?,
+      Kind::Postings => {
+        let (old, mut list) = match self.segment(store, key, primary_key)? {
+          Some((old, list)) => (Some(old), list),
+          None => (None, Vec::new()),
+        };
+        match list.binary_search_by(|(other, _)| other.cmp(primary_key)) {
+          Ok(i) => list[i].1 = included.clone(),
+          Err(i) => list.insert(i, (primary_key.clone(), included.clone())),
+        }
+        return self.replace_segment(store, key, old.as_deref(), &list);
+      }
+    };
+    Ok(())
+  }
+
+  /// Removes the entry of a row, if it is included in the index.
+  fn remove(&mut self, store: &mut Store, key: &[u8], value: &[u8]) -> paging::Result<(), Store> {
+    use map::Map;
+    let Some(index_key) = self.definition.index_key(key, value) else { return Ok(()) };
+    match self.kind() {
+      Kind::Unique => self.tree.remove(store, &index_key)?,
+      Kind::NonUnique => self.tree.remove(store, &entry_key(&index_key, key))?,
+      Kind::Postings => {
+        let Some((old, mut list)) = self.segment(store, &index_key, key)? else { return Ok(()) };
+        let Ok(i) = list.binary_search_by(|(primary_key, _)| (**primary_key).cmp(key)) else {
+          return Ok(());
+        };
+        list.remove(i);
+        return self.replace_segment(store, &index_key, Some(&old), &list);
+      }
+    };
+    Ok(())
+  }
+}
+
+/// A segment of a posting list, as its B+ tree key and its `(primary key, projection)` pairs.
+type Segment = (Box<[u8]>, Vec<map::OwnedElement>);
+
+/// The fill factor of B+ tree nodes when populating a new secondary index, leaving some room for
+/// later insertions without immediately splitting every node.
+const LOAD_FILL: f64 = 0.9;
+
+/// # Index loader
+///
+/// Builds the B+ tree of a new secondary index bottom-up from entries given in increasing order
+/// (see [`bplus::Loader`]). In posting list indices, consecutive entries with the same index key
+/// are packed into segments no larger than the page size.
+struct IndexLoader<Store: paging::Store> {
+  loader: bplus::Loader<Store>,
+  kind: Kind,
+  covering: bool,
+  segment: Option<PendingSegment>,
+}
+
+/// A segment of a posting list which may still be extended by the next entries.
+struct PendingSegment {
+  key: Box<[u8]>,
+  first: Box<[u8]>,
+  last: Box<[u8]>,
+  data: Vec<u8>,
+}
+
+impl<Store: paging::Store> IndexLoader<Store> {
+  /// Creates a loader for an index with the given definition.
+  fn new(store: &Store, definition: &Definition) -> Self {
+    let loader = bplus::Loader::new(store, LOAD_FILL);
+    Self { loader, kind: definition.kind(), covering: definition.is_covering(), segment: None }
+  }
+
+  /// Adds an entry after all entries added so far.
+  fn push(&mut self, store: &mut Store, entry: Entry) -> paging::Result<(), Store> {
+    let Entry { key, primary_key, included } = entry;
+    match self.kind {
+      Kind::Unique => {
+        self.loader.push(store, &key, &encode_unique(&primary_key, &included, self.covering))
+      }
+      Kind::NonUnique => self.loader.push(store, &entry_key(&key, &primary_key), &included),
+      Kind::Postings => {
+        if let Some(segment) = self.segment.as_mut().filter(|segment| segment.key == key) {
+          let len = segment.data.len();
+          encode_posting(&segment.last, &primary_key, &included, self.covering, &mut segment.data);
+          if segment.data.len() <= store.page_size() {
+            segment.last = primary_key;
+            return Ok(());
+          }
+          segment.data.truncate(len);
+        }
+        self.flush(store)?;
+        let mut data = Vec::new();
+        encode_posting(&[], &primary_key, &included, self.covering, &mut data);
+        let first = primary_key.clone();
+        self.segment = Some(PendingSegment { key, first, last: primary_key, data });
+        Ok(())
+      }
+    }
+  }
+
+  /// Adds the pending segment of a posting list index, if any.
+  fn flush(&mut self, store: &mut Store) -> paging::Result<(), Store> {
+    match self.segment.take() {
+      Some(PendingSegment { key, first, data, .. }) => {
+        self.loader.push(store, &entry_key(&key, &first), &data)
+      }
+      None => Ok(()),
+    }
+  }
+
+  /// Writes the remaining entries, returning the B+ tree.
+  fn finish(mut self, store: &mut Store) -> paging::Result<bplus::BasicTree<Store>, Store> {
+    self.flush(store)?;
+    self.loader.finish(store)
+  }
+
+  /// Abandons the loader, deallocating all pages written so far.
+  fn abort(self, store: &mut Store) -> paging::Result<(), Store> {
+    self.loader.abort(store)
+  }
+}
+
+/// Returns the B+ tree key of an index entry, given an index key and a suffix (usually the
+/// primary key).
+fn entry_key(key: &[u8], suffix: &[u8]) -> Vec<u8> {
+  let mut res = Vec::with_capacity(key.len() + suffix.len() + 2);
+  for &byte in key {
+    res.push(byte);
+    if byte == 0x00 {
+      res.push(0x01);
+    }
+  }
+  res.extend_from_slice(&[0x00, 0x00]);
+  res.extend_from_slice(suffix);
+  res
+}
+
+/// Encodes the value of an entry in a unique index.
+fn encode_unique(primary_key: &[u8], included: &[u8], covering: bool) -> Vec<u8> {
+  if !covering {
+    return primary_key.to_vec();
+  }
+  let mut res = Vec::new();
+  prefix_varint::encode(primary_key.len() as u64, &mut res);
+  res.extend_from_slice(primary_key);
+  res.extend_from_slice(included);
+  res
+}
+
+/// Decodes the value of an entry in a unique index into the primary key and projection.
+fn decode_unique(mut data: &[u8], covering: bool) -> Option<map::OwnedElement> {
+  if !covering {
+    return Some((data.into(), Box::default()));
+  }
+  let len = usize::try_from(paging::take_varint(&mut data)?).ok()?;
+  let primary_key = paging::take_bytes(&mut data, len)?;
+  Some((primary_key.into(), data.into()))
+}
+
+/// Encodes a posting list sorted by primary keys, storing each primary key as a delta from the
+/// previous one. Projections are only stored in covering indices.
+fn encode_postings(list: &[map::OwnedElement], covering: bool) -> Vec<u8> {
+  let mut res = Vec::new();
+  let mut prev: &[u8] = &[];
+  for (key, included) in list {
+    encode_posting(prev, key, included, covering, &mut res);
+    prev = key;
+  }
+  res
+}
+
+/// Appends a primary key and projection to an encoded posting list, given the previous primary key.
+fn encode_posting(prev: &[u8], key: &[u8], included: &[u8], covering: bool, res: &mut Vec<u8>) {
+  let shared = prev.iter().zip(key.iter()).take_while(|(a, b)| a == b).count();
+  prefix_varint::encode(shared as u64, res);
+  prefix_varint::encode((key.len() - shared) as u64, res);
+  res.extend_from_slice(&key[shared..]);
+  if covering {
+    prefix_varint::encode(included.len() as u64, res);
+    res.extend_from_slice(included);
+  }
+}
+
+/// Decodes a posting list into `(primary key, projection)` pairs.
+fn decode_postings(mut data: &[u8], covering: bool) -> Option<Vec<map::OwnedElement>> {
+  let mut res = Vec::<map::OwnedElement>::new();
+  while !data.is_empty() {
+    let shared = usize::try_from(paging::take_varint(&mut data)?).ok()?;
+    let len = usize::try_from(paging::take_varint(&mut data)?).ok()?;
+    let prev = res.last().map_or(&[][..], |(prev, _)| &prev[..]);
+    let suffix = paging::take_bytes(&mut data, len)?;
+    let key = [prev.get(..shared)?, suffix].concat().into();
+    let included = match covering {
+      true => {
+        let len = usize::try_from(paging::take_varint(&mut data)?).ok()?;
+        paging::take_bytes(&mut data, len)?.into()
+      }
+      false => Box::default(),
+    };
+    res.push((key, included));
+  }
+  Some(res)
+}
+
+/// Splits the B+ tree key of an index entry into the index key and the primary key.
+fn split_entry_key(entry: &[u8]) -> Option<(Vec<u8>, &[u8])> {
+  let mut key = Vec::new();
+  let mut rest = entry;
+  loop {
+    rest = match rest {
+      [0x00, 0x00, primary_key @ ..] => return Some((key, primary_key)),
+      [0x00, 0x01, tail @ ..] => {
+        key.push(0x00);
+        tail
+      }
+      [0x00, ..] | [] => return None,
+      [byte, tail @ ..] => {
+        key.push(*byte);
+        tail
+      }
+    }
+  }
+}
+
+/// # Table
+///
+/// A primary index together with its secondary indices. All modifications made through a table
+/// update the secondary indices in the same store, so that they are committed in the same
+/// transaction as the primary index.
+///
+/// Rows are checked against the constraints of all secondary indices before anything is written,
+/// so a row which violates them leaves the table unchanged. If a modifying method returns a storage
+/// error, however, the secondary indices may be partially updated, and the enclosing transaction
+/// should be rolled back.
+pub struct Table<Store: paging::Store, Primary: map::Map<Store>> {
+  primary: Primary,
+  indices: Vec<Index<Store>>,
+}
+
+impl<Store: paging::Store, Primary: map::Map<Store>> Table<Store, Primary> {
+  /// Creates a table from a primary index and its (already populated) secondary indices.
+  pub fn new(primary: Primary, indices: Vec<Index<Store>>) -> Self {
+    Self { primary, indices }
+  }
+
+  /// Returns the primary index.
+  pub fn primary(&self) -> &Primary {
+    &self.primary
+  }
+
+  /// Returns the secondary indices.
+  pub fn indices(&self) -> &[Index<Store>] {
+    &self.indices
+  }
+
+  /// Returns the secondary index with the given name.
+  pub fn index(&self, name: &str) -> Option<&Index<Store>> {
+    self.indices.iter().find(|index| index.name() == name)
+  }
+
+  /// Creates a secondary index and populates it with entries for all existing rows included in
+  /// it, building the B+ tree bottom-up (see [`bplus::Loader`]). If the index is unique and two
+  /// included rows share the same index key, nothing is written.
+  pub fn create_index(
+    &mut self,
+    store: &mut Store,
+    name: &str,
+    definition: Definition,
+  ) -> Result<&Index<Store>, Store> {
+    use map::Cursor;
+    let mut cursor = self.primary.lower_bound(store, ops::Bound::Unbounded)?;
+    let mut entries = Vec::new();
+    while let Some((key, value)) = cursor.next(store)? {
+      entries.extend(definition.entry(key, value));
+    }
+    entries.sort();
+    if definition.kind() == Kind::Unique {
+      if let Some(pair) = entries.windows(2).find(|pair| pair[0].key == pair[1].key) {
+        let primary_key = pair[1].primary_key.clone();
+        return Err(Error::UniqueViolation { index: name.into(), primary_key });
+      }
+    }
+    let mut loader = IndexLoader::new(store, &definition);
+    for entry in entries {
+      loader.push(store, entry)?;
+    }
+    let tree = loader.finish(store)?;
+    self.indices.push(Index { name: name.into(), definition, tree });
+    Ok(self.indices.last().unwrap())
+  }
+
+  /// Returns a copy of the value corresponding to the primary key.
+  pub fn get(&self, store: &mut Store, key: &[u8]) -> paging::Result<Option<Box<[u8]>>, Store> {
+    self.primary.get(store, key)
+  }
+
+  /// Inserts or updates a row, updating all secondary indices. Returns whether the primary key was
+  /// present.
+  ///
+  /// Fails with [`Error::UniqueViolation`] if a unique index already contains the index key of the
+  /// row for another primary key.
+  pub fn insert(&mut self, store: &mut Store, key: &[u8], value: &[u8]) -> Result<bool, Store> {
+    let old = self.primary.get(store, key)?;
+    for index in &self.indices {
+      if let Some(conflict) = index.conflict(store, key, value)? {
+        return Err(Error::UniqueViolation { index: index.name.clone(), primary_key: conflict });
+      }
+    }
+    for index in &mut self.indices {
+      if let Some(old) = &old {
+        index.remove(store, key, old)?;
+      }
+      index.insert(store, key, value)?;
+    }
+    self.primary.insert(store, key, value)?;
+    Ok(old.is_some())
+  }
+
+  /// Removes a row, updating all secondary indices. Returns whether the primary key was present.
+  pub fn remove(&mut self, store: &mut Store, key: &[u8]) -> paging::Result<bool, Store> {
+    let Some(old) = self.primary.get(store, key)? else { return Ok(false) };
+    for index in &mut self.indices {
+      index.remove(store, key, &old)?;
+    }
+    self.primary.remove(store, key)?;
+    Ok(true)
+  }
+}
+
+impl<Store: paging::Store, Policy: prolly::Policy, Aggregate: prolly::Aggregate>
+  Table<Store, prolly::BasicTree<Store, Policy, Aggregate>>
+{
+  /// Starts building a secondary index from the current version of the primary index, without
+  /// modifying the table. See [`IndexBuild`].
+  pub fn build_index(
+    &self,
+    store: &mut Store,
+    name: &str,
+    definition: Definition,
+  ) -> paging::Result<IndexBuild<Store>, Store> {
+    let snapshot = self.primary.root();
+    let scan = prolly::Diff::new(store, None, snapshot)?;
+    let loader = IndexLoader::new(store, &definition);
+    Ok(IndexBuild {
+      snapshot,
+      _pin: self.primary.pin(),
+      scan,
+      scanned: false,
+      batch: Vec::new(),
+      batch_len: 0,
+      batch_size: BUILD_BATCH_SIZE,
+      runs: Vec::new(),
+      heads: collections::BinaryHeap::new(),
+      prev: None,
+      name: name.into(),
+      definition,
+      loader,
+    })
+  }
+
+  /// Completes a build started by [`Table::build_index`], applying the changes made to the table
+  /// since it started, and attaches the index to the table.
+  ///
+  /// An existing index with the same name is replaced and returned, with its pages still
+  /// allocated. If it is registered in the schema table, the new index should be swapped in
+  /// through [`Catalog::replace_index`] in the same transaction, which deallocates them; otherwise
+  /// they should be deallocated through [`Index::destroy`].
+  ///
+  /// [`Catalog::replace_index`]: super::catalog::Catalog::replace_index
+  ///
+  /// If the index is unique and two rows share the same index key, the pages of the new index are
+  /// deallocated and the table is left unchanged.
+  pub fn finish_index(
+    &mut self,
+    store: &mut Store,
+    mut build: IndexBuild<Store>,
+  ) -> Result<Option<Index<Store>>, Store> {
+    loop {
+      match build.step(store, usize::MAX) {
+        Ok(true) => break,
+        Ok(false) => {}
+        Err(error) => {
+          build.abort(store)?;
+          return Err(error);
+        }
+      }
+    }
+    // The rest of `build`, including the pin on the primary index, is dropped on return.
+    let IndexBuild { snapshot, name, definition, loader, .. } = build;
+    let mut index = Index { name, definition, tree: loader.finish(store)? };
+    if let Err(error) = self.catch_up(store, &mut index, snapshot) {
+      index.destroy(store)?;
+      return Err(error);
+    }
+    match self.indices.iter().position(|existing| existing.name == index.name) {
+      Some(i) => Ok(Some(mem::replace(&mut self.indices[i], index))),
+      None => {
+        self.indices.push(index);
+        Ok(None)
+      }
+    }
+  }
+
+  /// Applies the changes made to the primary index since the given version to a new index.
+  fn catch_up(
+    &self,
+    store: &mut Store,
+    index: &mut Index<Store>,
+    snapshot: Option<u64>,
+  ) -> Result<(), Store> {
+    let mut diff = prolly::Diff::new(store, snapshot, self.primary.root())?;
+    let mut changes = Vec::new();
+    while let Some(change) = diff.next(store)? {
+      changes.push(change);
+    }
+    // Remove all outdated entries first, so that rows may exchange unique index keys.
+    for change in &changes {
+      if let Some(old) = &change.old {
+        index.remove(store, &change.key, old)?;
+      }
+    }
+    for change in &changes {
+      if let Some(new) = &change.new {
+        if let Some(conflict) = index.conflict(store, &change.key, new)? {
+          return Err(Error::UniqueViolation { index: index.name.clone(), primary_key: conflict });
+        }
+        index.insert(store, &change.key, new)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+/// The default number of bytes of entries sorted in memory at a time by an online index build.
+const BUILD_BATCH_SIZE: usize = 4 << 20;
+
+/// # Online index build
+///
+/// Builds or rebuilds a secondary index of a table whose primary index is a Prolly tree, in steps
+/// between which the table may be modified as usual. The build goes through three phases:
+///
+/// 1. Scanning the version of the primary index at the start of the build, which stays unchanged
+///    since Prolly trees are copy-on-write, and collecting the entries of the index in batches.
+///    Each full batch is sorted and written into temporary overflow pages as a sorted run.
+/// 2. Merging the sorted runs, and loading the entries into a new B+ tree bottom-up (see
+///    [`bplus::Loader`]).
+/// 3. Catching up on rows changed since the start of the build, by diffing the two versions of the
+///    primary index (see [`prolly::Diff`]), and attaching the index to the table.
+///
+/// The first two phases are carried out by [`IndexBuild::step`], and do not modify the table. The
+/// last one is carried out by [`Table::finish_index`], and usually takes much less time. At most
+/// one batch of entries (see [`IndexBuild::batch_size`]) is kept in memory during the scan, and
+/// about one page of entries from each run during the merge.
+///
+/// The primary index is pinned (see [`prolly::BasicTree::pin`]) until the build is finished or
+/// aborted, so that the scanned version stays readable.
+pub struct IndexBuild<Store: paging::Store> {
+  snapshot: Option<u64>,
+  _pin: prolly::Pin,
+  scan: prolly::Diff,
+  scanned: bool,
+  batch: Vec<Entry>,
+  batch_len: usize,
+  batch_size: usize,
+  runs: Vec<Run>,
+  heads: collections::BinaryHeap<cmp::Reverse<(Entry, usize)>>,
+  prev: Option<Box<[u8]>>,
+  name: Box<str>,
+  definition: Definition,
+  loader: IndexLoader<Store>,
+}
+
+impl<Store: paging::Store> IndexBuild<Store> {
+  /// Sets the total size in bytes of the index keys, primary keys and projections of the entries
+  /// sorted in memory at a time, which is 4 MiB by default. This should be set before any steps.
+  pub fn batch_size(mut self, batch_size: usize) -> Self {
+    self.batch_size = batch_size;
+    self
+  }
+
+  /// Scans or loads up to `limit` rows. Returns whether both phases are complete.
+  ///
+  /// Fails with [`Error::UniqueViolation`] while loading if the index is unique and two rows share
+  /// the same index key. The build should then be aborted.
+  pub fn step(&mut self, store: &mut Store, limit: usize) -> Result<bool, Store> {
+    for _ in 0..limit {
+      if !self.scanned {
+        let Some(row) = self.scan.next(store)? else {
+          // The last batch is merged directly from memory.
+          self.batch.sort();
+          let entries = mem::take(&mut self.batch).into();
+          self.runs.push(Run { entries, chunks: collections::VecDeque::new() });
+          for (i, run) in self.runs.iter_mut().enumerate() {
+            if let Some(entry) = run.next(store)? {
+              self.heads.push(cmp::Reverse((entry, i)));
+            }
+          }
+          self.scanned = true;
+          continue;
+        };
+        let value = row.new.as_deref().unwrap_or_default();
+        if let Some(entry) = self.definition.entry(&row.key, value) {
+          self.batch_len += entry.key.len() + entry.primary_key.len() + entry.included.len();
+          self.batch.push(entry);
+          if self.batch_len >= self.batch_size {
+            self.batch.sort();
+            let run = Run::write(store, mem::take(&mut self.batch))?;
+            self.runs.push(run);
+            self.batch_len = 0;
+          }
+        }
+      } else if let Some(cmp::Reverse((entry, i))) = self.heads.pop() {
+        if let Some(next) = self.runs[i].next(store)? {
+          self.heads.push(cmp::Reverse((next, i)));
+        }
+        if self.definition.kind() == Kind::Unique && self.prev.as_ref() == Some(&entry.key) {
+          let primary_key = entry.primary_key;
+          return Err(Error::UniqueViolation { index: self.name.clone(), primary_key });
+        }
+        self.prev = Some(entry.key.clone());
+        self.loader.push(store, entry)?;
+      } else {
+        return Ok(true);
+      }
+    }
+    Ok(self.scanned && self.heads.is_empty())
+  }
+
+  /// Abandons the build, deallocating the pages of the sorted runs and the new index.
+  pub fn abort(self, store: &mut Store) -> paging::Result<(), Store> {
+    for run in self.runs {
+      run.free(store)?;
+    }
+    self.loader.abort(store)
+  }
+}
+
+/// A sorted run of entries written by an online index build. Entries are read from overflow pages
+/// one chunk of about a page at a time, and each chunk is deallocated once read.
+struct Run {
+  entries: collections::VecDeque<Entry>,
+  chunks: collections::VecDeque<(u64, u64)>,
+}
+
+impl Run {
+  /// Writes sorted entries into chunks of overflow pages, given by their first page IDs and
+  /// lengths.
+  fn write<Store: paging::Store>(
+    store: &mut Store,
+    entries: Vec<Entry>,
+  ) -> paging::Result<Self, Store> {
+    let mut chunks = collections::VecDeque::new();
+    let mut data = Vec::new();
+    for (i, Entry { key, primary_key, included }) in entries.iter().enumerate() {
+      for part in [key, primary_key, included] {
+        prefix_varint::encode(part.len() as u64, &mut data);
+        data.extend_from_slice(part);
+      }
+      if data.len() >= store.page_size() || i + 1 == entries.len() {
+        chunks.push_back((paging::write_overflow(store, &data)?, data.len() as u64));
+        data.clear();
+      }
+    }
+    Ok(Self { entries: collections::VecDeque::new(), chunks })
+  }
+
+  /// Returns the next entry, reading the next chunk if necessary.
+  fn next<Store: paging::Store>(
+    &mut self,
+    store: &mut Store,
+  ) -> paging::Result<Option<Entry>, Store> {
+    if self.entries.is_empty() {
+      let Some((page_id, len)) = self.chunks.pop_front() else { return Ok(None) };
+      let data = paging::read_overflow(store, page_id, len)?;
+      paging::free_overflow(store, page_id)?;
+      let mut rest = &data[..];
+      while !rest.is_empty() {
+        let mut take = || {
+          let len = usize::try_from(paging::take_varint(&mut rest)?).ok()?;
+          Some(Box::from(paging::take_bytes(&mut rest, len)?))
+        };
+        let (Some(key), Some(primary_key), Some(included)) = (take(), take(), take()) else {
+          return Err(paging::Error::Corrupt { page_id });
+        };
+        self.entries.push_back(Entry { key, primary_key, included });
+      }
+    }
+    Ok(self.entries.pop_front())
+  }
+
+  /// Deallocates the chunks which have not been read yet.
+  fn free<Store: paging::Store>(self, store: &mut Store) -> paging::Result<(), Store> {
+    for (page_id, _) in self.chunks {
+      paging::free_overflow(store, page_id)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::storage::prolly;
+  use crate::storage::testing::{open_store, TestPolicy, TestStore};
+  use rand::rngs::StdRng;
+  use rand::{Rng, SeedableRng};
+  use std::collections;
+
+  type TestTable = Table<TestStore, prolly::BasicTree<TestStore, TestPolicy>>;
+
+  /// Indexes rows by the first byte of their values.
+  fn first_byte(_: &[u8], value: &[u8]) -> Box<[u8]> {
+    value[..1].into()
+  }
+
+  /// Indexes rows by their whole values.
+  fn whole(_: &[u8], value: &[u8]) -> Box<[u8]> {
+    value.into()
+  }
+
+  #[test]
+  fn test_entry_key() {
+    let keys: Vec<&[u8]> = vec![b"", b"\x00", b"\x00\x00", b"\x00\x01", b"\x01", b"a", b"a\x00b"];
+    for (i, key) in keys.iter().enumerate() {
+      let entry = entry_key(key, b"\x00pk");
+      assert_eq!(split_entry_key(&entry), Some((key.to_vec(), &b"\x00pk"[..])));
+      // The order of index keys is preserved regardless of the primary key.
+      for other in &keys[i + 1..] {
+        assert!(entry < entry_key(other, b""));
+      }
+    }
+    assert_eq!(split_entry_key(b"a\x00\x02"), None);
+    assert_eq!(split_entry_key(b"a"), None);
+  }
+
+  #[test]
+  fn test_postings_encoding() {
+    let keys: [&[u8]; 5] = [b"", b"a", b"abc", b"abd", b"b"];
+    let list = keys.map(|key| (key.into(), Box::default())).to_vec();
+    let encoded = encode_postings(&list, false);
+    assert_eq!(decode_postings(&encoded, false), Some(list));
+    assert_eq!(decode_postings(&[], false), Some(Vec::new()));
+    // Projections are stored after each primary key in covering indices.
+    let list = keys.map(|key| (key.into(), [key, b"!"].concat().into())).to_vec();
+    let encoded = encode_postings(&list, true);
+    assert_eq!(decode_postings(&encoded, true), Some(list));
+    // Shared prefixes cannot be longer than the previous key.
+    let mut invalid = Vec::new();
+    prefix_varint::encode(1, &mut invalid);
+    prefix_varint::encode(0, &mut invalid);
+    assert_eq!(decode_postings(&invalid, false), None);
+  }
+
+  #[test]
+  fn test_long_postings() {
+    use map::{Cursor, Map};
+    let mut store = open_store();
+    let mut table = TestTable::new(prolly::BasicTree::new(TestPolicy), Vec::new());
+    table
+      .create_index(&mut store, "first_byte", Definition::new(Kind::Postings, first_byte))
+      .unwrap();
+    // Rows are inserted in decreasing order, so that the first segment keeps changing.
+    let key = |i: u32| format!("key{:06}", i).into_bytes();
+    for i in (0..1000).rev() {
+      table.insert(&mut store, &key(i), &[b'x', (i % 2) as u8]).unwrap();
+    }
+    let index = table.index("first_byte").unwrap();
+    let expected = (0..1000).map(|i| key(i).into()).collect::<Vec<Box<[u8]>>>();
+    assert_eq!(index.get(&mut store, b"x").unwrap(), expected);
+    // The posting list is split into segments which fit into a page.
+    let mut cursor = index.tree.lower_bound(&mut store, ops::Bound::Unbounded).unwrap();
+    let mut segments = 0;
+    while let Some((_, segment)) = cursor.next(&mut store).unwrap() {
+      assert!(segment.len() <= 512);
+      segments += 1;
+    }
+    assert!(segments > 5);
+    for i in (0..1000).step_by(2) {
+      table.insert(&mut store, &key(i), b"y").unwrap();
+    }
+    let index = table.index("first_byte").unwrap();
+    let expected = (1..1000).step_by(2).map(|i| key(i).into()).collect::<Vec<Box<[u8]>>>();
+    assert_eq!(index.get(&mut store, b"x").unwrap(), expected);
+    assert_eq!(index.range(&mut store, ..).unwrap().len(), 1000);
+  }
+
+  #[test]
+  fn test_maintenance() {
+    let mut store = open_store();
+    let mut table = TestTable::new(prolly::BasicTree::new(TestPolicy), Vec::new());
+    table.insert(&mut store, b"alice", b"a1").unwrap();
+    table.insert(&mut store, b"bob", b"b1").unwrap();
+    // Indices created on existing data are populated.
+    table
+      .create_index(&mut store, "first_byte", Definition::new(Kind::NonUnique, first_byte))
+      .unwrap();
+    table.insert(&mut store, b"carol", b"a2").unwrap();
+    let index = table.index("first_byte").unwrap();
+    let alice_carol = vec![b"alice"[..].into(), b"carol"[..].into()];
+    assert_eq!(index.get(&mut store, b"a").unwrap(), alice_carol);
+    // Updates move entries between index keys.
+    table.insert(&mut store, b"alice", b"b2").unwrap();
+    let index = table.index("first_byte").unwrap();
+    assert_eq!(index.get(&mut store, b"a").unwrap(), vec![b"carol"[..].into()]);
+    let alice_bob = vec![b"alice"[..].into(), b"bob"[..].into()];
+    assert_eq!(index.get(&mut store, b"b").unwrap(), alice_bob);
+    assert!(table.remove(&mut store, b"bob").unwrap());
+    assert!(!table.remove(&mut store, b"bob").unwrap());
+    let index = table.index("first_byte").unwrap();
+    assert_eq!(index.get(&mut store, b"b").unwrap(), vec![b"alice"[..].into()]);
+    assert!(index.get(&mut store, b"c").unwrap().is_empty());
+    // Empty primary keys and index keys are allowed.
+    table.insert(&mut store, b"", b"\x00").unwrap();
+    let index = table.index("first_byte").unwrap();
+    assert_eq!(index.get(&mut store, b"\x00").unwrap(), vec![b""[..].into()]);
+  }
+
+  #[test]
+  fn test_unique() {
+    let mut store = open_store();
+    let mut table = TestTable::new(prolly::BasicTree::new(TestPolicy), Vec::new());
+    table
+      .create_index(&mut store, "first_byte", Definition::new(Kind::NonUnique, first_byte))
+      .unwrap();
+    table.create_index(&mut store, "email", Definition::new(Kind::Unique, whole)).unwrap();
+    table.insert(&mut store, b"alice", b"alice@example.com").unwrap();
+    table.insert(&mut store, b"bob", b"bob@example.com").unwrap();
+    // Rows may keep their own index keys.
+    table.insert(&mut store, b"alice", b"alice@example.com").unwrap();
+    let violation = |res| match res {
+      Err(Error::UniqueViolation { index, primary_key }) => Some((index, primary_key)),
+      _ => None,
+    };
+    let expected = Some(("email".into(), b"alice"[..].into()));
+    assert_eq!(violation(table.insert(&mut store, b"carol", b"alice@example.com")), expected);
+    assert_eq!(violation(table.insert(&mut store, b"bob", b"alice@example.com")), expected);
+    let error = table.insert(&mut store, b"carol", b"alice@example.com").unwrap_err();
+    let message =
+      "duplicate key in unique index email, conflicting with row with primary key alice";
+    assert_eq!(error.to_string(), message);
+    // Nothing is written on violations.
+    assert_eq!(table.get(&mut store, b"carol").unwrap(), None);
+    assert_eq!(table.get(&mut store, b"bob").unwrap().as_deref(), Some(&b"bob@example.com"[..]));
+    let index = table.index("first_byte").unwrap();
+    assert_eq!(index.get(&mut store, b"a").unwrap(), vec![b"alice"[..].into()]);
+    let index = table.index("email").unwrap();
+    assert_eq!(index.get(&mut store, b"alice@example.com").unwrap(), vec![b"alice"[..].into()]);
+    // Index keys become available once they are no longer used.
+    table.remove(&mut store, b"alice").unwrap();
+    table.insert(&mut store, b"carol", b"alice@example.com").unwrap();
+    let index = table.index("email").unwrap();
+    assert_eq!(index.get(&mut store, b"alice@example.com").unwrap(), vec![b"carol"[..].into()]);
+  }
+
+  #[test]
+  fn test_create_unique_violation() {
+    let mut store = open_store();
+    let mut table = TestTable::new(prolly::BasicTree::new(TestPolicy), Vec::new());
+    table.insert(&mut store, b"alice", b"a1").unwrap();
+    table.insert(&mut store, b"bob", b"b1").unwrap();
+    table.insert(&mut store, b"carol", b"a2").unwrap();
+    let page_count = store.page_count();
+    let res =
+      table.create_index(&mut store, "first_byte", Definition::new(Kind::Unique, first_byte));
+    match res {
+      Err(Error::UniqueViolation { index, primary_key }) => {
+        assert_eq!((&*index, &*primary_key), ("first_byte", &b"carol"[..]));
+      }
+      _ => panic!("expected unique violation"),
+    }
+    assert!(table.indices().is_empty());
+    assert_eq!(store.page_count(), page_count);
+  }
+
+  #[test]
+  fn test_partial_and_expression() {
+    // Rows are `status:email`, and active rows have unique emails regardless of case.
+    let mut store = open_store();
+    let mut table = TestTable::new(prolly::BasicTree::new(TestPolicy), Vec::new());
+    let email = |_: &[u8], value: &[u8]| -> Box<[u8]> { value[2..].to_ascii_lowercase().into() };
+    let active = |_: &[u8], value: &[u8]| value.starts_with(b"a:");
+    table.insert(&mut store, b"alice", b"a:Alice@example.com").unwrap();
+    table.insert(&mut store, b"bob", b"i:ALICE@example.com").unwrap();
+    let definition = Definition::partial(Kind::Unique, email, active);
+    assert!(definition.is_partial());
+    assert_eq!(definition.index_key(b"bob", b"i:ALICE@example.com"), None);
+    table.create_index(&mut store, "email", definition).unwrap();
+    let index = table.index("email").unwrap();
+    let expected = vec![(b"alice@example.com"[..].into(), b"alice"[..].into())];
+    assert_eq!(index.range(&mut store, ..).unwrap(), expected);
+    // Excluded rows do not conflict with included ones.
+    table.insert(&mut store, b"carol", b"i:alice@EXAMPLE.com").unwrap();
+    let res = table.insert(&mut store, b"carol", b"a:alice@EXAMPLE.com");
+    assert!(matches!(res, Err(Error::UniqueViolation { .. })));
+    // Rows enter and leave the index when they start or stop matching the filter.
+    table.insert(&mut store, b"alice", b"i:Alice@example.com").unwrap();
+    table.insert(&mut store, b"bob", b"a:ALICE@example.com").unwrap();
+    let index = table.index("email").unwrap();
+    assert_eq!(index.get(&mut store, b"alice@example.com").unwrap(), vec![b"bob"[..].into()]);
+    table.remove(&mut store, b"bob").unwrap();
+    table.remove(&mut store, b"alice").unwrap();
+    let index = table.index("email").unwrap();
+    assert!(index.range(&mut store, ..).unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_covering() {
+    // Index rows by their first bytes, including the next two bytes.
+    let projection = |_: &[u8], value: &[u8]| -> Box<[u8]> { value[1..3].into() };
+    for kind in [Kind::NonUnique, Kind::Unique, Kind::Postings] {
+      let mut rng = StdRng::seed_from_u64(2);
+      let mut store = open_store();
+      let mut table = TestTable::new(prolly::BasicTree::new(TestPolicy), Vec::new());
+      let mut model = collections::BTreeMap::<Vec<u8>, Vec<u8>>::new();
+      for _ in 0..500 {
+        let key = format!("key{:04}", rng.gen_range(0..100)).into_bytes();
+        let value = vec![rng.gen_range(0..=255), rng.gen_range(0..4), rng.gen_range(0..4), 0];
+        if rng.gen_bool(0.3) {
+          table.remove(&mut store, &key).unwrap();
+          model.remove(&key);
+        } else if table.insert(&mut store, &key, &value).is_ok() {
+          model.insert(key, value);
+        }
+        if table.indices().is_empty() && model.len() > 20 {
+          let definition = Definition::new(kind, first_byte).include(projection);
+          assert!(definition.is_covering());
+          table.create_index(&mut store, "covering", definition).unwrap();
+        }
+      }
+      let mut expected = model
+        .iter()
+        .map(|(key, value)| Entry {
+          key: value[..1].into(),
+          primary_key: key[..].into(),
+          included: value[1..3].into(),
+        })
+        .collect::<Vec<_>>();
+      expected.sort();
+      let index = table.index("covering").unwrap();
+      assert_eq!(index.entries(&mut store, ..).unwrap(), expected);
+      let (low, high) = (&[100u8][..], &[200u8][..]);
+      let filtered = expected.iter().filter(|entry| *entry.key >= *low && *entry.key < *high);
+      assert_eq!(
+        index.entries(&mut store, low..high).unwrap(),
+        filtered.cloned().collect::<Vec<_>>()
+      );
+    }
+  }
+
+  #[test]
+  fn test_online_build() {
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut store = open_store();
+    let mut table = TestTable::new(prolly::BasicTree::new(TestPolicy), Vec::new());
+    let mut model = collections::BTreeMap::<Vec<u8>, Vec<u8>>::new();
+    let mut modify = |store: &mut TestStore, table: &mut TestTable, rng: &mut StdRng| {
+      let key = format!("key{:04}", rng.gen_range(0..400)).into_bytes();
+      if rng.gen_bool(0.6) {
+        let value = vec![rng.gen_range(0..8); rng.gen_range(1..100)];
+        model.insert(key.clone(), value.clone());
+        table.insert(store, &key, &value).unwrap();
+      } else {
+        model.remove(&key);
+        table.remove(store, &key).unwrap();
+      }
+      model.iter().map(|(key, value)| (value[..1].into(), key[..].into())).collect::<Vec<_>>()
+    };
+    for _ in 0..300 {
+      modify(&mut store, &mut table, &mut rng);
+    }
+    for round in 0..3 {
+      // Build (or rebuild) the index while modifying the table between steps, sorting entries in
+      // several runs in earlier rounds.
+      let definition = Definition::new(Kind::NonUnique, first_byte);
+      let build = table.build_index(&mut store, "first_byte", definition).unwrap();
+      let mut build = build.batch_size([300, 1500, BUILD_BATCH_SIZE][round]);
+      while !build.step(&mut store, 20).unwrap() {
+        modify(&mut store, &mut table, &mut rng);
+      }
+      let expected = modify(&mut store, &mut table, &mut rng);
+      let replaced = table.finish_index(&mut store, build).unwrap();
+      assert_eq!(replaced.is_some(), round > 0);
+      replaced.into_iter().try_for_each(|index| index.destroy(&mut store)).unwrap();
+      let mut expected = expected.into_iter().collect::<Vec<map::OwnedElement>>();
+      expected.sort();
+      let index = table.index("first_byte").unwrap();
+      assert_eq!(index.range(&mut store, ..).unwrap(), expected);
+      assert_eq!(table.indices().len(), 1);
+    }
+  }
+
+  #[test]
+  fn test_online_build_unique_violation() {
+    use paging::Store;
+    let mut store = open_store();
+    let mut table = TestTable::new(prolly::BasicTree::new(TestPolicy), Vec::new());
+    table.insert(&mut store, b"alice", b"alice@example.com").unwrap();
+    table.insert(&mut store, b"bob", b"bob@example.com").unwrap();
+    // Rows exchanging index keys during the build are fine.
+    let definition = Definition::new(Kind::Unique, whole);
+    let build = table.build_index(&mut store, "email", definition).unwrap();
+    table.insert(&mut store, b"alice", b"bob@example.com").unwrap();
+    table.insert(&mut store, b"bob", b"alice@example.com").unwrap();
+    assert!(table.finish_index(&mut store, build).unwrap().is_none());
+    let index = table.index("email").unwrap();
+    assert_eq!(index.get(&mut store, b"alice@example.com").unwrap(), vec![b"bob"[..].into()]);
+    // Conflicts introduced during the build leave the table unchanged.
+    let definition = Definition::new(Kind::Unique, first_byte);
+    let mut build = table.build_index(&mut store, "first_byte", definition).unwrap();
+    assert!(build.step(&mut store, 100).unwrap());
+    table.insert(&mut store, b"carol", b"bcarol@example.com").unwrap();
+    match table.finish_index(&mut store, build) {
+      Err(Error::UniqueViolation { index, primary_key }) => {
+        assert_eq!((&*index, &*primary_key), ("first_byte", &b"alice"[..]));
+      }
+      _ => panic!("expected unique violation"),
+    }
+    assert!(table.index("first_byte").is_none());
+    // Conflicts in the scanned version are reported by steps. Aborting deallocates all pages of
+    // the build, whether or not the sorted runs have been merged.
+    for limit in [2, 100] {
+      let page_count = store.page_count();
+      let definition = Definition::new(Kind::Unique, first_byte);
+      let build = table.build_index(&mut store, "first_byte", definition).unwrap();
+      let mut build = build.batch_size(1);
+      match limit {
+        2 => assert!(!build.step(&mut store, limit).unwrap()),
+        _ => assert!(matches!(build.step(&mut store, limit), Err(Error::UniqueViolation { .. }))),
+      }
+      let grown = store.page_count();
+      assert!(grown > page_count);
+      build.abort(&mut store).unwrap();
+      for _ in page_count..grown {
+        store.allocate().unwrap();
+      }
+      assert_eq!(store.page_count(), grown);
+    }
+  }
+
+  #[test]
+  fn test_random_operations() {
+    // Test against a reference implementation.
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut store = open_store();
+    let mut table = TestTable::new(prolly::BasicTree::new(TestPolicy), Vec::new());
+    table
+      .create_index(&mut store, "first_byte", Definition::new(Kind::NonUnique, first_byte))
+      .unwrap();
+    table.create_index(&mut store, "whole", Definition::new(Kind::Unique, whole)).unwrap();
+    table
+      .create_index(&mut store, "postings", Definition::new(Kind::Postings, first_byte))
+      .unwrap();
+    let mut model = collections::BTreeMap::<Vec<u8>, Vec<u8>>::new();
+    for _ in 0..2000 {
+      let key = format!("key{:04}", rng.gen_range(0..300)).into_bytes();
+      if rng.gen_bool(0.6) {
+        // Use zero bytes in index keys to exercise escaping.
+        let value = vec![rng.gen_range(0..4); rng.gen_range(1..20)];
+        match model.iter().find(|(k, v)| **k != key && **v == value) {
+          Some((conflict, _)) => match table.insert(&mut store, &key, &value) {
+            Err(Error::UniqueViolation { index, primary_key }) => {
+              assert_eq!((&*index, &*primary_key), ("whole", &conflict[..]));
+            }
+            _ => panic!("expected unique violation"),
+          },
+          None => {
+            let present = model.insert(key.clone(), value.clone()).is_some();
+            assert_eq!(table.insert(&mut store, &key, &value).unwrap(), present);
+          }
+        }
+      } else {
+        assert_eq!(table.remove(&mut store, &key).unwrap(), model.remove(&key).is_some());
+      }
+    }
+    let index = table.index("whole").unwrap();
+    let expected = model.iter().map(|(key, value)| (value[..].into(), key[..].into()));
+    let mut expected = expected.collect::<Vec<map::OwnedElement>>();
+    expected.sort();
+    assert_eq!(index.range(&mut store, ..).unwrap(), expected);
+    let index = table.index("first_byte").unwrap();
+    let mut expected = model
+      .iter()
+      .map(|(key, value)| (value[..1].into(), key[..].into()))
+      .collect::<Vec<map::OwnedElement>>();
+    expected.sort();
+    assert_eq!(index.range(&mut store, ..).unwrap(), expected);
+    let (low, high) = (&[1u8][..], &[2u8][..]);
+    let filtered =
+      expected.iter().filter(|(k, _)| **k > *low && **k <= *high).cloned().collect::<Vec<_>>();
+    let bounds = (ops::Bound::Excluded(low), ops::Bound::Included(high));
+    assert_eq!(index.range(&mut store, bounds).unwrap(), filtered);
+    // Posting list indices contain the same entries.
+    let index = table.index("postings").unwrap();
+    assert_eq!(index.range(&mut store, ..).unwrap(), expected);
+    assert_eq!(index.range(&mut store, bounds).unwrap(), filtered);
+  }
+}
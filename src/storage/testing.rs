@@ -0,0 +1,35 @@
+//! # Test fixtures
+//!
+//! Stores and Prolly tree policies shared by the unit tests of the storage engine.
+
+use super::paging;
+use super::prolly;
+use super::vfs;
+use std::hash::{self, Hash, Hasher};
+use vfs::FileSystem;
+
+/// The store used in tests, backed by an in-memory file.
+pub type TestStore = paging::BasicStore<vfs::MemoryFile>;
+
+/// A policy producing small nodes, so that trees with few entries have many layers.
+pub struct TestPolicy;
+
+impl prolly::Policy for TestPolicy {
+  fn boundary_decision(&self, height: usize, key: &[u8], size: usize) -> bool {
+    let mut hasher = hash::DefaultHasher::new();
+    (height, key).hash(&mut hasher);
+    hasher.finish() % 16 < size as u64
+  }
+
+  fn content_hash(&self, content: &[u8]) -> Box<[u8]> {
+    let mut hasher = hash::DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish().to_le_bytes().into()
+  }
+}
+
+/// Opens an empty store with 512-byte pages, so that small trees already have several levels.
+pub fn open_store() -> TestStore {
+  let mut fs = vfs::MemoryFileSystem::default();
+  paging::BasicStore::open(fs.open("db").unwrap(), 512).unwrap()
+}
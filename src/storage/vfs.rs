@@ -7,13 +7,12 @@
 
 #![doc = include_str!("../../doc/atomic_commit.md")]
 
-use std::cell;
 use std::collections;
 use std::fmt;
 use std::fs;
 use std::io;
 use std::path;
-use std::rc;
+use std::sync;
 
 /// # File system interface
 ///
@@ -61,13 +60,17 @@ pub trait File {
   /// Flushes any buffered data to the file.
   fn sync(&mut self) -> Result<(), Self::Error>;
 
-  /// Tries locking the file exclusively.
-  fn try_lock(&mut self) -> Result<(), Self::Error>;
+  /// Tries locking the file exclusively. Returns `false` if it is locked through another handle.
+  fn try_lock(&mut self) -> Result<bool, Self::Error>;
+
+  /// Tries locking the file in shared mode, which still allows other handles to lock it in shared
+  /// mode, but not exclusively. Returns `false` if it is locked exclusively through another handle.
+  fn try_lock_shared(&mut self) -> Result<bool, Self::Error>;
 
   /// Locks the file exclusively.
   fn lock(&mut self) -> Result<(), Self::Error>;
 
-  /// Unlocks the file.
+  /// Unlocks the file, whether it is locked exclusively or in shared mode.
   fn unlock(&mut self) -> Result<(), Self::Error>;
 }
 
@@ -143,9 +146,14 @@ impl File for StandardFile {
     fs::File::sync_all(inner)
   }
 
-  fn try_lock(&mut self) -> Result<(), Self::Error> {
+  fn try_lock(&mut self) -> Result<bool, Self::Error> {
     let StandardFile(inner) = self;
-    fs2::FileExt::try_lock_exclusive(inner)
+    contended(fs2::FileExt::try_lock_exclusive(inner))
+  }
+
+  fn try_lock_shared(&mut self) -> Result<bool, Self::Error> {
+    let StandardFile(inner) = self;
+    contended(fs2::FileExt::try_lock_shared(inner))
   }
 
   fn lock(&mut self) -> Result<(), Self::Error> {
@@ -159,18 +167,29 @@ impl File for StandardFile {
   }
 }
 
+/// Converts the result of trying to lock a file, reporting a contended lock as `false`.
+fn contended(res: io::Result<()>) -> io::Result<bool> {
+  match res {
+    Ok(()) => Ok(true),
+    Err(err) if err.raw_os_error() == fs2::lock_contended_error().raw_os_error() => Ok(false),
+    Err(err) => Err(err),
+  }
+}
+
 #[derive(Debug, Default)]
 struct MemoryFileData {
   data: Vec<u8>,
   locked: bool,
+  shared: usize,
 }
 
 /// In-memory implementation for [`FileSystem`]
 ///
-/// Each file is represented by a byte vector and a boolean indicating whether the file is locked.
+/// Each file is represented by a byte vector, a boolean indicating whether the file is locked
+/// exclusively, and the number of shared locks on it.
 #[derive(Debug)]
 pub struct MemoryFileSystem {
-  files: collections::HashMap<String, rc::Rc<cell::RefCell<MemoryFileData>>>,
+  files: collections::HashMap<String, sync::Arc<sync::Mutex<MemoryFileData>>>,
 }
 
 /// Public constructor for [`MemoryFileSystem`].
@@ -198,16 +217,36 @@ impl FileSystem for MemoryFileSystem {
 
 /// In-memory implementation for [`File`]
 ///
-/// Each file is represented by a byte vector and a boolean indicating whether the file is locked.
+/// Each file is represented by a byte vector and its lock state. Like the locks of
+/// [`StandardFile`], locks are held by handles, and are released when the handle is dropped. Since
+/// waiting is not supported, [`File::lock`] fails if the file is locked through another handle.
 #[derive(Debug)]
 pub struct MemoryFile {
-  file: rc::Rc<cell::RefCell<MemoryFileData>>,
+  file: sync::Arc<sync::Mutex<MemoryFileData>>,
+  lock: MemoryLock,
+}
+
+/// The lock held through a [`MemoryFile`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MemoryLock {
+  Unlocked,
+  Shared,
+  Exclusive,
 }
 
 /// Public constructor for [`MemoryFile`].
-impl From<rc::Rc<cell::RefCell<MemoryFileData>>> for MemoryFile {
-  fn from(file: rc::Rc<cell::RefCell<MemoryFileData>>) -> Self {
-    MemoryFile { file }
+impl From<sync::Arc<sync::Mutex<MemoryFileData>>> for MemoryFile {
+  fn from(file: sync::Arc<sync::Mutex<MemoryFileData>>) -> Self {
+    MemoryFile { file, lock: MemoryLock::Unlocked }
+  }
+}
+
+/// Releases the lock held through the handle.
+impl Drop for MemoryFile {
+  fn drop(&mut self) {
+    if self.lock != MemoryLock::Unlocked {
+      let _ = self.unlock();
+    }
   }
 }
 
@@ -215,18 +254,18 @@ impl File for MemoryFile {
   type Error = String;
 
   fn size(&mut self) -> Result<u64, Self::Error> {
-    u64::try_from(self.file.borrow().data.len()).map_err(|x| x.to_string())
+    u64::try_from(self.file.lock().unwrap().data.len()).map_err(|x| x.to_string())
   }
 
   fn truncate(&mut self, size: u64) -> Result<(), Self::Error> {
     let size = usize::try_from(size).map_err(|x| x.to_string())?;
-    self.file.borrow_mut().data.resize(size, 0xCC);
+    self.file.lock().unwrap().data.resize(size, 0xCC);
     Ok(())
   }
 
   fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Self::Error> {
     let offset = usize::try_from(offset).map_err(|x| x.to_string())?;
-    let file = self.file.borrow();
+    let file = self.file.lock().unwrap();
     if offset + buf.len() > file.data.len() {
       return Err(String::new());
     }
@@ -236,7 +275,7 @@ impl File for MemoryFile {
 
   fn write(&mut self, offset: u64, buf: &[u8]) -> Result<(), Self::Error> {
     let offset = usize::try_from(offset).map_err(|x| x.to_string())?;
-    let mut file = self.file.borrow_mut();
+    let mut file = self.file.lock().unwrap();
     if offset + buf.len() > file.data.len() {
       file.data.resize(offset + buf.len(), 0xCC);
     }
@@ -248,34 +287,48 @@ impl File for MemoryFile {
     Ok(())
   }
 
-  fn try_lock(&mut self) -> Result<(), Self::Error> {
-    let mut file = self.file.borrow_mut();
-    if file.locked {
-      Err(String::new())
-    } else {
-      file.locked = true;
-      Ok(())
+  fn try_lock(&mut self) -> Result<bool, Self::Error> {
+    let mut file = self.file.lock().unwrap();
+    match self.lock {
+      MemoryLock::Exclusive => return Ok(true),
+      MemoryLock::Shared if !file.locked && file.shared == 1 => file.shared = 0,
+      MemoryLock::Unlocked if !file.locked && file.shared == 0 => {}
+      _ => return Ok(false),
     }
+    file.locked = true;
+    self.lock = MemoryLock::Exclusive;
+    Ok(true)
+  }
+
+  fn try_lock_shared(&mut self) -> Result<bool, Self::Error> {
+    let mut file = self.file.lock().unwrap();
+    match self.lock {
+      MemoryLock::Shared => return Ok(true),
+      MemoryLock::Exclusive => file.locked = false,
+      MemoryLock::Unlocked if !file.locked => {}
+      MemoryLock::Unlocked => return Ok(false),
+    }
+    file.shared += 1;
+    self.lock = MemoryLock::Shared;
+    Ok(true)
   }
 
   fn lock(&mut self) -> Result<(), Self::Error> {
-    let mut file = self.file.borrow_mut();
-    if file.locked {
-      Err(String::new())
-    } else {
-      file.locked = true;
-      Ok(())
+    match self.try_lock()? {
+      true => Ok(()),
+      false => Err(String::new()),
     }
   }
 
   fn unlock(&mut self) -> Result<(), Self::Error> {
-    let mut file = self.file.borrow_mut();
-    if file.locked {
-      file.locked = false;
-      Ok(())
-    } else {
-      Err(String::new())
+    let mut file = self.file.lock().unwrap();
+    match self.lock {
+      MemoryLock::Unlocked => return Err(String::new()),
+      MemoryLock::Shared => file.shared -= 1,
+      MemoryLock::Exclusive => file.locked = false,
     }
+    self.lock = MemoryLock::Unlocked;
+    Ok(())
   }
 }
 
@@ -366,13 +419,27 @@ mod tests {
   fn test_file_lock_unlock<F: File>(file1: &mut F, file2: &mut F) {
     // No other access is possible once an exclusive lock is created.
     file1.lock().unwrap();
-    file2.try_lock().unwrap_err();
+    assert!(!file2.try_lock().unwrap());
+    assert!(!file2.try_lock_shared().unwrap());
 
     // Once the exclusive lock is dropped, the second file is able to create a lock.
     file1.unlock().unwrap();
     file2.lock().unwrap();
   }
 
+  fn test_file_lock_shared<F: File>(file1: &mut F, file2: &mut F) {
+    // Shared locks only exclude exclusive locks.
+    assert!(file1.try_lock_shared().unwrap());
+    assert!(file2.try_lock_shared().unwrap());
+    assert!(!file1.try_lock().unwrap());
+    file2.unlock().unwrap();
+    file1.unlock().unwrap();
+    assert!(file2.try_lock().unwrap());
+    assert!(!file1.try_lock_shared().unwrap());
+    file2.unlock().unwrap();
+    assert!(file1.try_lock_shared().unwrap());
+  }
+
   #[test]
   fn test_standard_filesystem_open_create() {
     let mut fs = StandardFileSystem;
@@ -478,6 +545,16 @@ mod tests {
     test_file_lock_unlock(&mut file1, &mut file2);
   }
 
+  #[test]
+  fn test_standard_file_lock_shared() {
+    let mut fs = StandardFileSystem;
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("file");
+    let mut file1 = fs.open(&path).unwrap();
+    let mut file2 = fs.open(&path).unwrap();
+    test_file_lock_shared(&mut file1, &mut file2);
+  }
+
   #[test]
   fn test_memory_filesystem_open_create() {
     let mut fs = MemoryFileSystem::default();
@@ -570,4 +647,13 @@ mod tests {
     let mut file2 = fs.open(&path).unwrap();
     test_file_lock_unlock(&mut file1, &mut file2);
   }
+
+  #[test]
+  fn test_memory_file_lock_shared() {
+    let mut fs = MemoryFileSystem::default();
+    let path = "file".to_owned();
+    let mut file1 = fs.open(&path).unwrap();
+    let mut file2 = fs.open(&path).unwrap();
+    test_file_lock_shared(&mut file1, &mut file2);
+  }
 }